@@ -1,265 +1,878 @@
-use crate::{config::Config, crypto, git, paths};
+use crate::destination::{self, Configured};
+use crate::filter::Filter;
+use crate::retention;
+use crate::sqlite::{self, DatabaseCheck};
+use crate::{config::Config, crypto, dedup, git, keys, paths, signing, verify};
 use anyhow::{Context, Result};
-use chrono::{Duration, Utc};
+use chrono::Utc;
+use ed25519_dalek::SigningKey;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
-use tar::Builder;
+use std::time::UNIX_EPOCH;
+use tar::{Builder, Header};
 
 /// 90MB chunks (under GitHub's 100MB limit)
-const CHUNK_SIZE: u64 = 90_000_000;
+pub const CHUNK_SIZE: u64 = 90_000_000;
 
-#[derive(Serialize, Deserialize)]
+/// Directory holding the deduplicated repository in the git checkout and on Google Drive
+pub const INCREMENTAL_DIR: &str = "repo";
+
+/// Top-level directory of the archive
+const ARCHIVE_ROOT: &str = "whatsapp-data";
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ChunkInfo {
     pub name: String,
     pub size: u64,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Manifest {
     pub version: u8,
+    /// Encryption format of the reassembled file (see `crypto::FORMAT_VERSION`)
+    #[serde(default = "legacy_format_version")]
+    pub format_version: u8,
     pub timestamp: String,
     pub original_size: u64,
     pub chunk_size: u64,
     pub chunks: Vec<ChunkInfo>,
     pub sha256: String,
+    /// Integrity check of each SQLite database snapshot in the archive
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub databases: Vec<DatabaseCheck>,
+    /// Ed25519 signature over the rest of the manifest (see `signing`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
-/// Computes SHA256 hash of a file
-fn compute_sha256(path: &Path) -> Result<String> {
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-    let mut hasher = Sha256::new();
-    let mut buffer = [0u8; 65536];
+/// Manifests written before the format version was recorded describe v1 files
+fn legacy_format_version() -> u8 {
+    crypto::FORMAT_LEGACY
+}
 
-    loop {
-        let bytes_read = reader.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
+/// Encrypted backup files produced by a single pipeline run
+pub struct BackupFiles {
+    /// Chunk files, or the single `.enc` file for small backups
+    pub data: Vec<PathBuf>,
+    /// Manifest listing the data files
+    pub manifest: PathBuf,
+    /// Total encrypted size in bytes
+    pub size: u64,
+}
+
+impl BackupFiles {
+    /// Path to pass to `restore` (the manifest for chunked backups)
+    pub fn restore_path(&self) -> &Path {
+        if self.is_chunked() {
+            &self.manifest
+        } else {
+            &self.data[0]
         }
-        hasher.update(&buffer[..bytes_read]);
     }
 
-    Ok(format!("{:x}", hasher.finalize()))
+    pub fn is_chunked(&self) -> bool {
+        self.data.len() > 1
+    }
+
+    /// All files belonging to this backup
+    pub fn all(&self) -> Vec<PathBuf> {
+        let mut files = self.data.clone();
+        files.push(self.manifest.clone());
+        files
+    }
 }
 
-/// Splits a file into chunks, returns paths to chunks and manifest
-fn split_into_chunks(file: &Path, timestamp: &str) -> Result<(Vec<PathBuf>, PathBuf)> {
-    let parent = file.parent().context("No parent directory")?;
-    let original_size = std::fs::metadata(file)?.len();
-    let sha256 = compute_sha256(file)?;
-
-    let mut input = BufReader::new(File::open(file)?);
-    let mut chunks = Vec::new();
-    let mut chunk_infos = Vec::new();
-    let mut chunk_num = 1u32;
-    let mut buffer = vec![0u8; CHUNK_SIZE as usize];
-
-    loop {
-        let bytes_read = input.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
+/// Writer that splits its input into numbered chunk files while hashing it,
+/// so chunks come straight out of the encryptor without an intermediate file
+pub struct ChunkWriter {
+    dir: PathBuf,
+    timestamp: String,
+    chunk_size: u64,
+    current: Option<BufWriter<File>>,
+    current_size: u64,
+    chunks: Vec<ChunkInfo>,
+    hasher: Sha256,
+    total_size: u64,
+    signing_key: Option<SigningKey>,
+    databases: Vec<DatabaseCheck>,
+}
+
+impl ChunkWriter {
+    pub fn new(dir: &Path, timestamp: &str, chunk_size: u64) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            timestamp: timestamp.to_string(),
+            chunk_size,
+            current: None,
+            current_size: 0,
+            chunks: Vec::new(),
+            hasher: Sha256::new(),
+            total_size: 0,
+            signing_key: None,
+            databases: Vec::new(),
+        }
+    }
+
+    /// Signs the manifest with `key`
+    pub fn with_signing_key(mut self, key: Option<SigningKey>) -> Self {
+        self.signing_key = key;
+        self
+    }
+
+    /// Records database integrity checks in the manifest
+    pub fn with_databases(mut self, databases: Vec<DatabaseCheck>) -> Self {
+        self.databases = databases;
+        self
+    }
+
+    fn chunk_name(&self, number: usize) -> String {
+        format!("{}.enc.{:03}", self.timestamp, number)
+    }
+
+    /// Closes the current chunk file and records its size
+    fn close_chunk(&mut self) -> std::io::Result<()> {
+        if let Some(mut file) = self.current.take() {
+            file.flush()?;
+            let name = self.chunk_name(self.chunks.len() + 1);
+            self.chunks.push(ChunkInfo {
+                name,
+                size: self.current_size,
+            });
+            self.current_size = 0;
+        }
+        Ok(())
+    }
+
+    /// Finalizes the chunks: a single chunk becomes `<timestamp>.enc`. Every
+    /// backup gets a manifest with the SHA256 of the whole stream, signed if
+    /// a signing key was given.
+    pub fn finish(mut self) -> Result<BackupFiles> {
+        self.close_chunk()?;
+        let size = self.total_size;
+
+        if self.chunks.len() == 1 {
+            let first = self.dir.join(&self.chunks[0].name);
+            let name = format!("{}.enc", self.timestamp);
+            std::fs::rename(&first, self.dir.join(&name))
+                .with_context(|| format!("Failed to rename {}", first.display()))?;
+            self.chunks[0].name = name;
         }
 
-        let chunk_name = format!("{}.enc.{:03}", timestamp, chunk_num);
-        let chunk_path = parent.join(&chunk_name);
+        let data = self.chunks.iter().map(|c| self.dir.join(&c.name)).collect();
+        let mut manifest = Manifest {
+            version: 2,
+            format_version: crypto::FORMAT_VERSION,
+            timestamp: self.timestamp.clone(),
+            original_size: size,
+            chunk_size: self.chunk_size,
+            chunks: self.chunks,
+            sha256: format!("{:x}", self.hasher.finalize()),
+            databases: self.databases,
+            signature: None,
+        };
+        if let Some(key) = &self.signing_key {
+            signing::sign_manifest(&mut manifest, key)?;
+        }
 
-        let mut output = BufWriter::new(File::create(&chunk_path)?);
-        output.write_all(&buffer[..bytes_read])?;
-        output.flush()?;
+        let manifest_path = self.dir.join(format!("{}.enc.manifest", self.timestamp));
+        let manifest_file = File::create(&manifest_path)?;
+        serde_json::to_writer_pretty(manifest_file, &manifest)?;
 
-        chunk_infos.push(ChunkInfo {
-            name: chunk_name,
-            size: bytes_read as u64,
-        });
-        chunks.push(chunk_path);
-        chunk_num += 1;
-    }
-
-    // Create manifest
-    let manifest = Manifest {
-        version: 1,
-        timestamp: timestamp.to_string(),
-        original_size,
-        chunk_size: CHUNK_SIZE,
-        chunks: chunk_infos,
-        sha256,
-    };
+        Ok(BackupFiles {
+            data,
+            manifest: manifest_path,
+            size,
+        })
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.current_size == self.chunk_size {
+            self.close_chunk()?;
+        }
+        let file = match self.current.as_mut() {
+            Some(file) => file,
+            None => {
+                let path = self.dir.join(self.chunk_name(self.chunks.len() + 1));
+                self.current.insert(BufWriter::new(File::create(path)?))
+            }
+        };
+
+        let room = (self.chunk_size - self.current_size) as usize;
+        let n = buf.len().min(room);
+        file.write_all(&buf[..n])?;
+        self.hasher.update(&buf[..n]);
+        self.current_size += n as u64;
+        self.total_size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.current.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
 
-    let manifest_path = parent.join(format!("{}.enc.manifest", timestamp));
-    let manifest_file = File::create(&manifest_path)?;
-    serde_json::to_writer_pretty(manifest_file, &manifest)?;
+/// Removes files left behind by a failed pipeline run
+fn remove_partial_chunks(dir: &Path, timestamp: &str) {
+    let prefix = format!("{}.enc", timestamp);
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                std::fs::remove_file(entry.path()).ok();
+            }
+        }
+    }
+}
+
+/// Writes a tar archive of the WhatsApp data allowed by `filter` into
+/// `writer`, returning the writer and the integrity check of every SQLite
+/// database. Databases are archived from consistent snapshots instead of the
+/// live files.
+fn create_archive<W: Write>(
+    whatsapp_dir: &Path,
+    filter: &Filter,
+    writer: W,
+) -> Result<(W, Vec<DatabaseCheck>)> {
+    // Private (0700) directory for database snapshots, removed when dropped
+    let staging = tempfile::Builder::new()
+        .prefix("whatsapp-backup-")
+        .tempdir()
+        .context("Failed to create staging directory")?;
+    let mut archive = Builder::new(writer);
+    let mut databases = Vec::new();
+
+    archive.append_dir(ARCHIVE_ROOT, whatsapp_dir)?;
+    for entry in filter.entries(whatsapp_dir)? {
+        let name = Path::new(ARCHIVE_ROOT).join(&entry.relative);
+        if entry.is_dir {
+            archive.append_dir(&name, &entry.path)?;
+        } else if sqlite::is_sidecar(&entry.path) {
+            // Its contents are part of the database snapshot
+            continue;
+        } else if sqlite::is_database(&entry.path) {
+            databases.push(append_database(&mut archive, &entry.path, &name, staging.path())?);
+        } else {
+            archive
+                .append_path_with_name(&entry.path, &name)
+                .with_context(|| format!("Failed to add {} to archive", entry.path.display()))?;
+        }
+    }
 
-    Ok((chunks, manifest_path))
+    let writer = archive.into_inner().context("Failed to finalize archive")?;
+    Ok((writer, databases))
 }
 
-/// Creates a compressed tar archive of WhatsApp data
-fn create_archive(whatsapp_dir: &Path, output: &Path) -> Result<()> {
-    let file = File::create(output)
-        .with_context(|| format!("Failed to create archive: {}", output.display()))?;
+/// Fingerprint of everything a backup would archive: paths, sizes and
+/// modification times, plus the rules, backup mode, key slots and
+/// destinations, and the contents of SQLite databases if
+/// `fingerprint_databases` is set
+fn tree_fingerprint(
+    whatsapp_dir: &Path,
+    filter: &Filter,
+    config: &Config,
+    incremental: bool,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(&(&config.include, &config.exclude, incremental))?);
+    // A new key or destination has to reach a backup even if the data is unchanged
+    hasher.update(serde_json::to_vec(&(
+        config.key_slots(),
+        &config.destinations,
+        &config.git_remote,
+    ))?);
+
+    for entry in filter.entries(whatsapp_dir)? {
+        let modified = std::fs::metadata(&entry.path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos());
+        hasher.update(entry.relative.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(entry.size.to_le_bytes());
+        hasher.update(modified.to_le_bytes());
+
+        let is_sqlite = sqlite::is_database(&entry.path) || sqlite::is_sidecar(&entry.path);
+        if config.fingerprint_databases && !entry.is_dir && is_sqlite {
+            std::io::copy(&mut File::open(&entry.path)?, &mut hasher)?;
+        }
+    }
 
-    let encoder = GzEncoder::new(file, Compression::default());
-    let mut archive = Builder::new(encoder);
+    Ok(format!("{:x}", hasher.finalize()))
+}
 
-    archive
-        .append_dir_all("whatsapp-data", whatsapp_dir)
-        .context("Failed to add WhatsApp data to archive")?;
+/// Lists the files a backup would archive with the configured rules, and
+/// their total size
+pub fn dry_run() -> Result<()> {
+    let config = Config::load()?;
+    let whatsapp_dir = paths::whatsapp_data_dir()?;
+    let filter = Filter::from_config(&config, &whatsapp_dir)?;
 
-    archive.finish().context("Failed to finalize archive")?;
+    let mut total = 0;
+    let mut count = 0;
+    for entry in filter.entries(&whatsapp_dir)? {
+        if entry.is_dir || sqlite::is_sidecar(&entry.path) {
+            continue;
+        }
+        println!("  {} ({:.2} MB)", entry.relative.display(), entry.size as f64 / 1_000_000.0);
+        total += entry.size;
+        count += 1;
+    }
 
+    println!(
+        "\n{} files, {:.2} MB would be archived (before compression)",
+        count,
+        total as f64 / 1_000_000.0
+    );
     Ok(())
 }
 
-/// Cleans up old backups beyond retention period (including chunks and manifests)
-fn cleanup_old_backups(backup_dir: &Path, retention_days: u32) -> Result<()> {
-    let cutoff = Utc::now() - Duration::days(retention_days as i64);
-
-    for entry in std::fs::read_dir(backup_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-
-        // Match .enc files, chunk files (.enc.001, etc), and manifests
-        let is_backup_file = name.ends_with(".enc")
-            || name.contains(".enc.")
-            || name.ends_with(".manifest");
-
-        if is_backup_file {
-            if let Ok(metadata) = entry.metadata() {
-                if let Ok(modified) = metadata.modified() {
-                    let modified_time: chrono::DateTime<Utc> = modified.into();
-                    if modified_time < cutoff {
-                        std::fs::remove_file(&path).ok();
-                        println!("  Removed old backup: {}", path.display());
-                    }
+/// Archives a consistent snapshot of a database with the original file's
+/// metadata. If SQLite can't read it, the live file and its WAL are archived
+/// as they are and the failure is recorded.
+fn append_database<W: Write>(
+    archive: &mut Builder<W>,
+    path: &Path,
+    name: &Path,
+    staging: &Path,
+) -> Result<DatabaseCheck> {
+    let copy = staging.join("snapshot.sqlite");
+    let integrity = match sqlite::snapshot(path, &copy) {
+        Ok(()) => {
+            let integrity = sqlite::integrity_check(&copy)
+                .unwrap_or_else(|e| format!("integrity check failed: {}", e));
+            let mut header = Header::new_gnu();
+            header.set_metadata(&std::fs::metadata(path)?);
+            header.set_size(std::fs::metadata(&copy)?.len());
+            header.set_cksum();
+            archive.append_data(&mut header, name, File::open(&copy)?)?;
+            integrity
+        }
+        Err(e) => {
+            archive.append_path_with_name(path, name)?;
+            for suffix in ["-wal", "-shm"] {
+                let sidecar = PathBuf::from(format!("{}{}", path.display(), suffix));
+                if sidecar.exists() {
+                    let sidecar_name = format!("{}{}", name.display(), suffix);
+                    archive.append_path_with_name(&sidecar, sidecar_name)?;
                 }
             }
+            format!("snapshot failed, archived the live file: {:#}", e)
         }
+    };
+    std::fs::remove_file(&copy).ok();
+
+    let check = DatabaseCheck {
+        path: name.to_string_lossy().into_owned(),
+        integrity,
+    };
+    if !check.is_ok() {
+        println!("  Warning: {}: {}", check.path, check.integrity);
     }
+    Ok(check)
+}
+
+/// Archives, compresses, encrypts and chunks WhatsApp data in a single pass:
+/// tar → gzip → AES-256-GCM stream → chunk files. Plaintext never reaches disk.
+fn write_encrypted_backup(
+    whatsapp_dir: &Path,
+    filter: &Filter,
+    backup_dir: &Path,
+    timestamp: &str,
+    recipients: &[crypto::Recipient],
+    signing_key: Option<SigningKey>,
+) -> Result<BackupFiles> {
+    let chunks = ChunkWriter::new(backup_dir, timestamp, CHUNK_SIZE).with_signing_key(signing_key);
+    let encryptor = crypto::StreamEncryptor::for_recipients(chunks, recipients)?;
+    let encoder = GzEncoder::new(encryptor, Compression::default());
+
+    let (encoder, databases) = create_archive(whatsapp_dir, filter, encoder)?;
+    let encryptor = encoder.finish().context("Failed to compress archive")?;
+    let chunks = encryptor.finish().context("Failed to encrypt archive")?;
+
+    println!("  Snapshotted {} SQLite databases", databases.len());
+    chunks.with_databases(databases).finish()
+}
 
+/// Runs `action` for every configured destination. A failing destination is
+/// reported and doesn't stop the others; the error names every one that failed.
+fn for_each_destination<F>(config: &Config, mut action: F) -> Result<()>
+where
+    F: FnMut(&Configured) -> Result<()>,
+{
+    let mut failed = Vec::new();
+    for configured in destination::configured(config)? {
+        if let Err(e) = action(&configured) {
+            println!("  {} failed: {:#}", configured.name, e);
+            failed.push(configured.name);
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("Failed to update destinations: {}", failed.join(", "));
+    }
     Ok(())
 }
 
-/// Copies backup to Google Drive if available
-fn copy_to_google_drive(backup_file: &Path) -> Result<Option<PathBuf>> {
-    let Some(drive_dir) = paths::google_drive_dir() else {
-        return Ok(None);
-    };
+/// Mirrors the incremental repository to every other destination and
+/// commits it with `message`
+fn mirror_repository(
+    config: &Config,
+    repo_dir: &Path,
+    identities: &[crypto::Identity],
+    message: &str,
+) -> Result<()> {
+    for_each_destination(config, |configured| {
+        let destination = configured.destination.as_ref();
+        // The local repository is its own copy
+        if destination.local_dir() == repo_dir.parent() {
+            return Ok(());
+        }
+        println!("Mirroring to {} ({})...", configured.name, destination.location());
+        let copied = destination::mirror(destination, repo_dir, identities)?;
+        destination.commit(message)?;
+        println!("  Copied {} new files", copied);
+        Ok(())
+    })
+}
 
-    let backup_folder = drive_dir.join("WhatsApp-Backups");
-    if !backup_folder.exists() {
-        std::fs::create_dir_all(&backup_folder)?;
+/// Prunes the snapshots in every mirror of the incremental repository by
+/// that destination's own policy and commits the removals. Returns the
+/// number of snapshots removed (or that would be).
+fn prune_mirrors(
+    config: &Config,
+    repo: &dedup::Repository,
+    repo_dir: &Path,
+    dry_run: bool,
+) -> Result<usize> {
+    let prefix = format!("{}/", INCREMENTAL_DIR);
+    let mut removed = 0;
+    for_each_destination(config, |configured| {
+        let destination = configured.destination.as_ref();
+        if destination.local_dir() == repo_dir.parent()
+            || destination.stat(&format!("{}{}", prefix, dedup::KEY_FILE))?.is_none()
+        {
+            return Ok(());
+        }
+        println!("Snapshots at {} ({}):", configured.name, destination.location());
+        let (snapshots, chunks) =
+            repo.prune_at(destination, &prefix, configured.retention, dry_run)?;
+        println!("  {} snapshots, {} unreferenced chunks", snapshots, chunks);
+        if !dry_run && snapshots + chunks > 0 {
+            destination.commit("Prune old snapshots")?;
+        }
+        removed += snapshots;
+        Ok(())
+    })?;
+    Ok(removed)
+}
+
+/// Stores a deduplicated snapshot, prunes the repository and mirrors it to
+/// the destinations, which then prune their copies. Only chunks not already
+/// stored are uploaded.
+fn run_incremental_backup(
+    config: &mut Config,
+    whatsapp_dir: &Path,
+    filter: Filter,
+    timestamp: &str,
+    passphrase: &str,
+    signing_key: Option<SigningKey>,
+    fingerprint: String,
+) -> Result<PathBuf> {
+    let repo_dir = paths::dedup_repo_dir()?;
+    let identities = [crypto::Identity::Passphrase(passphrase.to_string())];
+    let recipients = keys::recipients(config)?;
+    if !dedup::Repository::exists(&repo_dir) {
+        if let Some((name, key)) = destination::find_repository_key(config, &repo_dir)? {
+            // Continue the mirrored repository rather than start one beside it
+            println!("Continuing the incremental repository at {}", name);
+            dedup::Repository::init_with_key(&repo_dir, &key, &identities, signing_key.as_ref())?;
+        }
     }
+    let repo = dedup::Repository::open_or_init(
+        &repo_dir,
+        &identities,
+        &recipients,
+        signing_key.as_ref(),
+    )?
+    .with_signing_key(signing_key);
+
+    // Archive on a separate thread and chunk the tar stream as it is produced
+    println!("Creating deduplicated snapshot...");
+    let (reader, writer) = std::io::pipe()?;
+    let source = whatsapp_dir.to_path_buf();
+    let archiver = std::thread::spawn(move || {
+        create_archive(&source, &filter, writer).map(|(_, databases)| databases)
+    });
+
+    let stored = repo.store_snapshot(reader, timestamp);
+    let archived = archiver
+        .join()
+        .map_err(|_| anyhow::anyhow!("Archive thread panicked"))?;
+
+    let stats = match (archived, stored) {
+        (Ok(databases), Ok(stats)) => {
+            println!("  Snapshotted {} SQLite databases", databases.len());
+            stats
+        }
+        // A failed archive ends the stream early, so the snapshot is incomplete
+        (Err(e), Ok(stats)) => {
+            std::fs::remove_file(&stats.path).ok();
+            return Err(e);
+        }
+        (_, Err(e)) => return Err(e),
+    };
+    println!("  Snapshot: {}", stats.path.display());
+    println!(
+        "  {} chunks, {} new ({:.2} MB of {:.2} MB)",
+        stats.chunks,
+        stats.new_chunks,
+        stats.new_bytes as f64 / 1_000_000.0,
+        stats.total_size as f64 / 1_000_000.0
+    );
 
-    let file_name = backup_file.file_name().context("Invalid backup filename")?;
-    let dest = backup_folder.join(file_name);
+    println!("Pruning old snapshots...");
+    let (snapshots, chunks) = repo.prune(config.local_retention(), false)?;
+    println!("  Removed {} snapshots, {} unreferenced chunks", snapshots, chunks);
 
-    std::fs::copy(backup_file, &dest)?;
+    let message = format!("Snapshot {}", timestamp);
+    let mirrored = mirror_repository(config, &repo_dir, &identities, &message)
+        .and_then(|()| prune_mirrors(config, &repo, &repo_dir, false));
+    git::bound_history(config)?;
+    mirrored?;
 
-    Ok(Some(dest))
+    config.update_last_backup(fingerprint)?;
+    println!("Backup complete!");
+
+    Ok(stats.path)
 }
 
-/// Main backup function
-pub fn run_backup() -> Result<PathBuf> {
+/// Main backup function. Returns `None` if the WhatsApp data hasn't changed
+/// since the last backup and `force` isn't set.
+pub fn run_backup(incremental: bool, force: bool) -> Result<Option<PathBuf>> {
     let mut config = Config::load()?;
 
     if !config.initialized {
         anyhow::bail!(
-            "Not initialized. Run 'whatsapp-backup init' first to set up encryption and GitHub."
+            "Not initialized. Run 'whatsapp-backup init' first to set up encryption and the git remote."
         );
     }
 
-    // Get passphrase from keychain
-    let passphrase = crypto::get_passphrase()?;
-
     // Check WhatsApp data exists
     println!("Checking WhatsApp data...");
     let whatsapp_dir = paths::whatsapp_data_dir()?;
     println!("  Found: {}", whatsapp_dir.display());
 
+    // Skip the backup if nothing changed since the last one
+    let incremental = incremental || config.incremental;
+    let filter = Filter::from_config(&config, &whatsapp_dir)?;
+    let fingerprint = tree_fingerprint(&whatsapp_dir, &filter, &config, incremental)?;
+    if !force && config.last_fingerprint.as_ref() == Some(&fingerprint) {
+        let last = match config.last_backup {
+            Some(time) => time.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            None => "the last backup".to_string(),
+        };
+        println!("No changes since {}, skipping backup (use --force to back up anyway)", last);
+        return Ok(None);
+    }
+
     // Create timestamp for filename
-    let timestamp = Utc::now().format("%Y-%m-%d_%H-%M-%S");
+    let timestamp = Utc::now().format(retention::TIMESTAMP_FORMAT).to_string();
     let backup_dir = paths::backup_dir()?;
 
-    // Create temporary archive
-    let archive_path = backup_dir.join(format!("{}.tar.gz", timestamp));
-    println!("Creating archive...");
-    create_archive(&whatsapp_dir, &archive_path)?;
-    println!("  Archive created: {}", archive_path.display());
-
-    // Get archive size for reporting
-    let archive_size = std::fs::metadata(&archive_path)?.len();
-    println!("  Size: {:.2} MB", archive_size as f64 / 1_000_000.0);
-
-    // Encrypt archive
-    let encrypted_path = backup_dir.join(format!("{}.enc", timestamp));
-    println!("Encrypting...");
-    crypto::encrypt_file(&archive_path, &encrypted_path, &passphrase)?;
-    println!("  Encrypted: {}", encrypted_path.display());
-
-    // Remove unencrypted archive
-    std::fs::remove_file(&archive_path)?;
-
-    // Push to GitHub - chunk if needed
-    let encrypted_size = std::fs::metadata(&encrypted_path)?.len();
-
-    if git::is_repo_initialized() {
-        let commit_msg = format!("Backup {}", timestamp);
-
-        if encrypted_size > CHUNK_SIZE {
-            // Split into chunks for GitHub
-            println!(
-                "Splitting into chunks ({:.0} MB > {:.0} MB limit)...",
-                encrypted_size as f64 / 1_000_000.0,
-                CHUNK_SIZE as f64 / 1_000_000.0
-            );
-            let (chunks, manifest) = split_into_chunks(&encrypted_path, &timestamp.to_string())?;
-            println!("  Created {} chunks + manifest", chunks.len());
-
-            // Collect all files to push
-            let mut files_to_push: Vec<PathBuf> = chunks;
-            files_to_push.push(manifest);
+    // Setups from before manifest signing get a signing key on their next backup
+    if config.signing_public_key.is_none() {
+        let key = signing::create_signing_key(&mut config)?;
+        config.save()?;
+        println!("Created manifest signing key: {}", signing::encode_verifying_key(&key));
+    }
+    let signing_key = signing::load_signing_key(&config)?;
 
-            println!("Pushing to GitHub...");
-            git::commit_and_push_files(&files_to_push, &commit_msg)?;
-            println!("  Pushed {} files to GitHub", files_to_push.len());
+    if incremental {
+        if !config.has_default_passphrase() {
+            anyhow::bail!(
+                "Incremental backups open the repository with the stored passphrase and \
+                 are not available when backups are encrypted to public keys only"
+            );
+        }
+        let passphrase = crypto::get_passphrase()?;
+        return run_incremental_backup(
+            &mut config,
+            &whatsapp_dir,
+            filter,
+            &timestamp,
+            &passphrase,
+            signing_key,
+            fingerprint,
+        )
+        .map(Some);
+    }
 
-            // Clean up chunk files from local backup dir (keep original .enc)
-            for file in &files_to_push {
-                std::fs::remove_file(file).ok();
-            }
-        } else {
-            println!("Pushing to GitHub...");
-            git::commit_and_push(&encrypted_path, &commit_msg)?;
-            println!("  Pushed to GitHub");
+    // One recipient per key slot in config
+    let recipients = keys::recipients(&config)?;
+
+    // Archive, compress, encrypt and chunk in one pass
+    println!("Creating encrypted archive...");
+    let files = match write_encrypted_backup(
+        &whatsapp_dir,
+        &filter,
+        &backup_dir,
+        &timestamp,
+        &recipients,
+        signing_key,
+    ) {
+        Ok(files) => files,
+        Err(e) => {
+            remove_partial_chunks(&backup_dir, &timestamp);
+            return Err(e);
         }
+    };
+    println!("  Encrypted: {}", files.restore_path().display());
+    if files.is_chunked() {
+        println!(
+            "  Split into {} chunks of up to {:.0} MB",
+            files.data.len(),
+            CHUNK_SIZE as f64 / 1_000_000.0
+        );
     }
 
-    // Copy to Google Drive
-    if let Some(drive_path) = copy_to_google_drive(&encrypted_path)? {
-        println!("Copied to Google Drive: {}", drive_path.display());
+    // Copy to every other destination, one file at a time
+    let message = format!("Backup {}", timestamp);
+    let stored = for_each_destination(&config, |configured| {
+        let destination = configured.destination.as_ref();
+        if destination.local_dir() == Some(backup_dir.as_path()) {
+            return Ok(());
+        }
+        println!("Copying to {} ({})...", configured.name, destination.location());
+        destination::store_files(destination, &files.all(), &message)?;
+        println!("  Stored {} files", files.all().len());
+        Ok(())
+    });
+
+    // The local copy is only kept until every destination has it
+    if stored.is_ok() && !config.keeps_local() {
+        for file in files.all() {
+            std::fs::remove_file(&file)?;
+        }
+        println!("Removed the local copy (no local destination configured)");
     }
 
-    // Cleanup old backups
-    println!("Cleaning up old backups...");
-    cleanup_old_backups(&backup_dir, config.retention_days)?;
+    // Nothing is pruned after a failed store, so old backups stay until a
+    // new one has reached every destination
+    stored?;
 
-    // Cleanup old backups in GitHub repo
-    if let Ok(repo_dir) = paths::github_repo_dir() {
-        cleanup_old_backups(&repo_dir, config.retention_days)?;
-    }
+    println!("Cleaning up old backups...");
+    prune_destinations(&config, false)?;
+    git::bound_history(&config)?;
 
     // Update config
-    config.update_last_backup()?;
+    config.update_last_backup(fingerprint)?;
 
     println!(
         "Backup complete! Size: {:.2} MB",
-        encrypted_size as f64 / 1_000_000.0
+        files.size as f64 / 1_000_000.0
     );
 
-    Ok(encrypted_path)
+    Ok(Some(files.restore_path().to_path_buf()))
+}
+
+/// Applies each destination's retention policy to the full backups stored
+/// there and commits the removals. Returns the number of backups removed (or
+/// that would be).
+fn prune_destinations(config: &Config, dry_run: bool) -> Result<usize> {
+    let mut removed = 0;
+    for configured in destination::configured(config)? {
+        let destination = configured.destination.as_ref();
+        println!("{} ({}):", configured.name, destination.location());
+        let count = retention::prune(destination, configured.retention, dry_run, |backup| {
+            verify::verify_stored(destination, backup)
+        })?;
+        if count > 0 && !dry_run {
+            destination.commit("Prune old backups")?;
+        }
+        removed += count;
+    }
+    Ok(removed)
+}
+
+/// Applies the retention policies to every destination and the incremental
+/// repository. With `dry_run`, only prints what would be kept and why.
+pub fn run_prune(dry_run: bool) -> Result<()> {
+    let config = Config::load()?;
+    if !config.initialized {
+        anyhow::bail!("Not initialized. Run 'whatsapp-backup init' first.");
+    }
+
+    let mut removed = prune_destinations(&config, dry_run)?;
+
+    let repo_dir = paths::dedup_repo_dir()?;
+    if dedup::Repository::exists(&repo_dir) {
+        println!("Snapshots ({}):", repo_dir.display());
+        let identities = keys::identities(&config, None)?;
+        let repo = dedup::Repository::open(&repo_dir, &identities)?;
+        let (snapshots, chunks) = repo.prune(config.local_retention(), dry_run)?;
+        println!("  {} snapshots, {} unreferenced chunks", snapshots, chunks);
+        removed += snapshots;
+        removed += prune_mirrors(&config, &repo, &repo_dir, dry_run)?;
+    }
+
+    if dry_run {
+        println!("\n{} backups would be removed", removed);
+    } else {
+        git::bound_history(&config)?;
+        println!("\nRemoved {} backups", removed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DestinationConfig, DestinationKind, KeySlot, KeySlotKind};
+
+    #[test]
+    fn test_chunk_writer_splits_and_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..2500u32).map(|i| (i % 256) as u8).collect();
+
+        let mut writer = ChunkWriter::new(dir.path(), "2026-01-18_05-59-34", 1000);
+        writer.write_all(&data).unwrap();
+        let files = writer.finish().unwrap();
+
+        assert_eq!(files.data.len(), 3);
+        assert_eq!(files.size, 2500);
+        let manifest: Manifest =
+            serde_json::from_reader(File::open(&files.manifest).unwrap()).unwrap();
+        let sizes: Vec<u64> = manifest.chunks.iter().map(|c| c.size).collect();
+        assert_eq!(sizes, vec![1000, 1000, 500]);
+        assert_eq!(manifest.sha256, format!("{:x}", Sha256::digest(&data)));
+
+        let reassembled: Vec<u8> = files
+            .data
+            .iter()
+            .flat_map(|path| std::fs::read(path).unwrap())
+            .collect();
+        assert_eq!(reassembled, data);
+    }
+
+    #[test]
+    fn test_archive_snapshots_live_databases() {
+        let source = tempfile::tempdir().unwrap();
+        std::fs::create_dir(source.path().join("Media")).unwrap();
+        std::fs::write(source.path().join("Media/photo.jpg"), b"jpeg").unwrap();
+
+        // Uncheckpointed WAL, as while WhatsApp is running
+        let db = source.path().join("ChatStorage.sqlite");
+        let writer = rusqlite::Connection::open(&db).unwrap();
+        writer.pragma_update(None, "journal_mode", "WAL").unwrap();
+        writer.pragma_update(None, "wal_autocheckpoint", 0).unwrap();
+        writer
+            .execute_batch("CREATE TABLE messages (text TEXT); INSERT INTO messages VALUES ('hi');")
+            .unwrap();
+
+        let filter = Filter::new(source.path(), &[], &[]).unwrap();
+        let (tar_bytes, databases) = create_archive(source.path(), &filter, Vec::new()).unwrap();
+        drop(writer);
+        assert_eq!(databases.len(), 1);
+        assert_eq!(databases[0].path, "whatsapp-data/ChatStorage.sqlite");
+        assert!(databases[0].is_ok());
+
+        let out = tempfile::tempdir().unwrap();
+        tar::Archive::new(tar_bytes.as_slice()).unpack(out.path()).unwrap();
+        let restored = out.path().join("whatsapp-data");
+        assert_eq!(std::fs::read(restored.join("Media/photo.jpg")).unwrap(), b"jpeg");
+        assert!(!restored.join("ChatStorage.sqlite-wal").exists());
+        let conn = rusqlite::Connection::open(restored.join("ChatStorage.sqlite")).unwrap();
+        let text: String = conn
+            .query_row("SELECT text FROM messages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(text, "hi");
+    }
+
+    #[test]
+    fn test_tree_fingerprint_detects_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let db = root.join("ChatStorage.sqlite");
+        rusqlite::Connection::open(&db)
+            .unwrap()
+            .execute_batch("CREATE TABLE messages (text TEXT); INSERT INTO messages VALUES ('a');")
+            .unwrap();
+        std::fs::write(root.join("photo.jpg"), b"jpeg").unwrap();
+
+        let mut config = Config::default();
+        let filter = Filter::new(root, &[], &[]).unwrap();
+        let fingerprint = |config: &Config| tree_fingerprint(root, &filter, config, false).unwrap();
+
+        let first = fingerprint(&config);
+        assert_eq!(fingerprint(&config), first);
+        assert_ne!(tree_fingerprint(root, &filter, &config, true).unwrap(), first);
+
+        config.destinations.push(DestinationConfig {
+            name: None,
+            kind: DestinationKind::SyncedFolder {
+                path: root.join("synced"),
+            },
+            retention: None,
+        });
+        assert_ne!(fingerprint(&config), first);
+        config.destinations.pop();
+        config
+            .add_key_slot(KeySlot {
+                label: "recovery".to_string(),
+                kind: KeySlotKind::PublicKey {
+                    public_key: "wbpk1test".to_string(),
+                },
+            })
+            .unwrap();
+        assert_ne!(fingerprint(&config), first);
+        config.remove_key_slot("recovery").unwrap();
+        assert_eq!(fingerprint(&config), first);
+
+        // Same size and mtime, different content: only seen with content hashes
+        let modified = std::fs::metadata(&db).unwrap().modified().unwrap();
+        let mut bytes = std::fs::read(&db).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        std::fs::write(&db, bytes).unwrap();
+        File::options().write(true).open(&db).unwrap().set_modified(modified).unwrap();
+        assert_eq!(fingerprint(&config), first);
+        config.fingerprint_databases = true;
+        let with_contents = fingerprint(&config);
+        assert_ne!(with_contents, first);
+
+        std::fs::write(root.join("new.jpg"), b"jpeg").unwrap();
+        assert_ne!(fingerprint(&config), with_contents);
+    }
+
+    #[test]
+    fn test_chunk_writer_single_chunk_has_signed_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+
+        let mut writer = ChunkWriter::new(dir.path(), "2026-01-18_05-59-34", 1000)
+            .with_signing_key(Some(key.clone()));
+        writer.write_all(&[1u8; 1000]).unwrap();
+        let files = writer.finish().unwrap();
+
+        assert_eq!(files.data, vec![dir.path().join("2026-01-18_05-59-34.enc")]);
+        assert_eq!(files.restore_path(), files.data[0]);
+        assert_eq!(std::fs::read(&files.data[0]).unwrap(), vec![1u8; 1000]);
+
+        let manifest: Manifest =
+            serde_json::from_reader(File::open(&files.manifest).unwrap()).unwrap();
+        assert_eq!(manifest.chunks[0].name, "2026-01-18_05-59-34.enc");
+        signing::verify_manifest(&manifest, &key.verifying_key()).unwrap();
+    }
 }
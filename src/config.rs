@@ -1,4 +1,5 @@
-use crate::paths;
+use crate::retention::{DestinationRetention, RetentionPolicy};
+use crate::{paths, secrets};
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
@@ -6,23 +7,347 @@ use std::path::PathBuf;
 
 const CONFIG_FILE: &str = "config.json";
 
+/// Key that new backups are encrypted to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeySlot {
+    pub label: String,
+    #[serde(flatten)]
+    pub kind: KeySlotKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KeySlotKind {
+    /// Passphrase kept in the secret store under `account`, so
+    /// scheduled backups can wrap the file key for it
+    Passphrase { account: String },
+    /// X25519 public key (`wbpk1...`); only the private key holder can restore
+    PublicKey { public_key: String },
+}
+
+impl KeySlot {
+    /// Slot used when none are configured: the passphrase set by `init`
+    pub fn default_passphrase() -> Self {
+        Self {
+            label: "default".to_string(),
+            kind: KeySlotKind::Passphrase {
+                account: secrets::DEFAULT_ACCOUNT.to_string(),
+            },
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match &self.kind {
+            KeySlotKind::Passphrase { account } => {
+                format!("passphrase (secret store account '{}')", account)
+            }
+            KeySlotKind::PublicKey { public_key } => format!("public key {}", public_key),
+        }
+    }
+}
+
+/// Service hosting the git remote. Its API is only used to create the repo.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum GitProvider {
+    /// GitHub, through the `gh` CLI
+    #[default]
+    Github,
+    /// Gitea or Forgejo, through its REST API
+    Gitea,
+    /// GitLab.com or self-hosted GitLab, through its REST API
+    Gitlab,
+    /// Any SSH, HTTPS or local remote that already exists
+    Plain,
+}
+
+impl GitProvider {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            GitProvider::Github => "GitHub",
+            GitProvider::Gitea => "Gitea",
+            GitProvider::Gitlab => "GitLab",
+            GitProvider::Plain => "plain git",
+        }
+    }
+}
+
+/// What happens to the git remote's history as backups are removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GitHistory {
+    /// Every pushed chunk stays in history, so the repo only grows
+    #[default]
+    Keep,
+    /// After each push, history is squashed to one commit holding the retained
+    /// backups and force-pushed
+    Squash,
+}
+
+/// A place backups are stored (see `destination`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DestinationConfig {
+    /// Name in output and for `restore --from`; the type if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub kind: DestinationKind,
+    /// Policy for this destination instead of `retention`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<RetentionPolicy>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DestinationKind {
+    /// The local backup directory, where backups are created
+    Local,
+    /// Checkout of `git_remote`, pushed after every change
+    Git,
+    /// `WhatsApp-Backups` in the Google Drive for Desktop folder, if installed
+    GoogleDrive,
+    /// Folder kept in sync by a desktop client (Dropbox, OneDrive, iCloud
+    /// Drive, ...), used if its parent directory exists
+    SyncedFolder { path: PathBuf },
+    /// Bucket on S3 or an S3-compatible service (B2, MinIO, ...)
+    S3(S3Config),
+    /// Directory on an SSH server, over SFTP
+    Sftp(SftpConfig),
+    /// Folder on a WebDAV server such as Nextcloud or ownCloud
+    Webdav(WebdavConfig),
+    /// Path on a remote from the user's rclone config, through `rclone`
+    Rclone(RcloneConfig),
+}
+
+/// Where an S3 destination stores objects
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Prepended to every object key, e.g. `whatsapp/`
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    /// Service URL, for S3-compatible services; AWS if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// `x-amz-storage-class` of stored objects, e.g. `STANDARD_IA`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_class: Option<String>,
+    /// Access key id; `AWS_ACCESS_KEY_ID` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_key_id: Option<String>,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+/// Where an SFTP destination stores files
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SftpConfig {
+    pub host: String,
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+    pub user: String,
+    /// Unencrypted private key; the SSH agent or `~/.ssh/id_*` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_path: Option<PathBuf>,
+    /// Directory on the server holding the backups
+    pub remote_dir: String,
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+/// Where an rclone destination stores files
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RcloneConfig {
+    /// Name of the remote in the rclone config, e.g. `gdrive`
+    pub remote: String,
+    /// Folder on the remote
+    #[serde(default)]
+    pub path: String,
+    /// rclone binary; `rclone` from `PATH` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary: Option<PathBuf>,
+    /// rclone config file; rclone's default if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<PathBuf>,
+}
+
+/// Where a WebDAV destination stores files
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebdavConfig {
+    /// Folder URL, e.g. `https://cloud.example.com/remote.php/dav/files/me/WhatsApp`
+    pub url: String,
+    /// User for basic authentication; none if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl DestinationKind {
+    /// Value of `type` in config
+    pub fn key(&self) -> &'static str {
+        match self {
+            DestinationKind::Local => "local",
+            DestinationKind::Git => "git",
+            DestinationKind::GoogleDrive => "google_drive",
+            DestinationKind::SyncedFolder { .. } => "synced_folder",
+            DestinationKind::S3(_) => "s3",
+            DestinationKind::Sftp(_) => "sftp",
+            DestinationKind::Webdav(_) => "webdav",
+            DestinationKind::Rclone(_) => "rclone",
+        }
+    }
+}
+
+impl DestinationConfig {
+    pub fn new(kind: DestinationKind) -> Self {
+        Self {
+            name: None,
+            kind,
+            retention: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(self.kind.key())
+    }
+}
+
+fn default_destinations() -> Vec<DestinationConfig> {
+    [
+        DestinationKind::Local,
+        DestinationKind::Git,
+        DestinationKind::GoogleDrive,
+    ]
+    .into_iter()
+    .map(DestinationConfig::new)
+    .collect()
+}
+
+/// Where passphrases are kept (see `secrets`)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SecretStoreKind {
+    /// macOS Keychain (`security` command)
+    #[default]
+    Keychain,
+    /// Linux Secret Service over D-Bus (`secret-tool` command)
+    SecretService,
+    /// `pass` password store, entries under `prefix`
+    Pass {
+        #[serde(default = "default_pass_prefix")]
+        prefix: String,
+    },
+    /// Environment variable (read-only)
+    Env {
+        #[serde(default = "default_env_variable")]
+        variable: String,
+    },
+    /// File readable only by the owner
+    File { path: PathBuf },
+    /// Ask on the terminal every time
+    Prompt,
+}
+
+fn default_pass_prefix() -> String {
+    "whatsapp-backup".to_string()
+}
+
+fn default_env_variable() -> String {
+    secrets::DEFAULT_ENV_VARIABLE.to_string()
+}
+
+fn default_repo_name() -> String {
+    "whatsapp-backup-encrypted".to_string()
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub initialized: bool,
-    pub github_repo: Option<String>,
+    /// URL of the git remote backups are pushed to
+    #[serde(alias = "github_repo")]
+    pub git_remote: Option<String>,
+    /// Where the git remote is hosted
+    #[serde(default)]
+    pub git_provider: GitProvider,
+    /// API base URL for Gitea and self-hosted GitLab, e.g. `https://git.example.com`
+    #[serde(default)]
+    pub git_api_url: Option<String>,
+    /// Name of the repo created through the provider
+    #[serde(default = "default_repo_name")]
+    pub git_repo_name: String,
+    /// Local checkout of the git remote, `~/<git_repo_name>` if unset
+    #[serde(default)]
+    pub git_checkout: Option<PathBuf>,
     pub last_backup: Option<DateTime<Utc>>,
-    pub retention_days: u32,
+    /// Where backups are stored, in the order they are copied to
+    #[serde(default = "default_destinations")]
+    pub destinations: Vec<DestinationConfig>,
+    /// Which backups `prune` keeps, by age
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    /// Policies for destinations that keep more or fewer backups than `retention`
+    #[serde(default)]
+    pub destination_retention: DestinationRetention,
+    /// Whether the git remote keeps the history of removed backups
+    #[serde(default, alias = "github_history")]
+    pub git_history: GitHistory,
     pub backup_interval_hours: u32,
+    /// Store backups as deduplicated snapshots instead of full `.enc` files
+    #[serde(default)]
+    pub incremental: bool,
+    /// Keys backups are encrypted to; each can decrypt new backups on its own.
+    /// When empty, backups use the passphrase from the secret store.
+    #[serde(default)]
+    pub key_slots: Vec<KeySlot>,
+    /// Backend holding passphrases
+    #[serde(default)]
+    pub secret_store: SecretStoreKind,
+    /// Ed25519 public key (`wbvk1...`) that manifests must be signed with
+    #[serde(default)]
+    pub signing_public_key: Option<String>,
+    /// Gitignore-style patterns left out of backups, on top of `filter::DEFAULT_EXCLUDES`
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Gitignore-style patterns archived even if excluded
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Also hash the contents of SQLite databases when checking for changes,
+    /// not only their sizes and modification times
+    #[serde(default)]
+    pub fingerprint_databases: bool,
+    /// Fingerprint of the WhatsApp data at the last successful backup
+    #[serde(default)]
+    pub last_fingerprint: Option<String>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             initialized: false,
-            github_repo: None,
+            git_remote: None,
+            git_provider: GitProvider::default(),
+            git_api_url: None,
+            git_repo_name: default_repo_name(),
+            git_checkout: None,
             last_backup: None,
-            retention_days: 7,
+            destinations: default_destinations(),
+            retention: RetentionPolicy::default(),
+            destination_retention: DestinationRetention::default(),
+            git_history: GitHistory::default(),
             backup_interval_hours: 6,
+            incremental: false,
+            key_slots: Vec::new(),
+            secret_store: SecretStoreKind::default(),
+            signing_public_key: None,
+            exclude: Vec::new(),
+            include: Vec::new(),
+            fingerprint_databases: false,
+            last_fingerprint: None,
         }
     }
 }
@@ -33,6 +358,40 @@ impl Config {
         Ok(dir.join(CONFIG_FILE))
     }
 
+    /// Retention policy applied to backups stored at `destination`
+    pub fn retention_for<'a>(&'a self, destination: &'a DestinationConfig) -> &'a RetentionPolicy {
+        destination
+            .retention
+            .as_ref()
+            .or_else(|| self.destination_retention.get(&destination.kind))
+            .unwrap_or(&self.retention)
+    }
+
+    /// Whether backups stay in the local backup directory after being copied
+    /// to the other destinations
+    pub fn keeps_local(&self) -> bool {
+        self.destinations
+            .iter()
+            .any(|d| d.kind == DestinationKind::Local)
+    }
+
+    /// Retention policy of the local backup directory, which also applies to
+    /// incremental snapshots
+    pub fn local_retention(&self) -> &RetentionPolicy {
+        match self
+            .destinations
+            .iter()
+            .find(|d| d.kind == DestinationKind::Local)
+        {
+            Some(local) => self.retention_for(local),
+            None => self
+                .destination_retention
+                .local
+                .as_ref()
+                .unwrap_or(&self.retention),
+        }
+    }
+
     pub fn load() -> Result<Self> {
         let path = Self::config_path()?;
 
@@ -43,7 +402,39 @@ impl Config {
         let content = std::fs::read_to_string(&path)
             .with_context(|| format!("Failed to read config: {}", path.display()))?;
 
-        serde_json::from_str(&content).context("Failed to parse config")
+        Self::parse(&content)
+    }
+
+    /// Parses a config file, migrating the `retention_days` key of older
+    /// versions to a `retention` policy that keeps at least the backups it kept
+    fn parse(content: &str) -> Result<Self> {
+        let mut value: serde_json::Value =
+            serde_json::from_str(content).context("Failed to parse config")?;
+        if let Some(object) = value.as_object_mut() {
+            if let Some(days) = object.remove("retention_days") {
+                if object.contains_key("retention") {
+                    println!("Note: 'retention_days' in config is ignored in favor of 'retention'");
+                } else {
+                    let days = days
+                        .as_u64()
+                        .and_then(|d| u32::try_from(d).ok())
+                        .context("Invalid 'retention_days' in config")?;
+                    let interval = object
+                        .get("backup_interval_hours")
+                        .and_then(|h| h.as_u64())
+                        .filter(|&h| h > 0)
+                        .unwrap_or(6);
+                    let policy = RetentionPolicy::from_days(days, interval as u32);
+                    println!(
+                        "Note: 'retention_days: {}' in config is now 'retention': keep_last {}, \
+                         keep_daily {}",
+                        days, policy.keep_last, policy.keep_daily
+                    );
+                    object.insert("retention".to_string(), serde_json::to_value(policy)?);
+                }
+            }
+        }
+        serde_json::from_value(value).context("Failed to parse config")
     }
 
     pub fn save(&self) -> Result<()> {
@@ -56,14 +447,228 @@ impl Config {
         Ok(())
     }
 
-    pub fn set_initialized(&mut self, github_repo: Option<String>) -> Result<()> {
+    pub fn set_initialized(&mut self, git_remote: Option<String>) -> Result<()> {
         self.initialized = true;
-        self.github_repo = github_repo;
+        self.git_remote = git_remote;
         self.save()
     }
 
-    pub fn update_last_backup(&mut self) -> Result<()> {
+    /// Local checkout of the git remote
+    pub fn git_checkout_dir(&self) -> Result<PathBuf> {
+        match &self.git_checkout {
+            Some(dir) => Ok(dir.clone()),
+            None => {
+                let home = dirs::home_dir().context("Failed to detect home directory")?;
+                Ok(home.join(&self.git_repo_name))
+            }
+        }
+    }
+
+    /// Key slots new backups are encrypted to, including the implicit default
+    pub fn key_slots(&self) -> Vec<KeySlot> {
+        if self.key_slots.is_empty() {
+            vec![KeySlot::default_passphrase()]
+        } else {
+            self.key_slots.clone()
+        }
+    }
+
+    /// Whether any backups are encrypted to public keys
+    pub fn uses_public_keys(&self) -> bool {
+        self.key_slots
+            .iter()
+            .any(|slot| matches!(slot.kind, KeySlotKind::PublicKey { .. }))
+    }
+
+    /// Whether backups are encrypted to the passphrase set by `init`
+    pub fn has_default_passphrase(&self) -> bool {
+        self.key_slots().contains(&KeySlot::default_passphrase())
+    }
+
+    /// Adds a key slot, keeping the implicit default slot if there was one
+    pub fn add_key_slot(&mut self, slot: KeySlot) -> Result<()> {
+        if self.key_slots().iter().any(|s| s.label == slot.label) {
+            anyhow::bail!("A key slot named '{}' already exists", slot.label);
+        }
+        self.key_slots = self.key_slots();
+        self.key_slots.push(slot);
+        Ok(())
+    }
+
+    /// Removes the key slot named `label`; the last slot can't be removed
+    pub fn remove_key_slot(&mut self, label: &str) -> Result<KeySlot> {
+        let mut slots = self.key_slots();
+        let index = slots
+            .iter()
+            .position(|s| s.label == label)
+            .with_context(|| format!("No key slot named '{}'", label))?;
+        if slots.len() == 1 {
+            anyhow::bail!("Can't remove the last key slot - backups need at least one key");
+        }
+        let removed = slots.remove(index);
+        self.key_slots = slots;
+        Ok(removed)
+    }
+
+    /// Records a successful backup of data with `fingerprint`
+    pub fn update_last_backup(&mut self, fingerprint: String) -> Result<()> {
         self.last_backup = Some(Utc::now());
+        self.last_fingerprint = Some(fingerprint);
         self.save()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public_key_slot(label: &str) -> KeySlot {
+        KeySlot {
+            label: label.to_string(),
+            kind: KeySlotKind::PublicKey {
+                public_key: "wbpk1...".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_key_slots_keep_implicit_default() {
+        let mut config = Config::default();
+        assert!(config.has_default_passphrase());
+
+        config.add_key_slot(public_key_slot("recovery")).unwrap();
+        assert_eq!(config.key_slots.len(), 2);
+        assert_eq!(config.key_slots[0], KeySlot::default_passphrase());
+        assert!(config.uses_public_keys());
+        assert!(config.add_key_slot(public_key_slot("recovery")).is_err());
+
+        let removed = config.remove_key_slot("default").unwrap();
+        assert_eq!(removed, KeySlot::default_passphrase());
+        assert!(!config.has_default_passphrase());
+        assert!(config.remove_key_slot("recovery").is_err());
+        assert!(config.remove_key_slot("missing").is_err());
+    }
+
+    #[test]
+    fn test_secret_store_json() {
+        let config = Config::parse(
+            r#"{"initialized": true, "github_repo": "git@github.com:u/r.git", "last_backup": null,
+                "retention_days": 7, "backup_interval_hours": 6}"#,
+        )
+        .unwrap();
+        assert_eq!(config.secret_store, SecretStoreKind::Keychain);
+        assert_eq!(config.git_remote.as_deref(), Some("git@github.com:u/r.git"));
+        assert_eq!(config.git_provider, GitProvider::Github);
+        assert!(config.git_checkout_dir().unwrap().ends_with("whatsapp-backup-encrypted"));
+
+        let kind: SecretStoreKind = serde_json::from_str(r#"{"type": "pass"}"#).unwrap();
+        assert_eq!(
+            kind,
+            SecretStoreKind::Pass {
+                prefix: "whatsapp-backup".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_retention_days_migration() {
+        let config = Config::parse(
+            r#"{"initialized": true, "last_backup": null, "retention_days": 30,
+                "backup_interval_hours": 6}"#,
+        )
+        .unwrap();
+        assert_eq!(config.retention.keep_last, 120);
+        assert_eq!(config.retention.keep_daily, 30);
+        assert!(!serde_json::to_string(&config).unwrap().contains("retention_days"));
+
+        // A short `retention_days` never keeps fewer than the defaults
+        let config = Config::parse(
+            r#"{"initialized": true, "last_backup": null, "retention_days": 1,
+                "backup_interval_hours": 24}"#,
+        )
+        .unwrap();
+        assert_eq!(config.retention, RetentionPolicy::default());
+
+        let config = Config::parse(
+            r#"{"initialized": true, "last_backup": null, "retention_days": 30,
+                "retention": {"keep_last": 2}, "backup_interval_hours": 6}"#,
+        )
+        .unwrap();
+        assert_eq!(config.retention.keep_last, 2);
+        assert_eq!(config.retention.keep_daily, 7);
+
+        assert!(Config::parse(
+            r#"{"initialized": true, "last_backup": null, "retention_days": -1,
+                "backup_interval_hours": 6}"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_destinations_json() {
+        assert_eq!(Config::default().destinations.len(), 3);
+
+        let config: Config = serde_json::from_str(
+            r#"{"initialized": true, "last_backup": null, "backup_interval_hours": 6,
+                "destination_retention": {"github": {"keep_last": 1}},
+                "destinations": [
+                    {"type": "git", "retention": {"keep_last": 2}},
+                    {"type": "google_drive", "name": "drive"},
+                    {"type": "s3", "bucket": "backups", "storage_class": "GLACIER_IR"},
+                    {"type": "sftp", "name": "nas", "host": "nas.local", "user": "backup",
+                     "remote_dir": "/volume1/whatsapp"},
+                    {"type": "webdav", "url": "https://cloud.example.com/dav/WhatsApp"},
+                    {"type": "rclone", "remote": "onedrive"},
+                    {"type": "synced_folder", "path": "/Users/me/Dropbox/WhatsApp"}
+                ]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.destinations[0].name(), "git");
+        assert_eq!(config.destinations[1].name(), "drive");
+        assert_eq!(config.retention_for(&config.destinations[0]).keep_last, 2);
+        let git = DestinationConfig::new(DestinationKind::Git);
+        assert_eq!(config.retention_for(&git).keep_last, 1);
+        assert_eq!(
+            config.retention_for(&config.destinations[1]),
+            &config.retention
+        );
+        assert!(!config.keeps_local());
+        assert_eq!(config.local_retention(), &config.retention);
+
+        let DestinationKind::S3(s3) = &config.destinations[2].kind else {
+            panic!("expected an S3 destination");
+        };
+        assert_eq!(config.destinations[2].name(), "s3");
+        assert_eq!(s3.region, "us-east-1");
+        assert_eq!(s3.prefix, "");
+        assert_eq!(s3.storage_class.as_deref(), Some("GLACIER_IR"));
+        let DestinationKind::Sftp(sftp) = &config.destinations[3].kind else {
+            panic!("expected an SFTP destination");
+        };
+        assert_eq!(sftp.port, 22);
+        assert_eq!(sftp.key_path, None);
+        assert_eq!(config.destinations[4].name(), "webdav");
+        let DestinationKind::Rclone(rclone) = &config.destinations[5].kind else {
+            panic!("expected an rclone destination");
+        };
+        assert_eq!(rclone.path, "");
+        assert_eq!(
+            config.destinations[6].kind,
+            DestinationKind::SyncedFolder {
+                path: PathBuf::from("/Users/me/Dropbox/WhatsApp")
+            }
+        );
+    }
+
+    #[test]
+    fn test_key_slot_json() {
+        let json = r#"{"label": "family", "type": "passphrase", "account": "slot-family"}"#;
+        let slot: KeySlot = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            slot.kind,
+            KeySlotKind::Passphrase {
+                account: "slot-family".to_string()
+            }
+        );
+    }
+}
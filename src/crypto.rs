@@ -1,24 +1,137 @@
+use crate::secrets;
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit, OsRng, Payload},
     Aes256Gcm, Nonce,
 };
 use anyhow::{Context, Result};
-use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+use argon2::{password_hash::SaltString, Algorithm, Argon2, Params, PasswordHasher, Version};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use rand::RngCore;
-use std::path::Path;
-use std::process::Command;
+use sha2::Sha256;
+use std::io::{self, BufRead, Read, Write};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
 
-const KEYCHAIN_SERVICE: &str = "whatsapp-backup";
-const KEYCHAIN_ACCOUNT: &str = "encryption-key";
 const NONCE_SIZE: usize = 12;
 const SALT_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+const FILE_KEY_SIZE: usize = 32;
+/// Sealed file key: [nonce (12)][key (32)][tag (16)]
+const WRAPPED_KEY_SIZE: usize = NONCE_SIZE + FILE_KEY_SIZE + TAG_SIZE;
 
-/// Derives a 256-bit key from passphrase using Argon2id
-fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32]> {
+/// Magic bytes at the start of every streaming-format backup
+const MAGIC: &[u8; 4] = b"WABK";
+/// Segmented stream with a bare salt header (Argon2id defaults, no associated data)
+const FORMAT_STREAM: u8 = 2;
+/// Segmented stream with a self-describing header authenticated as associated data
+const FORMAT_SELF_DESCRIBING: u8 = 3;
+/// Segmented stream under a random file key wrapped in one or more key slots
+const FORMAT_KEY_SLOTS: u8 = 4;
+/// Format version written by this build
+pub const FORMAT_VERSION: u8 = FORMAT_KEY_SLOTS;
+/// Format version of backups without a header ([salt][nonce][ciphertext][tag])
+pub const FORMAT_LEGACY: u8 = 1;
+
+/// Cipher identifier for AES-256-GCM
+const CIPHER_AES256GCM: u8 = 1;
+/// Key slot wrapping the file key under an Argon2-derived passphrase key
+const SLOT_PASSPHRASE: u8 = 1;
+/// Key slot wrapping the file key for an X25519 public key
+const SLOT_X25519: u8 = 2;
+/// HKDF info string for X25519 key wrapping
+const X25519_INFO: &[u8] = b"whatsapp-backup x25519 v1";
+/// HKDF info string for the v4 header MAC key
+const HEADER_MAC_INFO: &[u8] = b"whatsapp-backup header mac v1";
+const HEADER_MAC_SIZE: usize = 32;
+/// Plaintext bytes per encrypted segment
+const SEGMENT_SIZE: usize = 64 * 1024;
+const NONCE_PREFIX_SIZE: usize = 7;
+/// Upper bound on Argon2 memory accepted from a header (4 GiB), so a crafted
+/// file can't make restore allocate without limit
+const MAX_KDF_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+/// Upper bounds on the other Argon2 costs accepted from a header, far above
+/// anything this tool writes, so a crafted file can't make restore run for hours
+const MAX_KDF_ITERATIONS: u32 = 64;
+const MAX_KDF_PARALLELISM: u32 = 64;
+
+/// Argon2 parameters used to derive the key of a backup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: Algorithm,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// `Argon2::default()` as used by v1 and v2 backups
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::Argon2id,
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    fn algorithm_id(&self) -> u8 {
+        match self.algorithm {
+            Algorithm::Argon2d => 0,
+            Algorithm::Argon2i => 1,
+            Algorithm::Argon2id => 2,
+        }
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        bytes.push(self.algorithm_id());
+        bytes.extend_from_slice(&self.memory_kib.to_be_bytes());
+        bytes.extend_from_slice(&self.iterations.to_be_bytes());
+        bytes.extend_from_slice(&self.parallelism.to_be_bytes());
+    }
+
+    /// Parses [argon2 variant (1)][memory KiB (4)][iterations (4)][parallelism (4)]
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut fixed = [0u8; 13];
+        reader.read_exact(&mut fixed).context("Encrypted header too short")?;
+        let word = |i: usize| u32::from_be_bytes(fixed[i..i + 4].try_into().unwrap());
+
+        let algorithm = match fixed[0] {
+            0 => Algorithm::Argon2d,
+            1 => Algorithm::Argon2i,
+            2 => Algorithm::Argon2id,
+            other => anyhow::bail!("Unknown Argon2 variant in header: {}", other),
+        };
+        let memory_kib = word(1);
+        if memory_kib > MAX_KDF_MEMORY_KIB {
+            anyhow::bail!("Argon2 memory cost in header is too large: {} KiB", memory_kib);
+        }
+        let iterations = word(5);
+        if iterations > MAX_KDF_ITERATIONS {
+            anyhow::bail!("Argon2 iterations in header are too many: {}", iterations);
+        }
+        let parallelism = word(9);
+        if parallelism > MAX_KDF_PARALLELISM {
+            anyhow::bail!("Argon2 parallelism in header is too large: {}", parallelism);
+        }
+        Ok(Self {
+            algorithm,
+            memory_kib,
+            iterations,
+            parallelism,
+        })
+    }
+}
+
+/// Derives a 256-bit key from passphrase using Argon2
+fn derive_key(passphrase: &str, salt: &[u8], kdf: &KdfParams) -> Result<[u8; 32]> {
     let salt_string = SaltString::encode_b64(salt)
         .map_err(|e| anyhow::anyhow!("Failed to encode salt: {}", e))?;
 
-    let argon2 = Argon2::default();
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, None)
+        .map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {}", e))?;
+    let argon2 = Argon2::new(kdf.algorithm, Version::V0x13, params);
     let hash = argon2
         .hash_password(passphrase.as_bytes(), &salt_string)
         .map_err(|e| anyhow::anyhow!("Failed to derive key: {}", e))?;
@@ -31,35 +144,783 @@ fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32]> {
     Ok(key)
 }
 
-/// Encrypts data using AES-256-GCM
-/// Format: [salt (16 bytes)][nonce (12 bytes)][ciphertext][tag (16 bytes)]
-pub fn encrypt(data: &[u8], passphrase: &str) -> Result<Vec<u8>> {
-    let mut salt = [0u8; SALT_SIZE];
-    OsRng.fill_bytes(&mut salt);
+/// Derives the key that wraps a file key for an X25519 recipient
+fn x25519_wrap_key(shared: &[u8; 32], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> [u8; 32] {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral);
+    salt[32..].copy_from_slice(recipient);
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(X25519_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
 
-    let key = derive_key(passphrase, &salt)?;
-    let cipher = Aes256Gcm::new_from_slice(&key)
-        .map_err(|e| anyhow::anyhow!("Failed to create cipher: {}", e))?;
+/// Builds a STREAM nonce: [prefix (7 bytes)][segment counter (4 bytes BE)][last flag (1 byte)]
+fn segment_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], counter: u32, last: bool) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..NONCE_SIZE - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_SIZE - 1] = last as u8;
+    nonce
+}
+
+fn new_cipher(key: &[u8; 32]) -> Result<Aes256Gcm> {
+    Aes256Gcm::new_from_slice(key).map_err(|e| anyhow::anyhow!("Failed to create cipher: {}", e))
+}
 
-    let mut nonce_bytes = [0u8; NONCE_SIZE];
-    OsRng.fill_bytes(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+/// Encrypts a small object under a raw key with a random nonce.
+/// Format: [nonce (12 bytes)][ciphertext][tag (16 bytes)]
+pub fn seal(key: &[u8; 32], aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
 
-    let ciphertext = cipher
-        .encrypt(nonce, data)
+    let ciphertext = new_cipher(key)?
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad })
         .map_err(|e| anyhow::anyhow!("Encryption failed: {}", e))?;
 
-    let mut result = Vec::with_capacity(SALT_SIZE + NONCE_SIZE + ciphertext.len());
-    result.extend_from_slice(&salt);
-    result.extend_from_slice(&nonce_bytes);
-    result.extend_from_slice(&ciphertext);
+    let mut sealed = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Decrypts an object produced by `seal`
+pub fn open(key: &[u8; 32], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_SIZE + TAG_SIZE {
+        anyhow::bail!("Encrypted object too short");
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    new_cipher(key)?
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| anyhow::anyhow!("Decryption failed - wrong key or corrupted object"))
+}
+
+/// Someone a backup is encrypted to
+pub enum Recipient {
+    /// Anyone who knows the passphrase
+    Passphrase { passphrase: String, kdf: KdfParams },
+    /// Holder of the matching X25519 secret key
+    PublicKey(PublicKey),
+}
+
+impl Recipient {
+    /// Passphrase recipient with the default Argon2 parameters
+    pub fn passphrase(passphrase: &str) -> Self {
+        Recipient::Passphrase {
+            passphrase: passphrase.to_string(),
+            kdf: KdfParams::default(),
+        }
+    }
+}
+
+/// Secret able to unlock a key slot
+pub enum Identity {
+    Passphrase(String),
+    SecretKey(StaticSecret),
+}
+
+/// File key wrapped for one recipient, as stored in a v4 header
+#[derive(Debug, Clone)]
+pub enum KeySlot {
+    Passphrase {
+        kdf: KdfParams,
+        salt: Vec<u8>,
+        wrapped: Vec<u8>,
+    },
+    X25519 {
+        ephemeral: [u8; 32],
+        wrapped: Vec<u8>,
+    },
+    /// Slot type from a newer version, kept as is so rewrapping other slots
+    /// doesn't drop it
+    Unknown { slot_type: u8, body: Vec<u8> },
+}
+
+impl KeySlot {
+    /// Wraps `file_key` for `recipient`
+    fn wrap(file_key: &[u8; FILE_KEY_SIZE], recipient: &Recipient) -> Result<Self> {
+        match recipient {
+            Recipient::Passphrase { passphrase, kdf } => {
+                let mut salt = vec![0u8; SALT_SIZE];
+                OsRng.fill_bytes(&mut salt);
+                let key = derive_key(passphrase, &salt, kdf)?;
+                Ok(KeySlot::Passphrase {
+                    kdf: *kdf,
+                    salt,
+                    wrapped: seal(&key, &[], file_key)?,
+                })
+            }
+            Recipient::PublicKey(public_key) => {
+                let secret = EphemeralSecret::random_from_rng(OsRng);
+                let ephemeral = PublicKey::from(&secret).to_bytes();
+                let shared = secret.diffie_hellman(public_key);
+                let key = x25519_wrap_key(shared.as_bytes(), &ephemeral, public_key.as_bytes());
+                Ok(KeySlot::X25519 {
+                    ephemeral,
+                    wrapped: seal(&key, &[], file_key)?,
+                })
+            }
+        }
+    }
+
+    /// Tries to recover the file key with `identity`; `None` if it doesn't match
+    fn unwrap_with(&self, identity: &Identity) -> Result<Option<[u8; FILE_KEY_SIZE]>> {
+        let opened = match (self, identity) {
+            (KeySlot::Passphrase { kdf, salt, wrapped }, Identity::Passphrase(passphrase)) => {
+                let key = derive_key(passphrase, salt, kdf)?;
+                open(&key, &[], wrapped).ok()
+            }
+            (KeySlot::X25519 { ephemeral, wrapped }, Identity::SecretKey(secret)) => {
+                let recipient = PublicKey::from(secret);
+                let shared = secret.diffie_hellman(&PublicKey::from(*ephemeral));
+                let key = x25519_wrap_key(shared.as_bytes(), ephemeral, recipient.as_bytes());
+                open(&key, &[], wrapped).ok()
+            }
+            _ => None,
+        };
+
+        Ok(opened.and_then(|key| key.try_into().ok()))
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            KeySlot::Passphrase { .. } => "passphrase",
+            KeySlot::X25519 { .. } => "x25519",
+            KeySlot::Unknown { .. } => "unknown",
+        }
+    }
+
+    /// Serializes as [slot type (1)][body length (2)][body]
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        let mut body = Vec::new();
+        let slot_type = match self {
+            KeySlot::Passphrase { kdf, salt, wrapped } => {
+                kdf.write_to(&mut body);
+                body.push(salt.len() as u8);
+                body.extend_from_slice(salt);
+                body.extend_from_slice(wrapped);
+                SLOT_PASSPHRASE
+            }
+            KeySlot::X25519 { ephemeral, wrapped } => {
+                body.extend_from_slice(ephemeral);
+                body.extend_from_slice(wrapped);
+                SLOT_X25519
+            }
+            KeySlot::Unknown { slot_type, body: raw } => {
+                body.extend_from_slice(raw);
+                *slot_type
+            }
+        };
+        bytes.push(slot_type);
+        bytes.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&body);
+    }
+
+    /// Parses one slot; unknown slot types are kept as `Unknown`
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut prefix = [0u8; 3];
+        reader.read_exact(&mut prefix).context("Encrypted header too short")?;
+        let mut raw = vec![0u8; u16::from_be_bytes([prefix[1], prefix[2]]) as usize];
+        reader.read_exact(&mut raw).context("Encrypted header too short")?;
+        let mut body = raw.as_slice();
+
+        let slot = match prefix[0] {
+            SLOT_PASSPHRASE => {
+                let kdf = KdfParams::read_from(&mut body)?;
+                let (&salt_len, rest) = body.split_first().context("Invalid key slot")?;
+                if rest.len() != salt_len as usize + WRAPPED_KEY_SIZE {
+                    anyhow::bail!("Invalid passphrase key slot");
+                }
+                let (salt, wrapped) = rest.split_at(salt_len as usize);
+                KeySlot::Passphrase {
+                    kdf,
+                    salt: salt.to_vec(),
+                    wrapped: wrapped.to_vec(),
+                }
+            }
+            SLOT_X25519 => {
+                if body.len() != 32 + WRAPPED_KEY_SIZE {
+                    anyhow::bail!("Invalid X25519 key slot");
+                }
+                let (ephemeral, wrapped) = body.split_at(32);
+                KeySlot::X25519 {
+                    ephemeral: ephemeral.try_into().unwrap(),
+                    wrapped: wrapped.to_vec(),
+                }
+            }
+            slot_type => KeySlot::Unknown {
+                slot_type,
+                body: raw.clone(),
+            },
+        };
+        Ok(slot)
+    }
+}
+
+/// Where the stream key of a backup comes from
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    /// v2/v3: derived directly from the passphrase
+    Passphrase { kdf: KdfParams, salt: Vec<u8> },
+    /// v4: random file key wrapped in key slots
+    Slots(Vec<KeySlot>),
+}
+
+/// Header of a streaming-format backup
+///
+/// v4 layout (all integers big-endian):
+/// [magic "WABK"][version (1)][cipher id (1)][nonce prefix (7)][slot count (1)]
+/// followed by key slots of [slot type (1)][body length (2)][body]:
+/// - passphrase: [argon2 variant (1)][memory KiB (4)][iterations (4)]
+///   [parallelism (4)][salt len (1)][salt][wrapped key (60)]
+/// - x25519: [ephemeral public key (32)][wrapped key (60)]
+///
+/// and a final [header MAC (32)]: HMAC-SHA256 of everything before it, keyed
+/// from the file key. Segments authenticate only the fixed part up to the
+/// nonce prefix, so key slots can be rewrapped without re-encrypting the data.
+///
+/// v3 layout: [magic][version][cipher id][argon2 variant][memory KiB (4)]
+/// [iterations (4)][parallelism (4)][salt len (1)][salt][nonce prefix (7)]
+///
+/// The raw v3 header bytes are passed as associated data to every segment,
+/// so tampering with the header fails authentication. v2 headers carry
+/// only [magic][version][salt (16)][nonce prefix (7)] and imply Argon2 defaults.
+#[derive(Debug, Clone)]
+pub struct StreamHeader {
+    pub version: u8,
+    pub keys: KeySource,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    mac: [u8; HEADER_MAC_SIZE],
+    associated_data: Vec<u8>,
+    /// v4 header bytes before the MAC exactly as read, which the MAC is
+    /// checked against; empty for headers built or changed here
+    raw: Vec<u8>,
+}
+
+impl StreamHeader {
+    /// Creates a v4 header wrapping a fresh file key for every recipient
+    fn generate(recipients: &[Recipient]) -> Result<(Self, [u8; FILE_KEY_SIZE])> {
+        if recipients.is_empty() {
+            anyhow::bail!("No recipients to encrypt to");
+        }
+        if recipients.len() > u8::MAX as usize {
+            anyhow::bail!("Too many recipients: {}", recipients.len());
+        }
+
+        let mut file_key = [0u8; FILE_KEY_SIZE];
+        OsRng.fill_bytes(&mut file_key);
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut nonce_prefix);
+
+        let slots = recipients
+            .iter()
+            .map(|recipient| KeySlot::wrap(&file_key, recipient))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut header = Self {
+            version: FORMAT_KEY_SLOTS,
+            keys: KeySource::Slots(slots),
+            nonce_prefix,
+            mac: [0u8; HEADER_MAC_SIZE],
+            associated_data: Vec::new(),
+            raw: Vec::new(),
+        };
+        header.mac = header.compute_mac(&file_key);
+        header.associated_data = header.segment_aad();
+        Ok((header, file_key))
+    }
+
+    /// Returns a copy of this v4 header in which every slot that `old` opens is
+    /// replaced by a slot for `new`, or `None` if no slot matches `old`.
+    /// The data that follows the header is unchanged.
+    pub fn rekey(&self, old: &Identity, new: &Recipient) -> Result<Option<Self>> {
+        let KeySource::Slots(slots) = &self.keys else {
+            anyhow::bail!("Only v{} backups have key slots", FORMAT_KEY_SLOTS);
+        };
+
+        let mut file_key = None;
+        let mut kept = Vec::with_capacity(slots.len());
+        for slot in slots {
+            match slot.unwrap_with(old)? {
+                Some(key) => file_key = Some(key),
+                None => kept.push(slot.clone()),
+            }
+        }
+        let Some(file_key) = file_key else {
+            return Ok(None);
+        };
+        if !self.verify_mac(&file_key) {
+            anyhow::bail!("Header authentication failed - corrupted or tampered header");
+        }
+
+        kept.push(KeySlot::wrap(&file_key, new)?);
+        let mut header = Self {
+            keys: KeySource::Slots(kept),
+            raw: Vec::new(),
+            ..self.clone()
+        };
+        header.mac = header.compute_mac(&file_key);
+        Ok(Some(header))
+    }
+
+    /// Returns a copy of this v4 header with an extra slot for `new`, or `None`
+    /// if none of `identities` opens a slot. The data that follows the header
+    /// is unchanged.
+    pub fn add_slot(&self, identities: &[Identity], new: &Recipient) -> Result<Option<Self>> {
+        let KeySource::Slots(slots) = &self.keys else {
+            anyhow::bail!("Only v{} backups have key slots", FORMAT_KEY_SLOTS);
+        };
+
+        let mut file_key = None;
+        'slots: for slot in slots {
+            for identity in identities {
+                if let Some(key) = slot.unwrap_with(identity)? {
+                    file_key = Some(key);
+                    break 'slots;
+                }
+            }
+        }
+        let Some(file_key) = file_key else {
+            return Ok(None);
+        };
+        if !self.verify_mac(&file_key) {
+            anyhow::bail!("Header authentication failed - corrupted or tampered header");
+        }
+
+        let mut slots = slots.clone();
+        slots.push(KeySlot::wrap(&file_key, new)?);
+        let mut header = Self {
+            keys: KeySource::Slots(slots),
+            raw: Vec::new(),
+            ..self.clone()
+        };
+        header.mac = header.compute_mac(&file_key);
+        Ok(Some(header))
+    }
+
+    /// Serialized header, as written before the first segment
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.unauthenticated_bytes();
+        if matches!(self.keys, KeySource::Slots(_)) {
+            bytes.extend_from_slice(&self.mac);
+        }
+        bytes
+    }
+
+    /// Header bytes without the trailing v4 header MAC
+    fn unauthenticated_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(256);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(self.version);
+        bytes.push(CIPHER_AES256GCM);
+
+        match &self.keys {
+            KeySource::Passphrase { kdf, salt } => {
+                kdf.write_to(&mut bytes);
+                bytes.push(salt.len() as u8);
+                bytes.extend_from_slice(salt);
+                bytes.extend_from_slice(&self.nonce_prefix);
+            }
+            KeySource::Slots(slots) => {
+                bytes.extend_from_slice(&self.nonce_prefix);
+                bytes.push(slots.len() as u8);
+                for slot in slots {
+                    slot.write_to(&mut bytes);
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Bytes authenticated with every segment: the whole v3 header, or the
+    /// fixed part of a v4 header (its slots are covered by the header MAC)
+    fn segment_aad(&self) -> Vec<u8> {
+        let mut bytes = self.to_bytes();
+        if matches!(self.keys, KeySource::Slots(_)) {
+            bytes.truncate(MAGIC.len() + 2 + NONCE_PREFIX_SIZE);
+        }
+        bytes
+    }
+
+    fn mac_key(file_key: &[u8; FILE_KEY_SIZE]) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, file_key)
+            .expand(HEADER_MAC_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        key
+    }
+
+    fn header_mac(&self, file_key: &[u8; FILE_KEY_SIZE]) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&Self::mac_key(file_key))
+            .expect("HMAC accepts any key size");
+        if self.raw.is_empty() {
+            mac.update(&self.unauthenticated_bytes());
+        } else {
+            mac.update(&self.raw);
+        }
+        mac
+    }
+
+    fn compute_mac(&self, file_key: &[u8; FILE_KEY_SIZE]) -> [u8; HEADER_MAC_SIZE] {
+        self.header_mac(file_key).finalize().into_bytes().into()
+    }
+
+    fn verify_mac(&self, file_key: &[u8; FILE_KEY_SIZE]) -> bool {
+        self.header_mac(file_key).verify_slice(&self.mac).is_ok()
+    }
+
+    /// Parses the header following the magic bytes
+    fn read_after_magic<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut reader = Recording {
+            inner: reader,
+            bytes: MAGIC.to_vec(),
+        };
+        let reader = &mut reader;
+        let mut version = [0u8; 1];
+        reader.read_exact(&mut version).context("Encrypted data too short")?;
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+
+        match version[0] {
+            FORMAT_STREAM => {
+                let mut salt = vec![0u8; SALT_SIZE];
+                reader.read_exact(&mut salt).context("Encrypted data too short")?;
+                reader
+                    .read_exact(&mut nonce_prefix)
+                    .context("Encrypted data too short")?;
+                return Ok(Self {
+                    version: FORMAT_STREAM,
+                    keys: KeySource::Passphrase {
+                        kdf: KdfParams::default(),
+                        salt,
+                    },
+                    nonce_prefix,
+                    mac: [0u8; HEADER_MAC_SIZE],
+                    associated_data: Vec::new(),
+                    raw: Vec::new(),
+                });
+            }
+            FORMAT_SELF_DESCRIBING | FORMAT_KEY_SLOTS => {}
+            other => anyhow::bail!("Unsupported backup format version: {}", other),
+        }
+
+        let mut cipher = [0u8; 1];
+        reader.read_exact(&mut cipher).context("Encrypted header too short")?;
+        if cipher[0] != CIPHER_AES256GCM {
+            anyhow::bail!("Unsupported cipher id in header: {}", cipher[0]);
+        }
+
+        let mut mac = [0u8; HEADER_MAC_SIZE];
+        let mut raw = Vec::new();
+        let keys = if version[0] == FORMAT_SELF_DESCRIBING {
+            let kdf = KdfParams::read_from(reader)?;
+            let mut salt_len = [0u8; 1];
+            reader.read_exact(&mut salt_len).context("Encrypted header too short")?;
+            let mut salt = vec![0u8; salt_len[0] as usize];
+            reader.read_exact(&mut salt).context("Encrypted header too short")?;
+            reader
+                .read_exact(&mut nonce_prefix)
+                .context("Encrypted header too short")?;
+            KeySource::Passphrase { kdf, salt }
+        } else {
+            reader
+                .read_exact(&mut nonce_prefix)
+                .context("Encrypted header too short")?;
+            let mut count = [0u8; 1];
+            reader.read_exact(&mut count).context("Encrypted header too short")?;
+            let mut slots = Vec::with_capacity(count[0] as usize);
+            for _ in 0..count[0] {
+                slots.push(KeySlot::read_from(reader)?);
+            }
+            raw = std::mem::take(&mut reader.bytes);
+            reader.inner.read_exact(&mut mac).context("Encrypted header too short")?;
+            KeySource::Slots(slots)
+        };
+
+        let mut header = Self {
+            version: version[0],
+            keys,
+            nonce_prefix,
+            mac,
+            associated_data: Vec::new(),
+            raw,
+        };
+        header.associated_data = header.segment_aad();
+        Ok(header)
+    }
+
+    /// Recovers the stream key using the first identity that fits
+    fn stream_key(&self, identities: &[Identity]) -> Result<[u8; 32]> {
+        match &self.keys {
+            KeySource::Passphrase { kdf, salt } => {
+                let passphrase = identities
+                    .iter()
+                    .find_map(|identity| match identity {
+                        Identity::Passphrase(passphrase) => Some(passphrase),
+                        Identity::SecretKey(_) => None,
+                    })
+                    .context("This backup can only be decrypted with a passphrase")?;
+                derive_key(passphrase, salt, kdf)
+            }
+            KeySource::Slots(slots) => {
+                for slot in slots {
+                    for identity in identities {
+                        if let Some(key) = slot.unwrap_with(identity)? {
+                            if !self.verify_mac(&key) {
+                                anyhow::bail!(
+                                    "Header authentication failed - corrupted or tampered header"
+                                );
+                            }
+                            return Ok(key);
+                        }
+                    }
+                }
+                anyhow::bail!("No key slot matches - wrong passphrase or key")
+            }
+        }
+    }
+}
+
+/// Encryption format of a backup, detected from its first bytes
+#[derive(Debug, Clone)]
+pub enum Header {
+    /// v1: no header, whole file sealed with a single tag
+    Legacy,
+    /// v2 and later: segmented stream
+    Stream(StreamHeader),
+}
+
+/// Reader that keeps a copy of everything read through it
+struct Recording<'a, R: Read> {
+    inner: &'a mut R,
+    bytes: Vec<u8>,
+}
+
+impl<R: Read> Read for Recording<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl Header {
+    pub fn format_version(&self) -> u8 {
+        match self {
+            Header::Legacy => FORMAT_LEGACY,
+            Header::Stream(header) => header.version,
+        }
+    }
+
+    /// Short human-readable description of the format
+    pub fn describe(&self) -> String {
+        match self {
+            Header::Legacy => "v1 (AES-256-GCM, Argon2id defaults)".to_string(),
+            Header::Stream(StreamHeader {
+                version,
+                keys: KeySource::Passphrase { kdf, .. },
+                ..
+            }) => format!(
+                "v{} (AES-256-GCM stream, {:?} m={} KiB t={} p={})",
+                version, kdf.algorithm, kdf.memory_kib, kdf.iterations, kdf.parallelism
+            ),
+            Header::Stream(StreamHeader {
+                version,
+                keys: KeySource::Slots(slots),
+                ..
+            }) => format!(
+                "v{} (AES-256-GCM stream, key slots: {})",
+                version,
+                slots.iter().map(KeySlot::kind).collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+}
+
+/// Reads the header of an encrypted backup. Legacy files have no magic bytes,
+/// so nothing is consumed from the reader in that case.
+pub fn read_header<R: BufRead>(reader: &mut R) -> Result<Header> {
+    let buffered = reader.fill_buf()?;
+    if !buffered.starts_with(MAGIC) {
+        return Ok(Header::Legacy);
+    }
+    reader.consume(MAGIC.len());
+    Ok(Header::Stream(StreamHeader::read_after_magic(reader)?))
+}
+
+/// Streaming encryptor using segment-wise AES-256-GCM (STREAM construction)
+///
+/// The header is followed by segments of [ciphertext (up to 64 KiB)][tag (16 bytes)].
+/// The last segment is sealed with the final flag set in its nonce, so
+/// truncation at a segment boundary is detected on decryption.
+pub struct StreamEncryptor<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    header: StreamHeader,
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> StreamEncryptor<W> {
+    /// Writes the header and returns an encryptor ready to accept plaintext
+    pub fn new(inner: W, passphrase: &str) -> Result<Self> {
+        Self::for_recipients(inner, &[Recipient::passphrase(passphrase)])
+    }
+
+    /// Like `new`, encrypting to any set of recipients
+    pub fn for_recipients(mut inner: W, recipients: &[Recipient]) -> Result<Self> {
+        let (header, file_key) = StreamHeader::generate(recipients)?;
+        let cipher = new_cipher(&file_key)?;
+
+        inner.write_all(&header.to_bytes())?;
+
+        Ok(Self {
+            inner,
+            cipher,
+            header,
+            counter: 0,
+            buffer: Vec::with_capacity(SEGMENT_SIZE),
+        })
+    }
+
+    fn seal_segment(&mut self, last: bool) -> io::Result<()> {
+        let nonce = segment_nonce(&self.header.nonce_prefix, self.counter, last);
+        let payload = Payload {
+            msg: &self.buffer,
+            aad: &self.header.associated_data,
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| io::Error::other("Encryption failed"))?;
+        self.inner.write_all(&ciphertext)?;
+        self.buffer.clear();
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("Too many segments in encrypted stream"))?;
+        Ok(())
+    }
+
+    /// Seals the final segment and returns the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        self.seal_segment(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
 
-    Ok(result)
+impl<W: Write> Write for StreamEncryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full segment is only sealed once more data arrives, so that the
+        // last segment can always be marked as final in finish()
+        if self.buffer.len() == SEGMENT_SIZE && !buf.is_empty() {
+            self.seal_segment(false)?;
+        }
+        let n = buf.len().min(SEGMENT_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Streaming decryptor for the segmented format written by `StreamEncryptor`
+pub struct StreamDecryptor<R: Read> {
+    inner: R,
+    cipher: Aes256Gcm,
+    header: StreamHeader,
+    counter: u32,
+    ciphertext: Vec<u8>,
+    plaintext: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> StreamDecryptor<R> {
+    /// Recovers the stream key for an already-parsed header
+    fn new(inner: R, header: StreamHeader, identities: &[Identity]) -> Result<Self> {
+        let key = header.stream_key(identities)?;
+
+        Ok(Self {
+            inner,
+            cipher: new_cipher(&key)?,
+            header,
+            counter: 0,
+            ciphertext: Vec::with_capacity(SEGMENT_SIZE + TAG_SIZE + 1),
+            plaintext: Vec::new(),
+            position: 0,
+            finished: false,
+        })
+    }
+
+    /// Decrypts the next segment into the plaintext buffer
+    fn next_segment(&mut self) -> io::Result<()> {
+        // Read one byte past a full segment to learn whether this one is the last
+        let want = SEGMENT_SIZE + TAG_SIZE + 1;
+        while self.ciphertext.len() < want {
+            let start = self.ciphertext.len();
+            self.ciphertext.resize(want, 0);
+            let n = self.inner.read(&mut self.ciphertext[start..])?;
+            self.ciphertext.truncate(start + n);
+            if n == 0 {
+                break;
+            }
+        }
+
+        let last = self.ciphertext.len() < want;
+        let segment_len = if last {
+            self.ciphertext.len()
+        } else {
+            SEGMENT_SIZE + TAG_SIZE
+        };
+
+        let nonce = segment_nonce(&self.header.nonce_prefix, self.counter, last);
+        let payload = Payload {
+            msg: &self.ciphertext[..segment_len],
+            aad: &self.header.associated_data,
+        };
+        self.plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Decryption failed - wrong passphrase or corrupted data",
+                )
+            })?;
+        self.ciphertext.drain(..segment_len);
+        self.position = 0;
+        self.finished = last;
+        self.counter = self.counter.checked_add(1).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Too many segments in encrypted stream")
+        })?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.next_segment()?;
+        }
+        let n = buf.len().min(self.plaintext.len() - self.position);
+        buf[..n].copy_from_slice(&self.plaintext[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
 }
 
-/// Decrypts data encrypted with AES-256-GCM
-pub fn decrypt(encrypted: &[u8], passphrase: &str) -> Result<Vec<u8>> {
-    if encrypted.len() < SALT_SIZE + NONCE_SIZE + 16 {
+/// Decrypts a legacy v1 backup: [salt (16 bytes)][nonce (12 bytes)][ciphertext][tag (16 bytes)]
+fn decrypt_v1(encrypted: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    if encrypted.len() < SALT_SIZE + NONCE_SIZE + TAG_SIZE {
         anyhow::bail!("Encrypted data too short");
     }
 
@@ -67,9 +928,8 @@ pub fn decrypt(encrypted: &[u8], passphrase: &str) -> Result<Vec<u8>> {
     let nonce_bytes = &encrypted[SALT_SIZE..SALT_SIZE + NONCE_SIZE];
     let ciphertext = &encrypted[SALT_SIZE + NONCE_SIZE..];
 
-    let key = derive_key(passphrase, salt)?;
-    let cipher = Aes256Gcm::new_from_slice(&key)
-        .map_err(|e| anyhow::anyhow!("Failed to create cipher: {}", e))?;
+    let key = derive_key(passphrase, salt, &KdfParams::default())?;
+    let cipher = new_cipher(&key)?;
 
     let nonce = Nonce::from_slice(nonce_bytes);
 
@@ -78,131 +938,364 @@ pub fn decrypt(encrypted: &[u8], passphrase: &str) -> Result<Vec<u8>> {
         .map_err(|_| anyhow::anyhow!("Decryption failed - wrong passphrase or corrupted data"))
 }
 
-/// Encrypts a file and writes to output path
-pub fn encrypt_file(input: &Path, output: &Path, passphrase: &str) -> Result<()> {
-    let data = std::fs::read(input)
-        .with_context(|| format!("Failed to read file: {}", input.display()))?;
-
-    let encrypted = encrypt(&data, passphrase)?;
-
-    std::fs::write(output, &encrypted)
-        .with_context(|| format!("Failed to write encrypted file: {}", output.display()))?;
-
-    Ok(())
+/// Returns a reader yielding the decrypted plaintext, using the decoder that
+/// matches `header` (as returned by `read_header` on the same reader).
+/// Streaming backups decrypt in constant memory; legacy v1 backups have a
+/// single tag over the whole file and are decrypted in memory.
+pub fn decrypt_reader<'a, R: Read + 'a>(
+    mut reader: R,
+    header: Header,
+    identities: &[Identity],
+) -> Result<Box<dyn Read + 'a>> {
+    match header {
+        Header::Stream(header) => Ok(Box::new(StreamDecryptor::new(reader, header, identities)?)),
+        Header::Legacy => {
+            let passphrase = identities
+                .iter()
+                .find_map(|identity| match identity {
+                    Identity::Passphrase(passphrase) => Some(passphrase),
+                    Identity::SecretKey(_) => None,
+                })
+                .context("This backup can only be decrypted with a passphrase")?;
+            let mut encrypted = Vec::new();
+            reader.read_to_end(&mut encrypted)?;
+            let decrypted = decrypt_v1(&encrypted, passphrase)?;
+            Ok(Box::new(io::Cursor::new(decrypted)))
+        }
+    }
 }
 
-/// Decrypts a file and writes to output path
-pub fn decrypt_file(input: &Path, output: &Path, passphrase: &str) -> Result<()> {
-    let encrypted = std::fs::read(input)
-        .with_context(|| format!("Failed to read encrypted file: {}", input.display()))?;
-
-    let decrypted = decrypt(&encrypted, passphrase)?;
-
-    std::fs::write(output, &decrypted)
-        .with_context(|| format!("Failed to write decrypted file: {}", output.display()))?;
+/// Stores the passphrase in the configured secret store
+pub fn store_passphrase(passphrase: &str) -> Result<()> {
+    store_passphrase_for(secrets::DEFAULT_ACCOUNT, passphrase)
+}
 
-    Ok(())
+/// Stores a passphrase in the configured secret store under `account`
+pub fn store_passphrase_for(account: &str, passphrase: &str) -> Result<()> {
+    secrets::configured()?.store(account, passphrase)
 }
 
-/// Stores passphrase in macOS Keychain using security command
-pub fn store_passphrase(passphrase: &str) -> Result<()> {
-    // First try to delete any existing entry
-    let _ = Command::new("security")
-        .args([
-            "delete-generic-password",
-            "-s", KEYCHAIN_SERVICE,
-            "-a", KEYCHAIN_ACCOUNT,
-        ])
-        .output();
-
-    // Add new entry
-    let output = Command::new("security")
-        .args([
-            "add-generic-password",
-            "-s", KEYCHAIN_SERVICE,
-            "-a", KEYCHAIN_ACCOUNT,
-            "-w", passphrase,
-            "-U", // Update if exists
-        ])
-        .output()
-        .context("Failed to run security command")?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("Failed to store passphrase in keychain: {}", stderr);
-    }
-
-    Ok(())
-}
-
-/// Retrieves passphrase from macOS Keychain using security command
+/// Retrieves the passphrase from the configured secret store
 pub fn get_passphrase() -> Result<String> {
-    let output = Command::new("security")
-        .args([
-            "find-generic-password",
-            "-s", KEYCHAIN_SERVICE,
-            "-a", KEYCHAIN_ACCOUNT,
-            "-w", // Output password only
-        ])
-        .output()
-        .context("Failed to run security command")?;
-
-    if !output.status.success() {
-        anyhow::bail!(
-            "Failed to retrieve passphrase from keychain.\n\
-             Run 'whatsapp-backup init' to set up encryption."
-        );
-    }
+    get_passphrase_for(secrets::DEFAULT_ACCOUNT)
+}
 
-    let passphrase = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    Ok(passphrase)
+/// Retrieves the passphrase stored under `account`
+pub fn get_passphrase_for(account: &str) -> Result<String> {
+    secrets::configured()?.get(account)
 }
 
-/// Checks if passphrase exists in keychain
+/// Checks if the passphrase is available from the configured secret store
 pub fn has_passphrase() -> bool {
-    get_passphrase().is_ok()
+    secrets::configured()
+        .map(|store| store.has(secrets::DEFAULT_ACCOUNT))
+        .unwrap_or(false)
 }
 
-/// Deletes passphrase from keychain
-pub fn delete_passphrase() -> Result<()> {
-    let output = Command::new("security")
-        .args([
-            "delete-generic-password",
-            "-s", KEYCHAIN_SERVICE,
-            "-a", KEYCHAIN_ACCOUNT,
-        ])
-        .output()
-        .context("Failed to run security command")?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("Failed to delete passphrase: {}", stderr);
-    }
-
-    Ok(())
+/// Deletes the passphrase stored under `account`
+pub fn delete_passphrase_for(account: &str) -> Result<()> {
+    secrets::configured()?.delete(account)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn encrypt(data: &[u8], passphrase: &str) -> Vec<u8> {
+        let mut encryptor = StreamEncryptor::new(Vec::new(), passphrase).unwrap();
+        encryptor.write_all(data).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt(encrypted: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+        decrypt_with(encrypted, &[Identity::Passphrase(passphrase.to_string())])
+    }
+
+    fn decrypt_with(mut encrypted: &[u8], identities: &[Identity]) -> Result<Vec<u8>> {
+        let header = read_header(&mut encrypted)?;
+        let mut reader = decrypt_reader(encrypted, header, identities)?;
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+
     #[test]
     fn test_encrypt_decrypt_roundtrip() {
         let data = b"Hello, WhatsApp backup!";
         let passphrase = "test-passphrase-123";
 
-        let encrypted = encrypt(data, passphrase).unwrap();
+        let encrypted = encrypt(data, passphrase);
         let decrypted = decrypt(&encrypted, passphrase).unwrap();
 
         assert_eq!(data.as_slice(), decrypted.as_slice());
     }
 
+    #[test]
+    fn test_multi_segment_roundtrip() {
+        let passphrase = "test-passphrase-123";
+
+        for len in [0, SEGMENT_SIZE, 3 * SEGMENT_SIZE + 17] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let encrypted = encrypt(&data, passphrase);
+            let decrypted = decrypt(&encrypted, passphrase).unwrap();
+            assert_eq!(data, decrypted);
+        }
+    }
+
     #[test]
     fn test_wrong_passphrase_fails() {
         let data = b"Secret data";
-        let encrypted = encrypt(data, "correct-password").unwrap();
+        let encrypted = encrypt(data, "correct-password");
         let result = decrypt(&encrypted, "wrong-password");
 
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_truncated_stream_fails() {
+        let passphrase = "test-passphrase-123";
+        let data = vec![7u8; 2 * SEGMENT_SIZE + 100];
+        let encrypted = encrypt(&data, passphrase);
+
+        // Drop the final segment so the stream ends on a non-final segment
+        let truncated = &encrypted[..encrypted.len() - (100 + TAG_SIZE)];
+        assert!(decrypt(truncated, passphrase).is_err());
+    }
+
+    #[test]
+    fn test_header_records_kdf_params() {
+        let kdf = KdfParams {
+            memory_kib: 8 * 1024,
+            iterations: 3,
+            ..KdfParams::default()
+        };
+        let recipient = Recipient::Passphrase {
+            passphrase: "pass".to_string(),
+            kdf,
+        };
+        let mut encryptor = StreamEncryptor::for_recipients(Vec::new(), &[recipient]).unwrap();
+        encryptor.write_all(b"data").unwrap();
+        let encrypted = encryptor.finish().unwrap();
+
+        match read_header(&mut encrypted.as_slice()).unwrap() {
+            Header::Stream(StreamHeader {
+                version,
+                keys: KeySource::Slots(slots),
+                ..
+            }) => {
+                assert_eq!(version, FORMAT_VERSION);
+                assert!(matches!(&slots[..], [KeySlot::Passphrase { kdf: k, .. }] if *k == kdf));
+            }
+            other => panic!("expected a key-slot header, got {:?}", other),
+        }
+        assert_eq!(decrypt(&encrypted, "pass").unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_oversized_kdf_params_are_refused() {
+        let oversized = [
+            KdfParams {
+                memory_kib: MAX_KDF_MEMORY_KIB + 1,
+                ..KdfParams::default()
+            },
+            KdfParams {
+                iterations: MAX_KDF_ITERATIONS + 1,
+                ..KdfParams::default()
+            },
+            KdfParams {
+                parallelism: MAX_KDF_PARALLELISM + 1,
+                ..KdfParams::default()
+            },
+        ];
+        for kdf in oversized {
+            let mut bytes = Vec::new();
+            kdf.write_to(&mut bytes);
+            let error = KdfParams::read_from(&mut bytes.as_slice()).unwrap_err();
+            assert!(error.to_string().contains("too"), "{}", error);
+        }
+
+        let mut bytes = Vec::new();
+        KdfParams::default().write_to(&mut bytes);
+        assert_eq!(
+            KdfParams::read_from(&mut bytes.as_slice()).unwrap(),
+            KdfParams::default()
+        );
+    }
+
+    #[test]
+    fn test_tampered_header_fails() {
+        let mut encrypted = encrypt(b"data", "pass");
+        // Flip a bit in the stored nonce prefix
+        let prefix_offset = 4 + 1 + 1;
+        encrypted[prefix_offset] ^= 1;
+        assert!(decrypt(&encrypted, "pass").is_err());
+    }
+
+    #[test]
+    fn test_public_key_recipients() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let mallory = StaticSecret::random_from_rng(OsRng);
+        let recipients = [
+            Recipient::PublicKey(PublicKey::from(&alice)),
+            Recipient::PublicKey(PublicKey::from(&bob)),
+        ];
+
+        let mut encryptor = StreamEncryptor::for_recipients(Vec::new(), &recipients).unwrap();
+        encryptor.write_all(b"family chats").unwrap();
+        let encrypted = encryptor.finish().unwrap();
+
+        for secret in [alice, bob] {
+            let decrypted = decrypt_with(&encrypted, &[Identity::SecretKey(secret)]).unwrap();
+            assert_eq!(decrypted, b"family chats");
+        }
+        assert!(decrypt_with(&encrypted, &[Identity::SecretKey(mallory)]).is_err());
+        assert!(decrypt(&encrypted, "any passphrase").is_err());
+    }
+
+    #[test]
+    fn test_stripped_key_slot_fails() {
+        let recipients = [Recipient::passphrase("first"), Recipient::passphrase("second")];
+        let mut encryptor = StreamEncryptor::for_recipients(Vec::new(), &recipients).unwrap();
+        encryptor.write_all(b"data").unwrap();
+        let encrypted = encryptor.finish().unwrap();
+
+        let Header::Stream(mut header) = read_header(&mut encrypted.as_slice()).unwrap() else {
+            panic!("expected a stream header");
+        };
+        let original_len = header.to_bytes().len();
+        if let KeySource::Slots(slots) = &mut header.keys {
+            slots.remove(0);
+        }
+        let mut stripped = header.to_bytes();
+        stripped.extend_from_slice(&encrypted[original_len..]);
+
+        assert!(decrypt(&stripped, "second").is_err());
+    }
+
+    #[test]
+    fn test_rekey_keeps_data_and_other_slots() {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let recipients = [
+            Recipient::passphrase("old"),
+            Recipient::PublicKey(PublicKey::from(&secret)),
+        ];
+        let mut encryptor = StreamEncryptor::for_recipients(Vec::new(), &recipients).unwrap();
+        encryptor.write_all(b"data").unwrap();
+        let encrypted = encryptor.finish().unwrap();
+
+        let Header::Stream(header) = read_header(&mut encrypted.as_slice()).unwrap() else {
+            panic!("expected a stream header");
+        };
+        let old = Identity::Passphrase("old".to_string());
+        let rekeyed = header
+            .rekey(&old, &Recipient::passphrase("new"))
+            .unwrap()
+            .unwrap();
+        let mut bytes = rekeyed.to_bytes();
+        bytes.extend_from_slice(&encrypted[header.to_bytes().len()..]);
+
+        assert_eq!(decrypt(&bytes, "new").unwrap(), b"data");
+        assert!(decrypt(&bytes, "old").is_err());
+        assert_eq!(decrypt_with(&bytes, &[Identity::SecretKey(secret)]).unwrap(), b"data");
+
+        let wrong = Identity::Passphrase("wrong".to_string());
+        assert!(header.rekey(&wrong, &Recipient::passphrase("new")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unknown_key_slot_is_authenticated_and_kept() {
+        let recipients = [Recipient::passphrase("old")];
+        let mut encryptor = StreamEncryptor::for_recipients(Vec::new(), &recipients).unwrap();
+        encryptor.write_all(b"data").unwrap();
+        let encrypted = encryptor.finish().unwrap();
+
+        // Write the header a newer version would, with a slot type unknown here
+        let Header::Stream(mut header) = read_header(&mut encrypted.as_slice()).unwrap() else {
+            panic!("expected a stream header");
+        };
+        let original_len = header.to_bytes().len();
+        let old = Identity::Passphrase("old".to_string());
+        let KeySource::Slots(slots) = &mut header.keys else {
+            panic!("expected key slots");
+        };
+        let file_key = slots[0].unwrap_with(&old).unwrap().unwrap();
+        slots.push(KeySlot::Unknown {
+            slot_type: 0x7f,
+            body: vec![9u8; 40],
+        });
+        header.raw = Vec::new();
+        header.mac = header.compute_mac(&file_key);
+        let mut bytes = header.to_bytes();
+        bytes.extend_from_slice(&encrypted[original_len..]);
+
+        assert_eq!(decrypt(&bytes, "old").unwrap(), b"data");
+
+        let Header::Stream(header) = read_header(&mut bytes.as_slice()).unwrap() else {
+            panic!("expected a stream header");
+        };
+        let rekeyed = header
+            .rekey(&old, &Recipient::passphrase("new"))
+            .unwrap()
+            .unwrap();
+        let KeySource::Slots(slots) = &rekeyed.keys else {
+            panic!("expected key slots");
+        };
+        assert!(slots.iter().any(|slot| slot.kind() == "unknown"));
+        let mut rekeyed_bytes = rekeyed.to_bytes();
+        rekeyed_bytes.extend_from_slice(&bytes[header.to_bytes().len()..]);
+        assert_eq!(decrypt(&rekeyed_bytes, "new").unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_decrypts_v3() {
+        let passphrase = "test-passphrase-123";
+        let kdf = KdfParams::default();
+        let salt = vec![3u8; SALT_SIZE];
+
+        let mut header = StreamHeader {
+            version: FORMAT_SELF_DESCRIBING,
+            keys: KeySource::Passphrase {
+                kdf,
+                salt: salt.clone(),
+            },
+            nonce_prefix: [4u8; NONCE_PREFIX_SIZE],
+            mac: [0u8; HEADER_MAC_SIZE],
+            associated_data: Vec::new(),
+            raw: Vec::new(),
+        };
+        header.associated_data = header.to_bytes();
+
+        let mut encryptor = StreamEncryptor {
+            inner: header.to_bytes(),
+            cipher: new_cipher(&derive_key(passphrase, &salt, &kdf).unwrap()).unwrap(),
+            header,
+            counter: 0,
+            buffer: Vec::new(),
+        };
+        encryptor.write_all(b"v3 backup").unwrap();
+        let encrypted = encryptor.finish().unwrap();
+
+        assert_eq!(decrypt(&encrypted, passphrase).unwrap(), b"v3 backup");
+    }
+
+    #[test]
+    fn test_decrypts_legacy_v1() {
+        let passphrase = "test-passphrase-123";
+        let data = b"Backup from before streaming encryption";
+
+        let salt = [1u8; SALT_SIZE];
+        let nonce = [2u8; NONCE_SIZE];
+        let key = derive_key(passphrase, &salt, &KdfParams::default()).unwrap();
+        let ciphertext = new_cipher(&key)
+            .unwrap()
+            .encrypt(Nonce::from_slice(&nonce), data.as_slice())
+            .unwrap();
+
+        let mut legacy = salt.to_vec();
+        legacy.extend_from_slice(&nonce);
+        legacy.extend_from_slice(&ciphertext);
+
+        assert_eq!(decrypt(&legacy, passphrase).unwrap(), data);
+    }
 }
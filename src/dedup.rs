@@ -0,0 +1,751 @@
+use crate::crypto;
+use crate::destination::{self, Destination, Folder, StoredFile};
+use crate::retention::{self, RetentionPolicy};
+use crate::signing;
+use anyhow::{Context, Result};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// FastCDC chunk size bounds for the uncompressed tar stream
+const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+pub const KEY_FILE: &str = "key";
+pub const KEY_SIGNATURE_FILE: &str = "key.sig";
+const CHUNKS_DIR: &str = "chunks";
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+pub const SNAPSHOT_EXTENSION: &str = "snap";
+
+/// Chunk reference in a snapshot index
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    id: String,
+    size: u64,
+}
+
+/// Snapshot file: plaintext metadata plus the encrypted chunk index
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u8,
+    pub timestamp: String,
+    /// Size of the reassembled tar stream
+    pub total_size: u64,
+    pub chunk_count: usize,
+    /// Hex of the sealed, zlib-compressed JSON list of chunk references
+    index: String,
+    /// Ed25519 signature over the rest of the snapshot, including the index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+impl Snapshot {
+    /// Bytes covered by the signature: the snapshot as JSON without it
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        let unsigned = Snapshot {
+            signature: None,
+            ..self.clone()
+        };
+        Ok(serde_json::to_vec(&unsigned)?)
+    }
+}
+
+/// Outcome of storing one snapshot
+pub struct SnapshotStats {
+    pub path: PathBuf,
+    pub total_size: u64,
+    pub chunks: usize,
+    pub new_chunks: usize,
+    pub new_bytes: u64,
+}
+
+/// Content-defined, deduplicated repository of encrypted chunks
+///
+/// Layout:
+/// - `key`: random id key + data key, encrypted with the backup passphrase
+/// - `key.sig`: signature over `key` by the manifest signing key
+/// - `chunks/<2 hex>/<id>`: zlib-compressed chunk sealed with the data key,
+///   addressed by HMAC-SHA256(id key, plaintext)
+/// - `snapshots/<timestamp>.snap`: per-snapshot encrypted index
+pub struct Repository {
+    root: PathBuf,
+    id_key: [u8; 32],
+    data_key: [u8; 32],
+    signing_key: Option<SigningKey>,
+}
+
+impl Repository {
+    /// Opens the repository at `root`, creating it with fresh keys if needed.
+    /// The keys of a new repository are encrypted to `recipients`. With
+    /// `signing_key`, a key file without a signature gets one.
+    pub fn open_or_init(
+        root: &Path,
+        identities: &[crypto::Identity],
+        recipients: &[crypto::Recipient],
+        signing_key: Option<&SigningKey>,
+    ) -> Result<Self> {
+        if root.join(KEY_FILE).exists() {
+            let repo = Self::open(root, identities)?;
+            if let (Some(key), false) = (signing_key, root.join(KEY_SIGNATURE_FILE).exists()) {
+                write_key(root, &std::fs::read(root.join(KEY_FILE))?, Some(key))?;
+            }
+            return Ok(repo);
+        }
+
+        let mut keys = [0u8; 64];
+        rand::thread_rng().fill_bytes(&mut keys);
+        Self::init_with_key(root, &encrypt_keys(&keys, recipients)?, identities, signing_key)
+    }
+
+    /// Creates an empty repository at `root` around an existing key file,
+    /// e.g. one fetched from a mirror, so new snapshots continue that
+    /// repository. Fails unless `identities` can decrypt the key.
+    pub fn init_with_key(
+        root: &Path,
+        encrypted_keys: &[u8],
+        identities: &[crypto::Identity],
+        signing_key: Option<&SigningKey>,
+    ) -> Result<Self> {
+        let keys = decrypt_keys(encrypted_keys, identities)?;
+        std::fs::create_dir_all(root.join(CHUNKS_DIR))
+            .with_context(|| format!("Failed to create repository: {}", root.display()))?;
+        std::fs::create_dir_all(root.join(SNAPSHOTS_DIR))?;
+        write_key(root, encrypted_keys, signing_key)?;
+        Ok(Self::from_keys(root, &keys))
+    }
+
+    /// Signs new snapshots with `key`
+    pub fn with_signing_key(mut self, key: Option<SigningKey>) -> Self {
+        self.signing_key = key;
+        self
+    }
+
+    /// Opens an existing repository, decrypting its keys with any of `identities`
+    pub fn open(root: &Path, identities: &[crypto::Identity]) -> Result<Self> {
+        let key_path = root.join(KEY_FILE);
+        let file = File::open(&key_path)
+            .with_context(|| format!("Repository key not found: {}", key_path.display()))?;
+        let keys = decrypt_keys(BufReader::new(file), identities)?;
+        Ok(Self::from_keys(root, &keys))
+    }
+
+    /// Whether a repository has been initialized at `root`
+    pub fn exists(root: &Path) -> bool {
+        root.join(KEY_FILE).exists()
+    }
+
+    /// Whether `encrypted_keys`, e.g. the key file of a mirror, holds the
+    /// same keys as the repository at `root`. `false` if `identities` can't
+    /// decrypt it, since it can't be shown to belong to this repository.
+    pub fn has_same_keys(
+        root: &Path,
+        encrypted_keys: &[u8],
+        identities: &[crypto::Identity],
+    ) -> Result<bool> {
+        let repo = Self::open(root, identities)?;
+        let mut keys = repo.id_key.to_vec();
+        keys.extend_from_slice(&repo.data_key);
+        Ok(decrypt_keys(encrypted_keys, identities).is_ok_and(|other| other == keys))
+    }
+
+    /// Re-encrypts the repository keys to a new set of recipients. Chunks and
+    /// snapshots are sealed with those keys, so they stay unchanged.
+    pub fn rewrap_keys(
+        root: &Path,
+        identities: &[crypto::Identity],
+        recipients: &[crypto::Recipient],
+        signing_key: Option<&SigningKey>,
+    ) -> Result<()> {
+        let repo = Self::open(root, identities)?;
+        let mut keys = repo.id_key.to_vec();
+        keys.extend_from_slice(&repo.data_key);
+        write_key(root, &encrypt_keys(&keys, recipients)?, signing_key)
+    }
+
+    /// Replaces the passphrase slot of the repository keys, keeping other slots
+    pub fn change_passphrase(
+        root: &Path,
+        old: &str,
+        new: &str,
+        signing_key: Option<&SigningKey>,
+    ) -> Result<()> {
+        let key_path = root.join(KEY_FILE);
+        let encrypted = std::fs::read(&key_path)
+            .with_context(|| format!("Repository key not found: {}", key_path.display()))?;
+        let old_identity = [crypto::Identity::Passphrase(old.to_string())];
+        let keys = decrypt_keys(encrypted.as_slice(), &old_identity)?;
+
+        let mut reader = encrypted.as_slice();
+        let header = crypto::read_header(&mut reader)?;
+        let rekeyed = match header {
+            crypto::Header::Stream(header) if matches!(header.keys, crypto::KeySource::Slots(_)) => {
+                let rekeyed = header
+                    .rekey(&old_identity[0], &crypto::Recipient::passphrase(new))?
+                    .context("Repository key is not encrypted to the passphrase")?;
+                let mut bytes = rekeyed.to_bytes();
+                bytes.extend_from_slice(reader);
+                bytes
+            }
+            _ => encrypt_keys(&keys, &[crypto::Recipient::passphrase(new)])?,
+        };
+
+        // Verify before replacing the only copy of the keys
+        let new_identity = [crypto::Identity::Passphrase(new.to_string())];
+        if decrypt_keys(rekeyed.as_slice(), &new_identity)? != keys {
+            anyhow::bail!("Re-encrypted repository key does not match");
+        }
+        write_key(root, &rekeyed, signing_key)
+    }
+
+    fn from_keys(root: &Path, keys: &[u8]) -> Self {
+        let mut id_key = [0u8; 32];
+        let mut data_key = [0u8; 32];
+        id_key.copy_from_slice(&keys[..32]);
+        data_key.copy_from_slice(&keys[32..]);
+        Self {
+            root: root.to_path_buf(),
+            id_key,
+            data_key,
+            signing_key: None,
+        }
+    }
+
+    /// Keyed chunk address, so chunk ids don't reveal content hashes
+    fn chunk_id(&self, data: &[u8]) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.id_key).expect("HMAC accepts any key size");
+        mac.update(data);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn chunk_path(&self, id: &str) -> PathBuf {
+        self.root.join(CHUNKS_DIR).join(&id[..2]).join(id)
+    }
+
+    /// Splits `reader` into content-defined chunks, stores the ones not yet in
+    /// the repository and writes a snapshot index referencing all of them
+    pub fn store_snapshot<R: Read>(&self, reader: R, timestamp: &str) -> Result<SnapshotStats> {
+        let mut index = Vec::new();
+        let mut stats = SnapshotStats {
+            path: self.snapshot_path(timestamp),
+            total_size: 0,
+            chunks: 0,
+            new_chunks: 0,
+            new_bytes: 0,
+        };
+
+        let chunker =
+            fastcdc::v2020::StreamCDC::new(reader, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE);
+        for chunk in chunker {
+            let chunk = chunk.map_err(std::io::Error::from)?;
+            let id = self.chunk_id(&chunk.data);
+            let path = self.chunk_path(&id);
+
+            if !path.exists() {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&chunk.data)?;
+                let sealed = crypto::seal(&self.data_key, id.as_bytes(), &encoder.finish()?)?;
+
+                std::fs::create_dir_all(path.parent().context("Invalid chunk path")?)?;
+                write_atomic(&path, &sealed)?;
+                stats.new_chunks += 1;
+                stats.new_bytes += sealed.len() as u64;
+            }
+
+            stats.total_size += chunk.length as u64;
+            stats.chunks += 1;
+            index.push(IndexEntry {
+                id,
+                size: chunk.length as u64,
+            });
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        serde_json::to_writer(&mut encoder, &index)?;
+        let sealed = crypto::seal(&self.data_key, &index_aad(timestamp), &encoder.finish()?)?;
+
+        let mut snapshot = Snapshot {
+            version: 1,
+            timestamp: timestamp.to_string(),
+            total_size: stats.total_size,
+            chunk_count: index.len(),
+            index: hex::encode(sealed),
+            signature: None,
+        };
+        if let Some(key) = &self.signing_key {
+            snapshot.signature = Some(signing::sign(&snapshot.signed_bytes()?, key));
+        }
+        std::fs::create_dir_all(self.root.join(SNAPSHOTS_DIR))?;
+        write_atomic(&stats.path, &serde_json::to_vec_pretty(&snapshot)?)?;
+
+        Ok(stats)
+    }
+
+    fn snapshot_path(&self, timestamp: &str) -> PathBuf {
+        self.root
+            .join(SNAPSHOTS_DIR)
+            .join(format!("{}.{}", timestamp, SNAPSHOT_EXTENSION))
+    }
+
+    /// Decrypts the chunk index of a snapshot
+    fn read_index(&self, snapshot: &Snapshot) -> Result<Vec<IndexEntry>> {
+        let sealed = hex::decode(&snapshot.index).context("Invalid snapshot index encoding")?;
+        let compressed = crypto::open(&self.data_key, &index_aad(&snapshot.timestamp), &sealed)
+            .context("Failed to decrypt snapshot index")?;
+        serde_json::from_reader(ZlibDecoder::new(compressed.as_slice()))
+            .context("Failed to parse snapshot index")
+    }
+
+    /// Reads, decrypts and verifies a single chunk
+    fn read_chunk(&self, entry: &IndexEntry) -> Result<Vec<u8>> {
+        let path = self.chunk_path(&entry.id);
+        let sealed =
+            std::fs::read(&path).with_context(|| format!("Missing chunk: {}", entry.id))?;
+        let compressed = crypto::open(&self.data_key, entry.id.as_bytes(), &sealed)
+            .with_context(|| format!("Corrupted chunk: {}", entry.id))?;
+
+        let mut data = Vec::with_capacity(entry.size as usize);
+        ZlibDecoder::new(compressed.as_slice()).read_to_end(&mut data)?;
+        if data.len() as u64 != entry.size || self.chunk_id(&data) != entry.id {
+            anyhow::bail!("Chunk failed verification: {}", entry.id);
+        }
+        Ok(data)
+    }
+
+    /// Returns a reader that reassembles the snapshot's original stream
+    pub fn snapshot_reader(&self, snapshot_path: &Path) -> Result<SnapshotReader<'_>> {
+        let snapshot = read_snapshot(snapshot_path)?;
+        let index = self.read_index(&snapshot)?;
+        Ok(SnapshotReader {
+            repo: self,
+            index: index.into_iter(),
+            current: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// Deletes snapshots not kept by `policy` and then every chunk no longer
+    /// referenced by a remaining snapshot. The newest snapshot whose index
+    /// decrypts and whose chunks are all present is always kept. With
+    /// `dry_run` nothing is deleted and the decision for each snapshot is
+    /// printed.
+    /// Returns the number of removed snapshots and chunks.
+    pub fn prune(&self, policy: &RetentionPolicy, dry_run: bool) -> Result<(usize, usize)> {
+        self.prune_at(&Folder::new(&self.root), "", policy, dry_run)
+    }
+
+    /// `prune` for the copy of this repository under `prefix` at
+    /// `destination`, such as a mirror's `repo/`, which keeps snapshots by
+    /// its own policy
+    pub fn prune_at(
+        &self,
+        destination: &dyn Destination,
+        prefix: &str,
+        policy: &RetentionPolicy,
+        dry_run: bool,
+    ) -> Result<(usize, usize)> {
+        let chunks_prefix = format!("{}{}/", prefix, CHUNKS_DIR);
+        let files = destination.list(prefix)?;
+        // Chunk id -> stored name
+        let chunks: HashMap<&str, &str> = files
+            .iter()
+            .filter(|f| f.name.starts_with(&chunks_prefix))
+            .map(|f| (f.name.rsplit('/').next().unwrap_or_default(), f.name.as_str()))
+            .collect();
+
+        let mut snapshots = list_snapshots(destination, &files, prefix)?;
+        snapshots.sort_by(|a, b| a.1.timestamp.cmp(&b.1.timestamp));
+        let timestamps: Vec<&str> = snapshots.iter().map(|(_, s)| s.timestamp.as_str()).collect();
+
+        let mut reasons = policy.select(&timestamps);
+        retention::keep_newest_verified(&timestamps, &mut reasons, |i| {
+            for entry in self.read_index(&snapshots[i].1)? {
+                if !chunks.contains_key(entry.id.as_str()) {
+                    anyhow::bail!("Missing chunk: {}", entry.id);
+                }
+            }
+            Ok(())
+        });
+
+        let mut removed_snapshots = 0;
+        let mut referenced = HashSet::new();
+
+        for ((name, snapshot), reasons) in snapshots.iter().zip(reasons) {
+            if !reasons.is_empty() {
+                if dry_run {
+                    println!("  {}: {}", name, retention::describe(&reasons));
+                }
+                for entry in self.read_index(snapshot)? {
+                    referenced.insert(entry.id);
+                }
+            } else if dry_run {
+                println!("  {}: remove", name);
+                removed_snapshots += 1;
+            } else {
+                destination.delete(name)?;
+                println!("  Removed old snapshot: {}", name);
+                removed_snapshots += 1;
+            }
+        }
+
+        let mut removed_chunks = 0;
+        for (id, name) in chunks {
+            if !referenced.contains(id) {
+                if !dry_run {
+                    destination.delete(name)?;
+                }
+                removed_chunks += 1;
+            }
+        }
+
+        Ok((removed_snapshots, removed_chunks))
+    }
+}
+
+/// Reader over the chunks of a snapshot, in index order
+pub struct SnapshotReader<'a> {
+    repo: &'a Repository,
+    index: std::vec::IntoIter<IndexEntry>,
+    current: Vec<u8>,
+    position: usize,
+}
+
+impl Read for SnapshotReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.current.len() {
+            match self.index.next() {
+                Some(entry) => {
+                    self.current = self
+                        .repo
+                        .read_chunk(&entry)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.position);
+        buf[..n].copy_from_slice(&self.current[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Associated data binding an index to its snapshot timestamp
+fn index_aad(timestamp: &str) -> Vec<u8> {
+    format!("snapshot:{}", timestamp).into_bytes()
+}
+
+/// Reads a snapshot file's metadata (the index stays encrypted)
+pub fn read_snapshot(path: &Path) -> Result<Snapshot> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open snapshot: {}", path.display()))?;
+    serde_json::from_reader(BufReader::new(file)).context("Failed to parse snapshot")
+}
+
+/// Checks the signatures of the repository key and of the snapshot at
+/// `snapshot_path` against `key`, before anything is decrypted with them
+pub fn verify_signatures(snapshot_path: &Path, key: &VerifyingKey) -> Result<()> {
+    let root = repository_root(snapshot_path)?;
+    let key_path = root.join(KEY_FILE);
+    let encrypted_keys = std::fs::read(&key_path)
+        .with_context(|| format!("Repository key not found: {}", key_path.display()))?;
+    let signature = std::fs::read_to_string(root.join(KEY_SIGNATURE_FILE))
+        .context("Repository key is not signed")?;
+    signing::verify(&encrypted_keys, &signature, key, "Repository key")?;
+
+    let snapshot = read_snapshot(snapshot_path)?;
+    let signature = snapshot
+        .signature
+        .as_deref()
+        .context("Snapshot is not signed")?;
+    signing::verify(&snapshot.signed_bytes()?, signature, key, "Snapshot")
+}
+
+/// Repository root for a snapshot file (`<root>/snapshots/<timestamp>.snap`)
+pub fn repository_root(snapshot_path: &Path) -> Result<PathBuf> {
+    snapshot_path
+        .parent()
+        .and_then(Path::parent)
+        .map(Path::to_path_buf)
+        .context("Snapshot is not inside a repository")
+}
+
+/// Lists the snapshots among `files` of a repository at `destination`, as
+/// (stored name, snapshot). Fails on a snapshot that can't be read, since
+/// every chunk it references would otherwise look unreferenced.
+fn list_snapshots<'a>(
+    destination: &dyn Destination,
+    files: &'a [StoredFile],
+    prefix: &str,
+) -> Result<Vec<(&'a str, Snapshot)>> {
+    let dir = format!("{}{}/", prefix, SNAPSHOTS_DIR);
+    let extension = format!(".{}", SNAPSHOT_EXTENSION);
+
+    let mut snapshots = Vec::new();
+    for file in files {
+        if file.name.starts_with(&dir) && file.name.ends_with(&extension) {
+            let snapshot = serde_json::from_slice(&destination::read(destination, &file.name)?)
+                .with_context(|| format!("Unreadable snapshot: {}", file.name))?;
+            snapshots.push((file.name.as_str(), snapshot));
+        }
+    }
+    Ok(snapshots)
+}
+
+fn encrypt_keys(keys: &[u8], recipients: &[crypto::Recipient]) -> Result<Vec<u8>> {
+    let mut encryptor = crypto::StreamEncryptor::for_recipients(Vec::new(), recipients)?;
+    encryptor.write_all(keys)?;
+    encryptor.finish()
+}
+
+/// Decrypts the 64-byte key file contents (id key + data key)
+fn decrypt_keys<R: BufRead>(mut reader: R, identities: &[crypto::Identity]) -> Result<Vec<u8>> {
+    let header = crypto::read_header(&mut reader)?;
+    let mut keys = Vec::new();
+    crypto::decrypt_reader(reader, header, identities)?
+        .read_to_end(&mut keys)
+        .context("Failed to decrypt repository key")?;
+    if keys.len() != 64 {
+        anyhow::bail!("Invalid repository key file");
+    }
+    Ok(keys)
+}
+
+/// Writes the key file and its signature, or removes a signature that no
+/// longer matches when there is no signing key
+fn write_key(root: &Path, encrypted_keys: &[u8], signing_key: Option<&SigningKey>) -> Result<()> {
+    let signature_path = root.join(KEY_SIGNATURE_FILE);
+    if signing_key.is_none() && signature_path.exists() {
+        std::fs::remove_file(&signature_path)?;
+    }
+    write_atomic(&root.join(KEY_FILE), encrypted_keys)?;
+    if let Some(key) = signing_key {
+        write_atomic(&signature_path, signing::sign(encrypted_keys, key).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Writes via a temporary file and rename, so interrupted runs never leave
+/// a truncated object under its final name
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passphrase(p: &str) -> Vec<crypto::Identity> {
+        vec![crypto::Identity::Passphrase(p.to_string())]
+    }
+
+    fn init(root: &Path, p: &str) -> Repository {
+        let recipients = [crypto::Recipient::passphrase(p)];
+        Repository::open_or_init(root, &passphrase(p), &recipients, None).unwrap()
+    }
+
+    fn sample_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip_and_dedup() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init(dir.path(), "test-passphrase");
+
+        let data = sample_data(3 * AVG_CHUNK_SIZE as usize, 1);
+        let first = repo
+            .store_snapshot(data.as_slice(), "2026-01-18_00-00-00")
+            .unwrap();
+        assert_eq!(first.total_size, data.len() as u64);
+        assert_eq!(first.new_chunks, first.chunks);
+
+        // Appending data should only store the chunks near the end
+        let mut grown = data.clone();
+        grown.extend(sample_data(AVG_CHUNK_SIZE as usize, 2));
+        let second = repo
+            .store_snapshot(grown.as_slice(), "2026-01-18_06-00-00")
+            .unwrap();
+        assert!(second.new_chunks < second.chunks);
+
+        let reopened = Repository::open(dir.path(), &passphrase("test-passphrase")).unwrap();
+        let mut restored = Vec::new();
+        reopened
+            .snapshot_reader(&second.path)
+            .unwrap()
+            .read_to_end(&mut restored)
+            .unwrap();
+        assert_eq!(restored, grown);
+    }
+
+    #[test]
+    fn test_wrong_passphrase_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        init(dir.path(), "test-passphrase");
+        assert!(Repository::open(dir.path(), &passphrase("wrong-passphrase")).is_err());
+    }
+
+    #[test]
+    fn test_prune_refuses_unreadable_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init(dir.path(), "test-passphrase");
+        let data = sample_data(AVG_CHUNK_SIZE as usize, 4);
+        let stats = repo
+            .store_snapshot(data.as_slice(), "2026-01-18_00-00-00")
+            .unwrap();
+        std::fs::write(
+            dir.path().join(SNAPSHOTS_DIR).join("2026-01-19_00-00-00.snap"),
+            b"{\"format\": 99",
+        )
+        .unwrap();
+
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            ..Default::default()
+        };
+        assert!(repo.prune(&policy, false).is_err());
+
+        // The readable snapshot and all of its chunks are untouched
+        let mut restored = Vec::new();
+        repo.snapshot_reader(&stats.path)
+            .unwrap()
+            .read_to_end(&mut restored)
+            .unwrap();
+        assert_eq!(restored, data);
+    }
+
+    #[test]
+    fn test_change_passphrase_keeps_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init(dir.path(), "old-passphrase");
+        let data = sample_data(AVG_CHUNK_SIZE as usize, 3);
+        let stats = repo
+            .store_snapshot(data.as_slice(), "2026-01-18_00-00-00")
+            .unwrap();
+
+        Repository::change_passphrase(dir.path(), "old-passphrase", "new-passphrase", None)
+            .unwrap();
+        assert!(Repository::open(dir.path(), &passphrase("old-passphrase")).is_err());
+
+        let reopened = Repository::open(dir.path(), &passphrase("new-passphrase")).unwrap();
+        let mut restored = Vec::new();
+        reopened
+            .snapshot_reader(&stats.path)
+            .unwrap()
+            .read_to_end(&mut restored)
+            .unwrap();
+        assert_eq!(restored, data);
+    }
+
+    #[test]
+    fn test_init_with_key_continues_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first");
+        init(&first, "pass");
+        let key = std::fs::read(first.join(KEY_FILE)).unwrap();
+
+        let second = dir.path().join("second");
+        assert!(Repository::init_with_key(&second, &key, &passphrase("wrong"), None).is_err());
+        Repository::init_with_key(&second, &key, &passphrase("pass"), None).unwrap();
+        assert!(Repository::has_same_keys(&first, &key, &passphrase("pass")).unwrap());
+        assert!(second.join(CHUNKS_DIR).is_dir());
+
+        let other = dir.path().join("other");
+        init(&other, "pass");
+        let other_key = std::fs::read(other.join(KEY_FILE)).unwrap();
+        assert!(!Repository::has_same_keys(&first, &other_key, &passphrase("pass")).unwrap());
+    }
+
+    #[test]
+    fn test_prune_mirror_by_its_own_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let repo = init(&root, "pass");
+        for (seed, timestamp) in [(1, "2026-01-17_00-00-00"), (2, "2026-01-18_00-00-00")] {
+            let data = sample_data(AVG_CHUNK_SIZE as usize, seed);
+            repo.store_snapshot(data.as_slice(), timestamp).unwrap();
+        }
+        let mirror = Folder::new(dir.path().join("mirror"));
+        destination::mirror(&mirror, &root, &passphrase("pass")).unwrap();
+
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            keep_hourly: 0,
+            keep_daily: 0,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            keep_yearly: 0,
+        };
+        let (snapshots, chunks) = repo.prune_at(&mirror, "repo/", &policy, false).unwrap();
+        assert_eq!(snapshots, 1);
+        assert!(chunks > 0);
+
+        let names: Vec<String> = mirror
+            .list("repo/snapshots/")
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(names, vec!["repo/snapshots/2026-01-18_00-00-00.snap"]);
+        // The local repository keeps its own snapshots
+        assert_eq!(Folder::new(&root).list("snapshots/").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_snapshot_and_key_signatures() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let other = SigningKey::from_bytes(&[8u8; 32]);
+        let recipients = [crypto::Recipient::passphrase("pass")];
+        let identities = passphrase("pass");
+        let repo = Repository::open_or_init(dir.path(), &identities, &recipients, Some(&key))
+            .unwrap()
+            .with_signing_key(Some(key.clone()));
+        let stats = repo.store_snapshot(&b"data"[..], "2026-01-18_00-00-00").unwrap();
+
+        verify_signatures(&stats.path, &key.verifying_key()).unwrap();
+        assert!(verify_signatures(&stats.path, &other.verifying_key()).is_err());
+
+        // Another key file, even one the passphrase opens, isn't signed
+        let both = [
+            crypto::Recipient::passphrase("pass"),
+            crypto::Recipient::passphrase("other"),
+        ];
+        Repository::rewrap_keys(dir.path(), &passphrase("pass"), &both, None).unwrap();
+        assert!(verify_signatures(&stats.path, &key.verifying_key()).is_err());
+        Repository::rewrap_keys(dir.path(), &passphrase("pass"), &both, Some(&key)).unwrap();
+        verify_signatures(&stats.path, &key.verifying_key()).unwrap();
+
+        // Swapping in another snapshot's index breaks the signature
+        let mut snapshot = read_snapshot(&stats.path).unwrap();
+        let unsigned = Repository::open(dir.path(), &passphrase("pass"))
+            .unwrap()
+            .store_snapshot(&b"other"[..], "2026-01-18_00-00-00")
+            .unwrap();
+        snapshot.index = read_snapshot(&unsigned.path).unwrap().index;
+        std::fs::write(&stats.path, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+        assert!(verify_signatures(&stats.path, &key.verifying_key()).is_err());
+    }
+}
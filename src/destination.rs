@@ -0,0 +1,871 @@
+use crate::backup::INCREMENTAL_DIR;
+use crate::config::{Config, DestinationConfig, DestinationKind};
+use crate::crypto::Identity;
+use crate::git::GitRepo;
+use crate::rclone::Rclone;
+use crate::restore;
+use crate::retention::RetentionPolicy;
+use crate::s3::S3;
+use crate::sftp::Sftp;
+use crate::webdav::Webdav;
+use crate::{dedup, paths};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Folder inside Google Drive holding backups
+const GOOGLE_DRIVE_FOLDER: &str = "WhatsApp-Backups";
+
+/// A file stored at a destination
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredFile {
+    /// Path relative to the destination root, `/`-separated
+    pub name: String,
+    pub size: u64,
+}
+
+/// Somewhere backups are stored. Files are named by `/`-separated paths
+/// relative to the destination root.
+pub trait Destination {
+    /// Where the files are, for output: a path or URL
+    fn location(&self) -> String;
+
+    /// Stores the local `file` as `name`, replacing any file of that name
+    fn put(&self, file: &Path, name: &str) -> Result<()>;
+
+    /// Copies `name` to the local `file`
+    fn get(&self, name: &str, file: &Path) -> Result<()>;
+
+    /// Every file whose name starts with `prefix`, including files in
+    /// subdirectories
+    fn list(&self, prefix: &str) -> Result<Vec<StoredFile>>;
+
+    fn delete(&self, name: &str) -> Result<()>;
+
+    /// The file called `name`, or `None` if there is none
+    fn stat(&self, name: &str) -> Result<Option<StoredFile>>;
+
+    /// Publishes the changes made since the last commit. Only needed by
+    /// destinations that don't write through immediately.
+    fn commit(&self, _message: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Directory holding the files, for destinations on this machine
+    fn local_dir(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// A destination from the config that is set up on this machine
+pub struct Configured<'a> {
+    pub name: &'a str,
+    pub retention: &'a RetentionPolicy,
+    pub destination: Box<dyn Destination>,
+}
+
+/// Opens the configured destinations in order, skipping ones that aren't
+/// set up on this machine (no git checkout, Google Drive not installed)
+pub fn configured(config: &Config) -> Result<Vec<Configured<'_>>> {
+    let mut destinations = Vec::new();
+    for entry in &config.destinations {
+        if let Some(destination) = open(config, entry)? {
+            destinations.push(Configured {
+                name: entry.name(),
+                retention: config.retention_for(entry),
+                destination,
+            });
+        }
+    }
+    Ok(destinations)
+}
+
+fn open(config: &Config, entry: &DestinationConfig) -> Result<Option<Box<dyn Destination>>> {
+    Ok(match &entry.kind {
+        DestinationKind::Local => Some(Box::new(Folder::new(paths::backup_dir()?))),
+        DestinationKind::Git => {
+            let checkout = config.git_checkout_dir()?;
+            if checkout.join(".git").exists() {
+                Some(Box::new(Git {
+                    checkout: Folder::new(checkout),
+                }))
+            } else {
+                None
+            }
+        }
+        DestinationKind::GoogleDrive => match paths::google_drive_dir() {
+            Some(drive_dir) => Some(Box::new(SyncedFolder::open(
+                drive_dir.join(GOOGLE_DRIVE_FOLDER),
+            )?)),
+            None => None,
+        },
+        // The sync client isn't installed if the folder's parent is missing
+        DestinationKind::SyncedFolder { path } => match path.parent() {
+            Some(parent) if parent.is_dir() => Some(Box::new(SyncedFolder::open(path.clone())?)),
+            _ => None,
+        },
+        DestinationKind::S3(s3) => Some(Box::new(S3::new(entry.name(), s3))),
+        DestinationKind::Sftp(sftp) => Some(Box::new(Sftp::new(sftp))),
+        DestinationKind::Webdav(webdav) => Some(Box::new(Webdav::new(entry.name(), webdav))),
+        DestinationKind::Rclone(rclone) => Some(Box::new(Rclone::new(rclone))),
+    })
+}
+
+/// Directory on this machine: the local backup directory, or a folder
+/// synced by a desktop client such as Google Drive
+pub struct Folder {
+    dir: PathBuf,
+}
+
+impl Folder {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Adds the files under `dir` (named `prefix...`) to `files`, skipping
+    /// hidden entries such as `.git`
+    fn walk(&self, dir: &Path, prefix: &str, files: &mut Vec<StoredFile>) -> Result<()> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to list {}", dir.display()));
+            }
+        };
+        for entry in entries {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+            if file_name.starts_with('.') {
+                continue;
+            }
+            let name = format!("{}{}", prefix, file_name);
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                self.walk(&entry.path(), &format!("{}/", name), files)?;
+            } else {
+                files.push(StoredFile {
+                    name,
+                    size: metadata.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Destination for Folder {
+    fn location(&self) -> String {
+        self.dir.display().to_string()
+    }
+
+    fn put(&self, file: &Path, name: &str) -> Result<()> {
+        let target = self.path(name);
+        // Backups are created in the local backup directory itself
+        if target == file {
+            return Ok(());
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(file, &target).with_context(|| {
+            format!("Failed to copy {} to {}", file.display(), target.display())
+        })?;
+        Ok(())
+    }
+
+    fn get(&self, name: &str, file: &Path) -> Result<()> {
+        let source = self.path(name);
+        std::fs::copy(&source, file)
+            .with_context(|| format!("Failed to copy {}", source.display()))?;
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StoredFile>> {
+        // Only walk the directory the prefix points into
+        let (dir, _) = prefix.rsplit_once('/').unwrap_or(("", ""));
+        let mut files = Vec::new();
+        if dir.is_empty() {
+            self.walk(&self.dir, "", &mut files)?;
+        } else {
+            self.walk(&self.path(dir), &format!("{}/", dir), &mut files)?;
+        }
+        files.retain(|f| f.name.starts_with(prefix));
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        let path = self.path(name);
+        std::fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))
+    }
+
+    fn stat(&self, name: &str) -> Result<Option<StoredFile>> {
+        match std::fs::metadata(self.path(name)) {
+            Ok(metadata) if metadata.is_file() => Ok(Some(StoredFile {
+                name: name.to_string(),
+                size: metadata.len(),
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        Some(&self.dir)
+    }
+}
+
+/// Folder kept in sync by a desktop client such as Google Drive, Dropbox,
+/// OneDrive or iCloud Drive. Files are written under a hidden temporary
+/// name, read back and compared, then renamed into place, so the client
+/// never uploads a half-written backup.
+pub struct SyncedFolder {
+    folder: Folder,
+}
+
+impl SyncedFolder {
+    /// Opens `dir`, creating it if needed
+    fn open(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        Ok(Self {
+            folder: Folder::new(dir),
+        })
+    }
+}
+
+impl Destination for SyncedFolder {
+    fn location(&self) -> String {
+        self.folder.location()
+    }
+
+    fn put(&self, file: &Path, name: &str) -> Result<()> {
+        let target = self.folder.path(name);
+        let mut input = BufReader::new(
+            File::open(file).with_context(|| format!("Failed to open {}", file.display()))?,
+        );
+        // An identical copy is already there, e.g. from an earlier run
+        let size = input.get_ref().metadata()?.len();
+        if std::fs::metadata(&target).is_ok_and(|m| m.is_file() && m.len() == size) {
+            let existing = copy_hashed(
+                &mut BufReader::new(File::open(&target)?),
+                &mut std::io::sink(),
+            )?;
+            let source = copy_hashed(&mut BufReader::new(File::open(file)?), &mut std::io::sink())?;
+            if existing == source {
+                return Ok(());
+            }
+        }
+
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file_name = target
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let partial = target.with_file_name(format!(".{}.partial", file_name));
+        let mut output = BufWriter::new(
+            File::create(&partial)
+                .with_context(|| format!("Failed to create {}", partial.display()))?,
+        );
+        let written = copy_hashed(&mut input, &mut output).with_context(|| {
+            format!("Failed to copy {} to {}", file.display(), partial.display())
+        })?;
+        output
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+
+        // Re-hash the copy, so a bad disk or full drive is caught before it
+        // replaces anything
+        let copied = copy_hashed(
+            &mut BufReader::new(File::open(&partial)?),
+            &mut std::io::sink(),
+        )?;
+        if copied != written {
+            let _ = std::fs::remove_file(&partial);
+            anyhow::bail!(
+                "Copy of {} is corrupt: {} bytes with SHA256 {}, expected {} bytes with {}",
+                name,
+                copied.0,
+                copied.1,
+                written.0,
+                written.1
+            );
+        }
+        std::fs::rename(&partial, &target)
+            .with_context(|| format!("Failed to rename {}", partial.display()))?;
+        Ok(())
+    }
+
+    fn get(&self, name: &str, file: &Path) -> Result<()> {
+        self.folder.get(name, file)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StoredFile>> {
+        self.folder.list(prefix)
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        self.folder.delete(name)
+    }
+
+    fn stat(&self, name: &str) -> Result<Option<StoredFile>> {
+        self.folder.stat(name)
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        self.folder.local_dir()
+    }
+}
+
+/// Checkout of the git remote; changes are committed and pushed on `commit`
+pub struct Git {
+    checkout: Folder,
+}
+
+impl Destination for Git {
+    fn location(&self) -> String {
+        self.checkout.location()
+    }
+
+    fn put(&self, file: &Path, name: &str) -> Result<()> {
+        self.checkout.put(file, name)
+    }
+
+    fn get(&self, name: &str, file: &Path) -> Result<()> {
+        self.checkout.get(name, file)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StoredFile>> {
+        self.checkout.list(prefix)
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        self.checkout.delete(name)
+    }
+
+    fn stat(&self, name: &str) -> Result<Option<StoredFile>> {
+        self.checkout.stat(name)
+    }
+
+    fn commit(&self, message: &str) -> Result<()> {
+        let dir = &self.checkout.dir;
+        let repo = GitRepo::open(dir)
+            .with_context(|| format!("Failed to open git repo: {}", dir.display()))?;
+        Ok(repo.commit_and_push_dir(".", message)?)
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        self.checkout.local_dir()
+    }
+}
+
+/// Copies `input` to `output`, returning the size and SHA256 of the data
+pub fn copy_hashed<R: Read, W: Write>(input: &mut R, output: &mut W) -> Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 65536];
+    let mut size = 0u64;
+    loop {
+        let read = input.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        output.write_all(&buffer[..read])?;
+        size += read as u64;
+    }
+    output.flush()?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Reads the whole file `name`, downloading it if the destination isn't on
+/// this machine
+pub fn read(destination: &dyn Destination, name: &str) -> Result<Vec<u8>> {
+    if let Some(dir) = destination.local_dir() {
+        let path = dir.join(name);
+        return std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()));
+    }
+    let file = tempfile::NamedTempFile::new()?;
+    destination.get(name, file.path())?;
+    Ok(std::fs::read(file.path())?)
+}
+
+/// Stores `files` one at a time, committing after each so no single push
+/// exceeds the limits of hosts like GitHub
+pub fn store_files(destination: &dyn Destination, files: &[PathBuf], message: &str) -> Result<()> {
+    let total = files.len();
+    for (i, file) in files.iter().enumerate() {
+        let name = file
+            .file_name()
+            .and_then(|n| n.to_str())
+            .context("Invalid backup filename")?;
+        destination.put(file, name)?;
+        destination.commit(&format!("{} ({}/{})", message, i + 1, total))?;
+    }
+    Ok(())
+}
+
+/// Copies the files of the incremental repository at `root` that `repo/` at
+/// `destination` doesn't have yet, and the key file if it changed. Nothing
+/// is removed: each destination prunes its own snapshots. A key file there
+/// is only replaced if it holds the same keys, decrypted with `identities`,
+/// so a mirror of another repository is never overwritten. Returns the
+/// number of files copied.
+pub fn mirror(
+    destination: &dyn Destination,
+    root: &Path,
+    identities: &[Identity],
+) -> Result<usize> {
+    let prefix = format!("{}/", INCREMENTAL_DIR);
+    let stored: HashMap<String, u64> = destination
+        .list(&prefix)?
+        .into_iter()
+        .map(|f| (f.name, f.size))
+        .collect();
+
+    let key_name = format!("{}{}", prefix, dedup::KEY_FILE);
+    let key_changed = if stored.contains_key(&key_name) {
+        let stored_key = read(destination, &key_name)?;
+        let changed = stored_key != std::fs::read(root.join(dedup::KEY_FILE))?;
+        if changed && !dedup::Repository::has_same_keys(root, &stored_key, identities)? {
+            anyhow::bail!(
+                "{} holds a different incremental repository; refusing to overwrite its key",
+                destination.location()
+            );
+        }
+        changed
+    } else {
+        true
+    };
+
+    let mut copied = 0;
+    for file in Folder::new(root).list("")? {
+        let name = format!("{}{}", prefix, file.name);
+        // Chunks and snapshots are immutable, but the key file and its
+        // signature may be rewritten
+        let up_to_date = match file.name.as_str() {
+            dedup::KEY_FILE => !key_changed,
+            dedup::KEY_SIGNATURE_FILE => !key_changed && stored.get(&name) == Some(&file.size),
+            _ => stored.get(&name) == Some(&file.size),
+        };
+        if !up_to_date {
+            destination.put(&root.join(&file.name), &name)?;
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+/// Mirrors the incremental repository to every destination that already
+/// has a copy, e.g. after its key file changed, and commits it with `message`
+pub fn sync_repository_mirrors(
+    config: &Config,
+    root: &Path,
+    identities: &[Identity],
+    message: &str,
+) -> Result<()> {
+    for configured in configured(config)? {
+        let destination = configured.destination.as_ref();
+        if destination
+            .list(&format!("{}/", INCREMENTAL_DIR))?
+            .is_empty()
+        {
+            continue;
+        }
+        mirror(destination, root, identities)?;
+        destination.commit(message)?;
+    }
+    Ok(())
+}
+
+/// Reads the key file of the first mirror of the incremental repository
+/// other than `root` itself, so a new or lost local repository continues
+/// it instead of starting a new one. Returns the destination's name and the
+/// key file, or `None` if no destination has a mirror. Fails if a
+/// destination can't be checked.
+pub fn find_repository_key<'a>(
+    config: &'a Config,
+    root: &Path,
+) -> Result<Option<(&'a str, Vec<u8>)>> {
+    let key_name = format!("{}/{}", INCREMENTAL_DIR, dedup::KEY_FILE);
+    for configured in configured(config)? {
+        let destination = configured.destination.as_ref();
+        if destination.local_dir() == root.parent() {
+            continue;
+        }
+        let found = destination.stat(&key_name).with_context(|| {
+            format!("Failed to check {} for an incremental repository", configured.name)
+        })?;
+        if found.is_some() {
+            return Ok(Some((configured.name, read(destination, &key_name)?)));
+        }
+    }
+    Ok(None)
+}
+
+/// Finds the backup or snapshot called `name` at the destination called
+/// `from`, or the first destination that has it, and returns a local path to
+/// restore it from. Files of destinations not on this machine are downloaded
+/// into `staging`.
+pub fn fetch_backup(
+    config: &Config,
+    from: Option<&str>,
+    name: &str,
+    staging: &Path,
+) -> Result<PathBuf> {
+    let destinations: Vec<Configured> = configured(config)?
+        .into_iter()
+        .filter(|c| from.is_none_or(|from| c.name == from))
+        .collect();
+    if let (Some(from), true) = (from, destinations.is_empty()) {
+        anyhow::bail!("No destination named '{}' is set up on this machine", from);
+    }
+
+    for configured in &destinations {
+        let destination = configured.destination.as_ref();
+        if let Some(path) = fetch_from(destination, configured.name, name, staging)? {
+            return Ok(path);
+        }
+    }
+
+    anyhow::bail!("Backup not found: {}", name)
+}
+
+/// `fetch_backup` for a single destination; `None` if it doesn't have `name`
+pub fn fetch_from(
+    destination: &dyn Destination,
+    label: &str,
+    name: &str,
+    staging: &Path,
+) -> Result<Option<PathBuf>> {
+    let (path, files) = if name.ends_with(&format!(".{}", dedup::SNAPSHOT_EXTENSION)) {
+        if destination.stat(name)?.is_none() {
+            return Ok(None);
+        }
+        (name.to_string(), None)
+    } else {
+        match restore::find_backups(destination)?
+            .into_iter()
+            .find(|b| b.name() == name || b.timestamp == name)
+        {
+            Some(backup) => (backup.name().to_string(), Some(backup.files)),
+            None => return Ok(None),
+        }
+    };
+    if let Some(dir) = destination.local_dir() {
+        return Ok(Some(dir.join(path)));
+    }
+
+    // Restoring a snapshot needs the repository key and its chunks
+    let files: Vec<String> = match files {
+        Some(files) => files,
+        None => {
+            let snapshots = format!("{}/{}/", INCREMENTAL_DIR, dedup::SNAPSHOTS_DIR);
+            destination
+                .list(&format!("{}/", INCREMENTAL_DIR))?
+                .into_iter()
+                .map(|f| f.name)
+                .filter(|n| *n == path || !n.starts_with(&snapshots))
+                .collect()
+        }
+    };
+    // Names come from the remote and are checked before anything is written,
+    // since the manifest signature is only checked later by restore
+    check_relative(&path)?;
+    for file in &files {
+        check_relative(file)?;
+    }
+
+    println!("Downloading {} from {}...", path, label);
+    for file in &files {
+        let target = staging.join(file);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        destination.get(file, &target)?;
+    }
+    Ok(Some(staging.join(path)))
+}
+
+/// Fails unless `name` is a relative path that stays inside the directory it
+/// is joined to
+fn check_relative(name: &str) -> Result<()> {
+    if Path::new(name).is_absolute()
+        || name
+            .split(['/', '\\'])
+            .any(|part| part.is_empty() || part == "." || part == "..")
+    {
+        anyhow::bail!("Refusing unsafe file name from the destination: {}", name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folder_operations() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.enc");
+        std::fs::write(&source, b"data").unwrap();
+        let folder = Folder::new(dir.path().join("dest"));
+
+        folder.put(&source, "a.enc").unwrap();
+        folder.put(&source, "repo/chunks/ab/abcd").unwrap();
+        std::fs::create_dir_all(dir.path().join("dest/.git")).unwrap();
+        std::fs::write(dir.path().join("dest/.git/HEAD"), b"ref").unwrap();
+
+        let names = |prefix| -> Vec<String> {
+            folder
+                .list(prefix)
+                .unwrap()
+                .into_iter()
+                .map(|f| f.name)
+                .collect()
+        };
+        assert_eq!(names(""), vec!["a.enc", "repo/chunks/ab/abcd"]);
+        assert_eq!(names("repo/"), vec!["repo/chunks/ab/abcd"]);
+        assert_eq!(names("repo/chunks/ab/ab"), vec!["repo/chunks/ab/abcd"]);
+        assert!(names("missing/").is_empty());
+
+        let stat = folder.stat("a.enc").unwrap().unwrap();
+        assert_eq!(stat.size, 4);
+        assert!(folder.stat("repo").unwrap().is_none());
+        assert_eq!(read(&folder, "repo/chunks/ab/abcd").unwrap(), b"data");
+
+        folder.delete("a.enc").unwrap();
+        assert!(folder.stat("a.enc").unwrap().is_none());
+        assert!(folder.delete("a.enc").is_err());
+    }
+
+    #[test]
+    fn test_synced_folder() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("a.enc");
+        std::fs::write(&source, b"data").unwrap();
+        let config = Config {
+            destinations: vec![
+                DestinationConfig::new(DestinationKind::SyncedFolder {
+                    path: dir.path().join("Dropbox/WhatsApp"),
+                }),
+                // No sync client installed here
+                DestinationConfig::new(DestinationKind::SyncedFolder {
+                    path: dir.path().join("OneDrive/WhatsApp"),
+                }),
+            ],
+            ..Default::default()
+        };
+        assert!(configured(&config).unwrap().is_empty());
+
+        std::fs::create_dir(dir.path().join("Dropbox")).unwrap();
+        let destinations = configured(&config).unwrap();
+        assert_eq!(destinations.len(), 1);
+        let folder = destinations[0].destination.as_ref();
+
+        folder.put(&source, "repo/chunks/ab/abcd").unwrap();
+        folder.put(&source, "a.enc").unwrap();
+        let target = dir.path().join("Dropbox/WhatsApp/a.enc");
+        assert_eq!(std::fs::read(&target).unwrap(), b"data");
+        let names: Vec<String> = folder
+            .list("")
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(names, vec!["a.enc", "repo/chunks/ab/abcd"]);
+
+        // An identical copy is left alone; a changed one is replaced
+        let inode = std::fs::metadata(&target).unwrap().ino();
+        folder.put(&source, "a.enc").unwrap();
+        assert_eq!(std::fs::metadata(&target).unwrap().ino(), inode);
+        std::fs::write(&source, b"dat2").unwrap();
+        folder.put(&source, "a.enc").unwrap();
+        assert_ne!(std::fs::metadata(&target).unwrap().ino(), inode);
+        assert_eq!(std::fs::read(&target).unwrap(), b"dat2");
+        assert!(!dir.path().join("Dropbox/WhatsApp/.a.enc.partial").exists());
+    }
+
+    #[test]
+    fn test_mirror_copies_and_keeps() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let identities = [Identity::Passphrase("pass".to_string())];
+        let recipients = [crate::crypto::Recipient::passphrase("pass")];
+        let repo = dedup::Repository::open_or_init(&root, &identities, &recipients, None).unwrap();
+        repo.store_snapshot(&b"first"[..], "2026-01-01_00-00-00").unwrap();
+        let folder = Folder::new(dir.path().join("mirror"));
+
+        assert_eq!(mirror(&folder, &root, &identities).unwrap(), 3);
+        assert_eq!(mirror(&folder, &root, &identities).unwrap(), 0);
+
+        // Files removed locally stay in the mirror
+        std::fs::remove_dir_all(root.join("snapshots")).unwrap();
+        repo.store_snapshot(&b"second"[..], "2026-01-02_00-00-00").unwrap();
+        assert_eq!(mirror(&folder, &root, &identities).unwrap(), 2);
+        let snapshots = folder.list("repo/snapshots/").unwrap();
+        assert_eq!(snapshots.len(), 2);
+
+        // A rewrapped key holds the same keys and replaces the mirrored one
+        let both = [
+            crate::crypto::Recipient::passphrase("pass"),
+            crate::crypto::Recipient::passphrase("other"),
+        ];
+        dedup::Repository::rewrap_keys(&root, &identities, &both, None).unwrap();
+        assert_eq!(mirror(&folder, &root, &identities).unwrap(), 1);
+
+        // A mirror of another repository is left alone
+        let other = dir.path().join("other");
+        dedup::Repository::open_or_init(&other, &identities, &recipients, None).unwrap();
+        let stored = std::fs::read(dir.path().join("mirror/repo/key")).unwrap();
+        assert!(mirror(&folder, &other, &identities).is_err());
+        assert_eq!(std::fs::read(dir.path().join("mirror/repo/key")).unwrap(), stored);
+    }
+
+    #[test]
+    fn test_git_commits_each_stored_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let remote_dir = tmp.path().join("remote.git");
+        let remote = git2::Repository::init_bare(&remote_dir).unwrap();
+        let checkout = tmp.path().join("checkout");
+        crate::git::init_repo(&checkout, remote_dir.to_str().unwrap()).unwrap();
+        let git = Git {
+            checkout: Folder::new(&checkout),
+        };
+
+        let files: Vec<PathBuf> = ["b.enc.001", "b.enc.manifest"]
+            .iter()
+            .map(|name| tmp.path().join(name))
+            .collect();
+        for file in &files {
+            std::fs::write(file, b"data").unwrap();
+        }
+        store_files(&git, &files, "Backup b").unwrap();
+
+        let mut walk = remote.revwalk().unwrap();
+        walk.push_ref("refs/heads/main").unwrap();
+        let messages: Vec<String> = walk
+            .map(|id| {
+                remote
+                    .find_commit(id.unwrap())
+                    .unwrap()
+                    .summary()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(messages, vec!["Backup b (2/2)", "Backup b (1/2)"]);
+
+        git.delete("b.enc.001").unwrap();
+        git.commit("Prune old backups").unwrap();
+        let tree = remote
+            .find_reference("refs/heads/main")
+            .unwrap()
+            .peel_to_tree()
+            .unwrap();
+        let names: Vec<String> = tree
+            .iter()
+            .filter_map(|e| e.name().map(String::from))
+            .collect();
+        assert_eq!(names, vec!["b.enc.manifest"]);
+    }
+
+    /// Remote destination serving whatever files it is given
+    struct Untrusted(HashMap<String, Vec<u8>>);
+
+    impl Destination for Untrusted {
+        fn location(&self) -> String {
+            "untrusted".to_string()
+        }
+
+        fn put(&self, _file: &Path, _name: &str) -> Result<()> {
+            anyhow::bail!("not supported by this mock")
+        }
+
+        fn get(&self, name: &str, file: &Path) -> Result<()> {
+            let data = self.0.get(name).context("Not found")?;
+            Ok(std::fs::write(file, data)?)
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<StoredFile>> {
+            let mut files: Vec<StoredFile> = self
+                .0
+                .iter()
+                .filter(|(name, _)| name.starts_with(prefix))
+                .map(|(name, data)| StoredFile {
+                    name: name.clone(),
+                    size: data.len() as u64,
+                })
+                .collect();
+            files.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(files)
+        }
+
+        fn delete(&self, _name: &str) -> Result<()> {
+            anyhow::bail!("not supported by this mock")
+        }
+
+        fn stat(&self, name: &str) -> Result<Option<StoredFile>> {
+            Ok(self.0.get(name).map(|data| StoredFile {
+                name: name.to_string(),
+                size: data.len() as u64,
+            }))
+        }
+    }
+
+    #[test]
+    fn test_fetch_rejects_unsafe_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let staging = dir.path().join("a/b/staging");
+        std::fs::create_dir_all(&staging).unwrap();
+
+        let manifest = serde_json::json!({
+            "version": 2,
+            "timestamp": "2026-01-18_00-00-00",
+            "original_size": 4,
+            "chunk_size": 4,
+            "chunks": [{"name": "..", "size": 4}],
+            "sha256": "",
+        });
+        let remote = Untrusted(HashMap::from([
+            ("..".to_string(), b"evil".to_vec()),
+            (
+                "2026-01-18_00-00-00.enc.manifest".to_string(),
+                manifest.to_string().into_bytes(),
+            ),
+        ]));
+        let err = fetch_from(&remote, "untrusted", "2026-01-18_00-00-00", &staging).unwrap_err();
+        assert!(err.to_string().contains("unsafe"), "{}", err);
+
+        let snapshot = format!("{}/snapshots/2026-01-18_00-00-00.snap", INCREMENTAL_DIR);
+        let remote = Untrusted(HashMap::from([
+            (snapshot.clone(), b"{}".to_vec()),
+            (format!("{}/../../../escaped", INCREMENTAL_DIR), b"evil".to_vec()),
+        ]));
+        let err = fetch_from(&remote, "untrusted", &snapshot, &staging).unwrap_err();
+        assert!(err.to_string().contains("unsafe"), "{}", err);
+        assert!(!dir.path().join("escaped").exists());
+        assert!(!dir.path().join("a/escaped").exists());
+        assert!(std::fs::read_dir(&staging).unwrap().next().is_none());
+
+        assert!(check_relative("repo/chunks/ab/abcd").is_ok());
+        for name in ["/etc/passwd", "a//b", "./a", "a/../b", ""] {
+            assert!(check_relative(name).is_err(), "{}", name);
+        }
+    }
+}
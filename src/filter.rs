@@ -0,0 +1,173 @@
+use crate::config::Config;
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::{Path, PathBuf};
+
+/// Left out of every backup unless re-included: caches, logs and thumbnails
+/// that WhatsApp rebuilds on its own
+pub const DEFAULT_EXCLUDES: &[&str] = &[
+    "Library/Caches/",
+    "Library/Logs/",
+    "Logs/",
+    "tmp/",
+    "*.log",
+    "*.thumb",
+    ".DS_Store",
+];
+
+/// Gitignore-style include/exclude rules, relative to the WhatsApp container.
+/// A path is archived unless an exclude pattern matches it or one of its
+/// parents, and include patterns win over excludes.
+pub struct Filter {
+    exclude: Gitignore,
+    include: Gitignore,
+}
+
+/// File or directory that goes into the archive
+pub struct ArchiveEntry {
+    pub path: PathBuf,
+    /// Path relative to the WhatsApp container
+    pub relative: PathBuf,
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+fn build(root: &Path, patterns: &[&str]) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        builder
+            .add_line(None, pattern)
+            .with_context(|| format!("Invalid pattern: {}", pattern))?;
+    }
+    Ok(builder.build()?)
+}
+
+impl Filter {
+    pub fn new(root: &Path, include: &[String], exclude: &[String]) -> Result<Self> {
+        let excludes: Vec<&str> = DEFAULT_EXCLUDES
+            .iter()
+            .copied()
+            .chain(exclude.iter().map(String::as_str))
+            .collect();
+        let includes: Vec<&str> = include.iter().map(String::as_str).collect();
+        Ok(Self {
+            exclude: build(root, &excludes)?,
+            include: build(root, &includes)?,
+        })
+    }
+
+    /// Rules from `include` and `exclude` in config on top of the defaults
+    pub fn from_config(config: &Config, root: &Path) -> Result<Self> {
+        Self::new(root, &config.include, &config.exclude)
+    }
+
+    fn is_included(&self, path: &Path, is_dir: bool) -> bool {
+        self.include
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+    }
+
+    fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        self.exclude
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+            && !self.is_included(path, is_dir)
+    }
+
+    /// Everything under `root` that goes into the archive, in a stable order
+    pub fn entries(&self, root: &Path) -> Result<Vec<ArchiveEntry>> {
+        let mut entries = Vec::new();
+        self.walk(root, root, &mut entries)?;
+        Ok(entries)
+    }
+
+    fn walk(&self, root: &Path, dir: &Path, entries: &mut Vec<ArchiveEntry>) -> Result<()> {
+        let mut paths = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        paths.sort();
+
+        for path in paths {
+            let relative = path.strip_prefix(root)?.to_path_buf();
+            let is_dir = path.is_dir();
+            if is_dir {
+                let excluded = self.is_excluded(&path, true);
+                // An excluded directory is only entered if something inside
+                // might be re-included
+                if excluded && self.include.is_empty() {
+                    continue;
+                }
+                if !excluded {
+                    entries.push(ArchiveEntry {
+                        path: path.clone(),
+                        relative,
+                        is_dir,
+                        size: 0,
+                    });
+                }
+                self.walk(root, &path, entries)?;
+            } else if !self.is_excluded(&path, false) {
+                let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                entries.push(ArchiveEntry {
+                    path,
+                    relative,
+                    is_dir,
+                    size,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(root: &Path, path: &str) {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, b"data").unwrap();
+    }
+
+    #[test]
+    fn test_entries_apply_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for path in [
+            "ChatStorage.sqlite",
+            "Library/Caches/big.bin",
+            "Library/Caches/keep.db",
+            "Message/Media/a.jpg",
+            "Message/Media/a.thumb",
+            "Message/debug.log",
+            "Stickers/s.webp",
+        ] {
+            touch(root, path);
+        }
+
+        let filter = Filter::new(
+            root,
+            &["Library/Caches/keep.db".to_string()],
+            &["Stickers/".to_string()],
+        )
+        .unwrap();
+        let files: Vec<String> = filter
+            .entries(root)
+            .unwrap()
+            .iter()
+            .filter(|e| !e.is_dir)
+            .map(|e| e.relative.to_string_lossy().into_owned())
+            .collect();
+
+        assert_eq!(
+            files,
+            vec![
+                "ChatStorage.sqlite",
+                "Library/Caches/keep.db",
+                "Message/Media/a.jpg"
+            ]
+        );
+    }
+}
@@ -1,47 +1,94 @@
+use crate::config::{Config, GitHistory, GitProvider};
 use crate::paths;
 use anyhow::{Context, Result};
+use git2::{
+    Commit, Cred, CredentialType, ErrorCode, IndexAddOption, Oid, PushOptions, RemoteCallbacks,
+    Repository, RepositoryInitOptions, Signature,
+};
+use std::cell::{Cell, RefCell};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-const REPO_NAME: &str = "whatsapp-backup-encrypted";
-
-/// Creates a private GitHub repo using gh CLI
-pub fn create_github_repo() -> Result<String> {
-    let repo_path = paths::github_repo_dir()?;
+const REPO_DESCRIPTION: &str = "Encrypted WhatsApp Desktop backups";
+
+/// API tokens for creating repos
+pub const GITEA_TOKEN_VARIABLE: &str = "GITEA_TOKEN";
+pub const GITLAB_TOKEN_VARIABLE: &str = "GITLAB_TOKEN";
+
+const GITLAB_URL: &str = "https://gitlab.com";
+
+const BRANCH: &str = "refs/heads/main";
+
+/// Local ref the squashed commit is pushed from
+const SQUASH_REF: &str = "refs/whatsapp-backup/squash";
+
+/// Environment variable with a token for HTTPS remotes
+pub const TOKEN_VARIABLE: &str = "WHATSAPP_BACKUP_GIT_TOKEN";
+
+/// How often credentials are offered before giving up
+const CREDENTIAL_ATTEMPTS: u32 = 3;
+
+/// Used when git config has no `user.name`/`user.email`
+const COMMITTER_NAME: &str = "whatsapp-backup";
+const COMMITTER_EMAIL: &str = "whatsapp-backup@localhost";
+
+/// Failures of the git destination
+#[derive(Debug, thiserror::Error)]
+pub enum GitError {
+    #[error("Git error: {0}")]
+    Git(#[from] git2::Error),
+    #[error("Failed to write {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error(
+        "Authentication to {url} failed - set up an SSH key or agent, a credential helper, or {}",
+        TOKEN_VARIABLE
+    )]
+    Authentication { url: String },
+    #[error("Push of {reference} was rejected: {message}")]
+    Rejected { reference: String, message: String },
+    #[error("Remote {reference} is at {actual}, expected {expected} - history left unchanged")]
+    StaleLease {
+        reference: String,
+        expected: Oid,
+        actual: Oid,
+    },
+    #[error("{0} has uncommitted changes")]
+    Uncommitted(PathBuf),
+}
 
+/// Creates a private GitHub repo using gh CLI, or finds an existing one.
+/// Returns its SSH URL.
+fn create_github_repo(name: &str) -> Result<String> {
     // Check if gh is installed
     let gh_check = Command::new("gh").arg("--version").output();
     if gh_check.is_err() {
         anyhow::bail!(
             "GitHub CLI (gh) not found. Install with: brew install gh\n\
-             Then authenticate with: gh auth login"
+             Then authenticate with: gh auth login\n\
+             Or pass an existing repo with --remote"
         );
     }
 
     // Check if repo already exists on GitHub
     let check_output = Command::new("gh")
-        .args(["repo", "view", REPO_NAME])
+        .args(["repo", "view", name])
         .output()
         .context("Failed to check GitHub repo")?;
 
-    let repo_url = if check_output.status.success() {
-        // Repo exists, get its URL
-        let output = Command::new("gh")
-            .args(["repo", "view", REPO_NAME, "--json", "sshUrl", "-q", ".sshUrl"])
-            .output()
-            .context("Failed to get repo URL")?;
-
-        String::from_utf8_lossy(&output.stdout).trim().to_string()
-    } else {
+    if !check_output.status.success() {
         // Create new private repo
         let output = Command::new("gh")
             .args([
                 "repo",
                 "create",
-                REPO_NAME,
+                name,
                 "--private",
                 "--description",
-                "Encrypted WhatsApp Desktop backups",
+                REPO_DESCRIPTION,
             ])
             .output()
             .context("Failed to create GitHub repo")?;
@@ -50,15 +97,175 @@ pub fn create_github_repo() -> Result<String> {
             let stderr = String::from_utf8_lossy(&output.stderr);
             anyhow::bail!("Failed to create GitHub repo: {}", stderr);
         }
+    }
 
-        // Get the SSH URL
-        let url_output = Command::new("gh")
-            .args(["repo", "view", REPO_NAME, "--json", "sshUrl", "-q", ".sshUrl"])
-            .output()
-            .context("Failed to get repo URL")?;
+    // Get the SSH URL
+    let url_output = Command::new("gh")
+        .args(["repo", "view", name, "--json", "sshUrl", "-q", ".sshUrl"])
+        .output()
+        .context("Failed to get repo URL")?;
+
+    Ok(String::from_utf8_lossy(&url_output.stdout)
+        .trim()
+        .to_string())
+}
+
+/// Reads an API token for a provider from the environment
+fn api_token(variable: &str) -> Result<String> {
+    std::env::var(variable).with_context(|| {
+        format!(
+            "Set {} to create the repo, or pass an existing one with --remote",
+            variable
+        )
+    })
+}
+
+/// Calls a provider's REST API. Returns `None` for 404 Not Found.
+fn api_call(
+    request: ureq::Request,
+    body: Option<serde_json::Value>,
+) -> Result<Option<serde_json::Value>> {
+    let url = request.url().to_string();
+    let response = match body {
+        Some(body) => request.send_json(body),
+        None => request.call(),
+    };
+    match response {
+        Ok(response) => Ok(Some(response.into_json()?)),
+        Err(ureq::Error::Status(404, _)) => Ok(None),
+        Err(ureq::Error::Status(code, response)) => {
+            let message = response.into_string().unwrap_or_default();
+            anyhow::bail!("{} returned {}: {}", url, code, message)
+        }
+        Err(e) => Err(e).with_context(|| format!("Request to {} failed", url)),
+    }
+}
+
+/// String field of an API response
+fn json_field(value: &serde_json::Value, field: &str) -> Result<String> {
+    value[field]
+        .as_str()
+        .map(String::from)
+        .with_context(|| format!("API response has no '{}'", field))
+}
+
+/// Creates a private repo on Gitea (or Forgejo) at `api_url`, or finds an
+/// existing one. Returns its SSH URL.
+fn create_gitea_repo(api_url: &str, name: &str) -> Result<String> {
+    let api = format!("{}/api/v1", api_url.trim_end_matches('/'));
+    let auth = format!("token {}", api_token(GITEA_TOKEN_VARIABLE)?);
+
+    let user = api_call(
+        ureq::get(&format!("{}/user", api)).set("Authorization", &auth),
+        None,
+    )?
+    .context("Failed to look up the Gitea user")?;
+    let owner = json_field(&user, "login")?;
+
+    let existing = api_call(
+        ureq::get(&format!("{}/repos/{}/{}", api, owner, name)).set("Authorization", &auth),
+        None,
+    )?;
+    let repo = match existing {
+        Some(repo) => repo,
+        None => api_call(
+            ureq::post(&format!("{}/user/repos", api)).set("Authorization", &auth),
+            Some(serde_json::json!({
+                "name": name,
+                "private": true,
+                "description": REPO_DESCRIPTION,
+            })),
+        )?
+        .context("Failed to create Gitea repo")?,
+    };
+    json_field(&repo, "ssh_url")
+}
 
-        String::from_utf8_lossy(&url_output.stdout).trim().to_string()
+/// Creates a private project on GitLab at `api_url`, or finds an existing
+/// one. Returns its SSH URL.
+fn create_gitlab_repo(api_url: &str, name: &str) -> Result<String> {
+    let api = format!("{}/api/v4", api_url.trim_end_matches('/'));
+    let token = api_token(GITLAB_TOKEN_VARIABLE)?;
+
+    let user = api_call(
+        ureq::get(&format!("{}/user", api)).set("PRIVATE-TOKEN", &token),
+        None,
+    )?
+    .context("Failed to look up the GitLab user")?;
+    let owner = json_field(&user, "username")?;
+
+    // Project paths are looked up URL-encoded
+    let existing = api_call(
+        ureq::get(&format!("{}/projects/{}%2F{}", api, owner, name)).set("PRIVATE-TOKEN", &token),
+        None,
+    )?;
+    let project = match existing {
+        Some(project) => project,
+        None => api_call(
+            ureq::post(&format!("{}/projects", api)).set("PRIVATE-TOKEN", &token),
+            Some(serde_json::json!({
+                "name": name,
+                "visibility": "private",
+                "description": REPO_DESCRIPTION,
+            })),
+        )?
+        .context("Failed to create GitLab project")?,
     };
+    json_field(&project, "ssh_url_to_repo")
+}
+
+/// Whether `url` is a path on this machine rather than an SSH or HTTPS remote
+fn is_local_path(url: &str) -> bool {
+    url.starts_with("file://")
+        || url.starts_with('/')
+        || url.starts_with('.')
+        || url.starts_with('~')
+}
+
+/// `url` with a leading `~/` replaced by the home directory; git2 would
+/// otherwise take `~` as a directory name
+fn expand_home(url: &str) -> Result<String> {
+    let Some(rest) = url.strip_prefix('~') else {
+        return Ok(url.to_string());
+    };
+    if !rest.is_empty() && !rest.starts_with('/') {
+        anyhow::bail!("Can't expand '{}': use an absolute path for the remote", url);
+    }
+    let home = dirs::home_dir().context("Failed to detect home directory")?;
+    Ok(format!("{}{}", home.display(), rest))
+}
+
+/// Works out the remote URL, creating the repo through the provider's API
+/// when no `remote` is given (or a bare repo for a local path that doesn't
+/// exist yet), and sets up the local checkout
+pub fn setup_remote(config: &Config, remote: Option<&str>) -> Result<String> {
+    let url = match (remote, config.git_provider) {
+        (Some(url), _) => {
+            let url = expand_home(url)?;
+            let path = Path::new(url.strip_prefix("file://").unwrap_or(&url));
+            if is_local_path(&url) && !path.exists() {
+                Repository::init_bare(path)
+                    .with_context(|| format!("Failed to create bare repo: {}", path.display()))?;
+                println!("  Created bare repo: {}", path.display());
+            }
+            url
+        }
+        (None, GitProvider::Github) => create_github_repo(&config.git_repo_name)?,
+        (None, GitProvider::Gitea) => {
+            let api_url = config
+                .git_api_url
+                .as_deref()
+                .context("Gitea needs --api-url (e.g. https://git.example.com)")?;
+            create_gitea_repo(api_url, &config.git_repo_name)?
+        }
+        (None, GitProvider::Gitlab) => create_gitlab_repo(
+            config.git_api_url.as_deref().unwrap_or(GITLAB_URL),
+            &config.git_repo_name,
+        )?,
+        (None, GitProvider::Plain) => anyhow::bail!("A plain remote needs --remote <url>"),
+    };
+
+    let repo_path = config.git_checkout_dir()?;
 
     // Create local directory if needed
     if !repo_path.exists() {
@@ -66,151 +273,431 @@ pub fn create_github_repo() -> Result<String> {
             .with_context(|| format!("Failed to create repo dir: {}", repo_path.display()))?;
     }
 
-    // Initialize git repo locally if not already done
+    // Initialize git repo locally if not already done, or point it at the new remote
     if !repo_path.join(".git").exists() {
-        let output = Command::new("git")
-            .args(["init"])
-            .current_dir(&repo_path)
-            .output()
-            .context("Failed to init git repo")?;
-
-        if !output.status.success() {
-            anyhow::bail!("git init failed");
-        }
-
-        // Set default branch to main
-        Command::new("git")
-            .args(["checkout", "-b", "main"])
-            .current_dir(&repo_path)
-            .output()
-            .ok();
-
-        // Add remote
-        Command::new("git")
-            .args(["remote", "add", "origin", &repo_url])
-            .current_dir(&repo_path)
-            .output()
-            .context("Failed to add remote")?;
+        init_repo(&repo_path, &url)?;
+    } else {
+        Repository::open(&repo_path)?.remote_set_url("origin", &url)?;
     }
 
-    Ok(repo_url)
+    Ok(url)
 }
 
-/// Commits and pushes a backup file using git CLI
-pub fn commit_and_push(file_path: &Path, message: &str) -> Result<()> {
-    commit_and_push_files(&[file_path.to_path_buf()], message)
+/// Creates the local checkout with `main` as its branch and `url` as `origin`
+pub fn init_repo(dir: &Path, url: &str) -> Result<(), GitError> {
+    let mut options = RepositoryInitOptions::new();
+    options.initial_head("main");
+    let repo = Repository::init_opts(dir, &options)?;
+    repo.remote("origin", url)?;
+    Ok(())
 }
 
-/// Removes old backup chunks from the repo before pushing new ones
-fn remove_old_chunks(repo_dir: &Path) -> Result<()> {
-    for entry in std::fs::read_dir(repo_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-
-        // Remove old chunks (.enc.001, etc) and manifests
-        if (name.contains(".enc.") && !name.ends_with(".enc"))
-            || name.ends_with(".manifest")
-        {
-            std::fs::remove_file(&path).ok();
-        }
-    }
-    Ok(())
+/// Local checkout of a backup repo that is pushed to `origin/main`
+pub struct GitRepo {
+    repo: Repository,
 }
 
-/// Commits and pushes multiple files using git CLI (incrementally to avoid GitHub limits)
-pub fn commit_and_push_files(files: &[PathBuf], message: &str) -> Result<()> {
-    let repo_dir = paths::github_repo_dir()?;
+impl GitRepo {
+    pub fn open(dir: &Path) -> Result<Self, GitError> {
+        Ok(Self {
+            repo: Repository::open(dir)?,
+        })
+    }
 
-    // Remove old chunks before adding new ones
-    remove_old_chunks(&repo_dir)?;
+    /// The configured checkout of the git remote
+    pub fn checkout() -> Result<Self> {
+        let dir = paths::git_checkout_dir()?;
+        Self::open(&dir).with_context(|| format!("Failed to open git repo: {}", dir.display()))
+    }
 
-    // Stage deletions first
-    let output = Command::new("git")
-        .args(["add", "-A"])
-        .current_dir(&repo_dir)
-        .output()
-        .context("Failed to run git add")?;
+    fn workdir(&self) -> &Path {
+        self.repo.workdir().unwrap_or_else(|| self.repo.path())
+    }
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("git add failed: {}", stderr);
+    fn head_commit(&self) -> Result<Option<Commit<'_>>, GitError> {
+        match self.repo.head() {
+            Ok(head) => Ok(Some(head.peel_to_commit()?)),
+            Err(e) if matches!(e.code(), ErrorCode::UnbornBranch | ErrorCode::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
     }
 
-    // Commit deletions if any
-    let output = Command::new("git")
-        .args(["commit", "-m", "Remove old backup chunks"])
-        .current_dir(&repo_dir)
-        .output()
-        .context("Failed to run git commit")?;
+    fn signature(&self) -> Result<Signature<'static>, GitError> {
+        match self.repo.signature() {
+            Ok(signature) => Ok(signature),
+            Err(_) => Ok(Signature::now(COMMITTER_NAME, COMMITTER_EMAIL)?),
+        }
+    }
 
-    // Push deletions (ignore if nothing to commit)
-    if output.status.success() {
-        let output = Command::new("git")
-            .args(["push", "-u", "origin", "main"])
-            .current_dir(&repo_dir)
-            .output()
-            .context("Failed to run git push")?;
+    /// Commits the index on top of HEAD. Returns false if nothing changed.
+    fn commit_index(&self, message: &str) -> Result<bool, GitError> {
+        let mut index = self.repo.index()?;
+        let tree_id = index.write_tree()?;
+        let parent = self.head_commit()?;
+        if parent.as_ref().map(|p| p.tree_id()) == Some(tree_id) {
+            return Ok(false);
+        }
+        index.write()?;
+
+        let tree = self.repo.find_tree(tree_id)?;
+        let signature = self.signature()?;
+        let parents: Vec<&Commit> = parent.iter().collect();
+        self.repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )?;
+        Ok(true)
+    }
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("git push failed: {}", stderr);
+    /// Commits every change under `subdir` (`.` for the whole repo) in a
+    /// single commit and pushes it
+    pub fn commit_and_push_dir(&self, subdir: &str, message: &str) -> Result<(), GitError> {
+        let pathspec = if subdir == "." { "*" } else { subdir };
+        let mut index = self.repo.index()?;
+        index.add_all([pathspec], IndexAddOption::DEFAULT, None)?;
+        // Picks up deleted files too
+        index.update_all([pathspec], None)?;
+        index.write()?;
+
+        if self.commit_index(message)? {
+            self.push(BRANCH, None)?;
         }
+        Ok(())
     }
 
-    // Push each file incrementally
-    let total = files.len();
-    for (i, file_path) in files.iter().enumerate() {
-        let file_name = file_path.file_name().context("Invalid file path")?;
-        let dest_path = repo_dir.join(file_name);
-        std::fs::copy(file_path, &dest_path).context("Failed to copy file to repo")?;
+    /// Pushes `source` to `main` on origin, printing upload progress. With
+    /// `lease`, the push is forced but cancelled unless the remote branch is
+    /// still at that commit.
+    fn push(&self, source: &str, lease: Option<Oid>) -> Result<(), GitError> {
+        let mut remote = self.repo.find_remote("origin")?;
+        let url = remote.url().unwrap_or("origin").to_string();
+        let config = self.repo.config()?;
+
+        let attempts = Cell::new(0);
+        let stale = Cell::new(None);
+        let rejected = RefCell::new(None);
+        let progress_shown = Cell::new(false);
+
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(|url, username, allowed| {
+            attempts.set(attempts.get() + 1);
+            credentials(&config, url, username, allowed, attempts.get())
+        });
+        callbacks.push_transfer_progress(|current, total, bytes| {
+            if total > 0 {
+                print!(
+                    "\r  Uploading: {}/{} objects, {:.2} MB",
+                    current,
+                    total,
+                    bytes as f64 / 1_000_000.0
+                );
+                std::io::stdout().flush().ok();
+                progress_shown.set(true);
+            }
+        });
+        callbacks.push_negotiation(|updates| {
+            let Some(expected) = lease else {
+                return Ok(());
+            };
+            for update in updates {
+                if update.dst_refname() == Some(BRANCH) && update.src() != expected {
+                    stale.set(Some(update.src()));
+                    return Err(git2::Error::from_str("remote branch moved"));
+                }
+            }
+            Ok(())
+        });
+        callbacks.push_update_reference(|reference, status| {
+            if let Some(message) = status {
+                *rejected.borrow_mut() = Some(GitError::Rejected {
+                    reference: reference.to_string(),
+                    message: message.to_string(),
+                });
+            }
+            Ok(())
+        });
+
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+        let refspec = match lease {
+            Some(_) => format!("+{}:{}", source, BRANCH),
+            None => format!("{}:{}", source, BRANCH),
+        };
+        let result = remote.push(&[refspec.as_str()], Some(&mut options));
+        // Ends the callbacks' borrows of the state above
+        drop(options);
+        if progress_shown.get() {
+            println!();
+        }
 
-        // git add
-        let output = Command::new("git")
-            .args(["add", &file_name.to_string_lossy()])
-            .current_dir(&repo_dir)
-            .output()
-            .context("Failed to run git add")?;
+        if let (Some(expected), Some(actual)) = (lease, stale.get()) {
+            return Err(GitError::StaleLease {
+                reference: BRANCH.to_string(),
+                expected,
+                actual,
+            });
+        }
+        match result {
+            Err(e) if e.code() == ErrorCode::Auth || attempts.get() > CREDENTIAL_ATTEMPTS => {
+                Err(GitError::Authentication { url })
+            }
+            Err(e) => Err(e.into()),
+            Ok(()) => match rejected.into_inner() {
+                Some(rejection) => Err(rejection),
+                None => Ok(()),
+            },
+        }
+    }
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("git add failed: {}", stderr);
+    /// Replaces the history of `main` with a single commit holding its
+    /// current tree, force-pushes it and drops the old objects locally.
+    ///
+    /// The new commit shares its tree with the old one, so only the commit
+    /// itself is uploaded, and the remote branch moves only if it still points
+    /// at the squashed commit. An interrupted or rejected push leaves both the
+    /// remote and the local history as they were.
+    pub fn squash_history(&self, message: &str) -> Result<(), GitError> {
+        let Some(head) = self.head_commit()? else {
+            return Ok(());
+        };
+        if head.parent_count() == 0 {
+            return Ok(());
+        }
+        // The index must not hold blobs that only the old history knows about
+        if self.repo.index()?.write_tree()? != head.tree_id() {
+            return Err(GitError::Uncommitted(self.workdir().to_path_buf()));
         }
 
-        // git commit
-        let commit_msg = format!("{} ({}/{})", message, i + 1, total);
-        let output = Command::new("git")
-            .args(["commit", "-m", &commit_msg])
-            .current_dir(&repo_dir)
-            .output()
-            .context("Failed to run git commit")?;
+        let signature = self.signature()?;
+        let squashed =
+            self.repo
+                .commit(None, &signature, &signature, message, &head.tree()?, &[])?;
+        self.repo
+            .reference(SQUASH_REF, squashed, true, "squash history")?;
+        let pushed = self.push(SQUASH_REF, Some(head.id()));
+        self.repo.find_reference(SQUASH_REF)?.delete()?;
+        pushed?;
+
+        // Only now that the remote has it does the local branch move
+        self.repo
+            .reference(BRANCH, squashed, true, "squash history")?;
+        self.compact()
+    }
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            if !stderr.contains("nothing to commit") {
-                anyhow::bail!("git commit failed: {}", stderr);
+    /// Repacks everything still reachable from a reference into one pack and
+    /// deletes all other packs, loose objects and reflogs
+    fn compact(&self) -> Result<(), GitError> {
+        let mut walk = self.repo.revwalk()?;
+        for reference in self.repo.references()? {
+            if let Some(target) = reference?.target() {
+                walk.push(target)?;
             }
         }
 
-        // git push
-        let output = Command::new("git")
-            .args(["push", "-u", "origin", "main"])
-            .current_dir(&repo_dir)
-            .output()
-            .context("Failed to run git push")?;
+        let objects = self.repo.path().join("objects");
+        let pack_dir = objects.join("pack");
+        let mut packer = self.repo.packbuilder()?;
+        packer.insert_walk(&mut walk)?;
+        packer.write(&pack_dir, 0o444)?;
+        let Some(name) = packer.name().map(|n| format!("pack-{}", n)) else {
+            return Ok(());
+        };
+
+        let io_error = |source| GitError::Io {
+            path: objects.clone(),
+            source,
+        };
+        for entry in std::fs::read_dir(&pack_dir).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+            if stem.starts_with("pack-") && stem != name {
+                std::fs::remove_file(&path).map_err(io_error)?;
+            }
+        }
+        for entry in std::fs::read_dir(&objects).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            let dir_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if dir_name.len() == 2 && dir_name.chars().all(|c| c.is_ascii_hexdigit()) {
+                std::fs::remove_dir_all(&path).map_err(io_error)?;
+            }
+        }
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("git push failed for {}: {}", file_name.to_string_lossy(), stderr);
+        let names: Vec<String> = self
+            .repo
+            .references()?
+            .names()
+            .map(|n| n.map(String::from))
+            .collect::<Result<_, _>>()?;
+        for name in names {
+            self.repo.reflog_delete(&name)?;
         }
+        Ok(())
     }
+}
 
-    Ok(())
+/// Offers the SSH agent, then the default key files, for SSH remotes, and
+/// the token variable or git's credential helpers for HTTPS remotes
+fn credentials(
+    config: &git2::Config,
+    url: &str,
+    username: Option<&str>,
+    allowed: CredentialType,
+    attempt: u32,
+) -> Result<Cred, git2::Error> {
+    if attempt > CREDENTIAL_ATTEMPTS {
+        return Err(git2::Error::from_str("no more credentials to try"));
+    }
+    let username = username.unwrap_or("git");
+
+    if allowed.contains(CredentialType::SSH_KEY) {
+        if attempt == 1 {
+            return Cred::ssh_key_from_agent(username);
+        }
+        let home = dirs::home_dir().unwrap_or_default();
+        if let Some(key) = ["id_ed25519", "id_ecdsa", "id_rsa"]
+            .iter()
+            .map(|name| home.join(".ssh").join(name))
+            .find(|path| path.exists())
+        {
+            return Cred::ssh_key(username, None, &key, None);
+        }
+    }
+    if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+        if let Ok(token) = std::env::var(TOKEN_VARIABLE) {
+            return Cred::userpass_plaintext("x-access-token", &token);
+        }
+        return Cred::credential_helper(config, url, Some(username));
+    }
+    if allowed.contains(CredentialType::USERNAME) {
+        return Cred::username(username);
+    }
+    Cred::default()
+}
+
+/// Squashes the git remote's history if `git_history` is `squash`, so
+/// only the backups currently in the checkout are kept on the remote
+pub fn bound_history(config: &Config) -> Result<()> {
+    if config.git_history != GitHistory::Squash || !is_repo_initialized() {
+        return Ok(());
+    }
+    println!("Squashing git history...");
+    Ok(GitRepo::checkout()?.squash_history("Retained backups")?)
 }
 
 /// Checks if the git repo is set up
 pub fn is_repo_initialized() -> bool {
-    paths::github_repo_dir()
+    paths::git_checkout_dir()
         .map(|p| p.join(".git").exists())
         .unwrap_or(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit_count(repo: &Repository) -> usize {
+        let mut walk = repo.revwalk().unwrap();
+        walk.push_ref(BRANCH).unwrap();
+        walk.count()
+    }
+
+    fn remote_files(repo: &Repository) -> Vec<String> {
+        let tree = repo.find_reference(BRANCH).unwrap().peel_to_tree().unwrap();
+        tree.iter()
+            .filter_map(|e| e.name().map(String::from))
+            .collect()
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(
+            expand_home("~/backups.git").unwrap(),
+            format!("{}/backups.git", home.display())
+        );
+        assert_eq!(expand_home("~").unwrap(), home.display().to_string());
+        assert!(expand_home("~other/backups.git").is_err());
+        assert_eq!(
+            expand_home("git@github.com:u/r.git").unwrap(),
+            "git@github.com:u/r.git"
+        );
+    }
+
+    #[test]
+    fn test_push_and_squash_to_bare_repo() {
+        let tmp = tempfile::tempdir().unwrap();
+        let remote_dir = tmp.path().join("remote.git");
+        let work = tmp.path().join("work");
+        let remote = Repository::init_bare(&remote_dir).unwrap();
+        init_repo(&work, remote_dir.to_str().unwrap()).unwrap();
+        let repo = GitRepo::open(&work).unwrap();
+
+        std::fs::write(work.join("old.enc"), b"data").unwrap();
+        repo.commit_and_push_dir(".", "Backup old (1/2)").unwrap();
+        std::fs::write(work.join("old.enc.manifest"), b"data").unwrap();
+        repo.commit_and_push_dir(".", "Backup old (2/2)").unwrap();
+        assert_eq!(commit_count(&remote), 2);
+
+        std::fs::remove_file(work.join("old.enc")).unwrap();
+        std::fs::remove_file(work.join("old.enc.manifest")).unwrap();
+        std::fs::write(work.join("new.enc"), b"data").unwrap();
+        repo.commit_and_push_dir(".", "Prune").unwrap();
+        assert_eq!(remote_files(&remote), vec!["new.enc"]);
+        // Nothing changed, so nothing is committed
+        repo.commit_and_push_dir(".", "Prune").unwrap();
+        assert_eq!(commit_count(&remote), 3);
+
+        let old_head = repo.head_commit().unwrap().unwrap().id();
+        repo.squash_history("Retained backups").unwrap();
+        assert_eq!(commit_count(&remote), 1);
+        assert_eq!(remote_files(&remote), vec!["new.enc"]);
+        let reopened = GitRepo::open(&work).unwrap();
+        assert!(reopened.repo.find_commit(old_head).is_err());
+        assert_eq!(commit_count(&reopened.repo), 1);
+
+        // Unpushed local commits must not be force-pushed over the remote
+        std::fs::write(work.join("unpushed.enc"), b"data").unwrap();
+        let mut index = reopened.repo.index().unwrap();
+        index.add_path(Path::new("unpushed.enc")).unwrap();
+        index.write().unwrap();
+        reopened.commit_index("Unpushed").unwrap();
+        let remote_head = remote.refname_to_id(BRANCH).unwrap();
+        let result = reopened.squash_history("Retained backups");
+        assert!(matches!(result, Err(GitError::StaleLease { .. })));
+        assert_eq!(remote.refname_to_id(BRANCH).unwrap(), remote_head);
+        assert_eq!(commit_count(&reopened.repo), 2);
+    }
+
+    #[test]
+    fn test_setup_plain_local_remote() {
+        let tmp = tempfile::tempdir().unwrap();
+        let remote = tmp.path().join("backups.git");
+        let config = Config {
+            git_provider: GitProvider::Plain,
+            git_checkout: Some(tmp.path().join("checkout")),
+            ..Config::default()
+        };
+        assert!(setup_remote(&config, None).is_err());
+
+        let url = setup_remote(&config, remote.to_str()).unwrap();
+        assert!(Repository::open_bare(&remote).is_ok());
+        let checkout = Repository::open(tmp.path().join("checkout")).unwrap();
+        assert_eq!(
+            checkout.find_remote("origin").unwrap().url(),
+            Some(url.as_str())
+        );
+
+        // Running it again points the existing checkout at the new remote
+        let other = tmp.path().join("other.git");
+        setup_remote(&config, other.to_str()).unwrap();
+        let checkout = Repository::open(tmp.path().join("checkout")).unwrap();
+        assert_eq!(
+            checkout.find_remote("origin").unwrap().url(),
+            other.to_str()
+        );
+    }
+}
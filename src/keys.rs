@@ -0,0 +1,226 @@
+use crate::config::{Config, KeySlotKind};
+use crate::{crypto, paths, recovery, secrets};
+use aes_gcm::aead::OsRng;
+use anyhow::{Context, Result};
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Text prefixes for encoded X25519 keys
+const PUBLIC_KEY_PREFIX: &str = "wbpk1";
+const SECRET_KEY_PREFIX: &str = "wbsk1";
+
+const IDENTITY_FILE: &str = "identity.key";
+
+/// Generates a new X25519 keypair
+pub fn generate_keypair() -> (StaticSecret, PublicKey) {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    (secret, public)
+}
+
+pub fn encode_public_key(public: &PublicKey) -> String {
+    format!("{}{}", PUBLIC_KEY_PREFIX, hex::encode(public.as_bytes()))
+}
+
+pub fn parse_public_key(encoded: &str) -> Result<PublicKey> {
+    let bytes = decode_key(encoded, PUBLIC_KEY_PREFIX).context("Invalid public key")?;
+    Ok(PublicKey::from(bytes))
+}
+
+fn encode_secret_key(secret: &StaticSecret) -> String {
+    format!("{}{}", SECRET_KEY_PREFIX, hex::encode(secret.to_bytes()))
+}
+
+fn parse_secret_key(encoded: &str) -> Result<StaticSecret> {
+    let bytes = decode_key(encoded, SECRET_KEY_PREFIX).context("Invalid secret key")?;
+    Ok(StaticSecret::from(bytes))
+}
+
+fn decode_key(encoded: &str, prefix: &str) -> Result<[u8; 32]> {
+    let hex_part = encoded
+        .trim()
+        .strip_prefix(prefix)
+        .with_context(|| format!("Expected a key starting with '{}'", prefix))?;
+    let bytes = hex::decode(hex_part)?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Key must be 32 bytes"))
+}
+
+/// Default location of the passphrase-protected private key
+pub fn identity_path() -> Result<PathBuf> {
+    Ok(paths::config_dir()?.join(IDENTITY_FILE))
+}
+
+/// Writes the private key encrypted with `passphrase`, readable only by the owner
+pub fn write_identity(path: &Path, secret: &StaticSecret, passphrase: &str) -> Result<()> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to write identity: {}", path.display()))?;
+
+    let mut encryptor = crypto::StreamEncryptor::new(file, passphrase)?;
+    encryptor.write_all(encode_secret_key(secret).as_bytes())?;
+    encryptor.finish()?;
+    Ok(())
+}
+
+/// Reads a private key file, either plain (`wbsk1...`) or passphrase-protected.
+/// The stored passphrase is tried first, then the passphrase is prompted for.
+pub fn read_identity(path: &Path) -> Result<StaticSecret> {
+    let contents =
+        std::fs::read(path).with_context(|| format!("Failed to read identity: {}", path.display()))?;
+
+    if contents.starts_with(SECRET_KEY_PREFIX.as_bytes()) {
+        return parse_secret_key(&String::from_utf8_lossy(&contents));
+    }
+
+    if let Ok(secret) = crypto::get_passphrase().and_then(|p| unlock_identity(&contents, &p)) {
+        return Ok(secret);
+    }
+
+    let passphrase = prompt_passphrase(&format!("Passphrase for {}: ", path.display()))?;
+    unlock_identity(&contents, &passphrase)
+        .with_context(|| format!("Failed to unlock identity: {}", path.display()))
+}
+
+/// Re-encrypts a passphrase-protected identity file under a new passphrase
+pub fn change_identity_passphrase(path: &Path, old: &str, new: &str) -> Result<()> {
+    let contents =
+        std::fs::read(path).with_context(|| format!("Failed to read identity: {}", path.display()))?;
+    let secret = unlock_identity(&contents, old)
+        .with_context(|| format!("Failed to unlock identity: {}", path.display()))?;
+
+    // Verify the new file before it replaces the old one
+    let tmp = path.with_extension("tmp");
+    write_identity(&tmp, &secret, new)?;
+    let rewritten = unlock_identity(&std::fs::read(&tmp)?, new)?;
+    if rewritten.to_bytes() != secret.to_bytes() {
+        std::fs::remove_file(&tmp).ok();
+        anyhow::bail!("Re-encrypted identity does not match");
+    }
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("Failed to write identity: {}", path.display()))
+}
+
+/// Whether the identity file is stored without passphrase protection
+pub fn is_plain_identity(path: &Path) -> Result<bool> {
+    let contents =
+        std::fs::read(path).with_context(|| format!("Failed to read identity: {}", path.display()))?;
+    Ok(contents.starts_with(SECRET_KEY_PREFIX.as_bytes()))
+}
+
+/// Decrypts a passphrase-protected private key
+fn unlock_identity(mut encrypted: &[u8], passphrase: &str) -> Result<StaticSecret> {
+    let header = crypto::read_header(&mut encrypted)?;
+    let mut encoded = String::new();
+    crypto::decrypt_reader(
+        encrypted,
+        header,
+        &[crypto::Identity::Passphrase(passphrase.to_string())],
+    )?
+    .read_to_string(&mut encoded)?;
+    parse_secret_key(&encoded)
+}
+
+/// Recipients new backups are encrypted to, one per configured key slot.
+/// Passphrases are read from the secret store.
+pub fn recipients(config: &Config) -> Result<Vec<crypto::Recipient>> {
+    config
+        .key_slots()
+        .iter()
+        .map(|slot| match &slot.kind {
+            KeySlotKind::Passphrase { account } => {
+                let passphrase = crypto::get_passphrase_for(account)
+                    .with_context(|| format!("No passphrase for key slot '{}'", slot.label))?;
+                Ok(crypto::Recipient::passphrase(&passphrase))
+            }
+            KeySlotKind::PublicKey { public_key } => Ok(crypto::Recipient::PublicKey(
+                parse_public_key(public_key)
+                    .with_context(|| format!("Invalid key slot '{}'", slot.label))?,
+            )),
+        })
+        .collect()
+}
+
+/// Secrets available to decrypt a backup: the private key from `identity_file`
+/// (or the default identity file) and the stored passphrases of the
+/// configured slots. Prompts for a passphrase when none are available.
+pub fn identities(config: &Config, identity_file: Option<&Path>) -> Result<Vec<crypto::Identity>> {
+    let mut identities = Vec::new();
+
+    let default_identity = identity_path()?;
+    let identity_file = identity_file.or(Some(default_identity.as_path()).filter(|p| p.exists()));
+    if let Some(path) = identity_file {
+        identities.push(crypto::Identity::SecretKey(read_identity(path)?));
+    }
+
+    let mut accounts = vec![secrets::DEFAULT_ACCOUNT.to_string()];
+    for slot in config.key_slots() {
+        if let KeySlotKind::Passphrase { account } = slot.kind {
+            if !accounts.contains(&account) {
+                accounts.push(account);
+            }
+        }
+    }
+    for account in &accounts {
+        if let Ok(passphrase) = crypto::get_passphrase_for(account) {
+            identities.push(crypto::Identity::Passphrase(passphrase));
+        }
+    }
+
+    if identities.is_empty() {
+        let passphrase = prompt_passphrase("Passphrase: ")?;
+        identities.push(crypto::Identity::Passphrase(passphrase));
+    }
+    Ok(identities)
+}
+
+/// Secrets to try when restoring: the key rebuilt from recovery shares, or
+/// the identity file and stored passphrases
+pub fn restore_identities(
+    identity_file: Option<&Path>,
+    shares: bool,
+) -> Result<Vec<crypto::Identity>> {
+    if shares {
+        let secret = recovery::prompt_shares()?;
+        println!("Recovery key rebuilt from shares");
+        return Ok(vec![crypto::Identity::SecretKey(secret)]);
+    }
+    identities(&Config::load()?, identity_file)
+}
+
+/// Reads a passphrase from stdin after printing `prompt`
+pub fn prompt_passphrase(prompt: &str) -> Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+
+    // Simple stdin read for passphrase (terminal echo disabled would be better)
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_encoding_roundtrip() {
+        let (secret, public) = generate_keypair();
+
+        let parsed = parse_public_key(&encode_public_key(&public)).unwrap();
+        assert_eq!(parsed.as_bytes(), public.as_bytes());
+
+        let parsed = parse_secret_key(&encode_secret_key(&secret)).unwrap();
+        assert_eq!(parsed.to_bytes(), secret.to_bytes());
+
+        assert!(parse_public_key(&encode_secret_key(&secret)).is_err());
+    }
+}
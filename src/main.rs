@@ -1,14 +1,28 @@
 mod backup;
 mod config;
 mod crypto;
+mod dedup;
+mod destination;
+mod filter;
 mod git;
+mod keys;
 mod paths;
+mod rclone;
+mod recovery;
+mod rekey;
 mod restore;
+mod retention;
+mod s3;
+mod sftp;
+mod secrets;
+mod signing;
+mod sqlite;
+mod verify;
+mod webdav;
 
 use anyhow::Result;
-use clap::{Parser, Subcommand};
-use config::Config;
-use std::io::{self, Write};
+use clap::{Args, Parser, Subcommand};
+use config::{Config, GitProvider, KeySlot, KeySlotKind};
 use std::path::PathBuf;
 use std::process::Command;
 
@@ -23,20 +37,98 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Initialize encryption key and GitHub repo
-    Init,
+    /// Initialize encryption key and git remote
+    Init {
+        /// Encrypt backups to a generated X25519 public key; the passphrase
+        /// only protects the private key and is not stored in the secret store
+        #[arg(long)]
+        public_key: bool,
+        /// Also create a recovery kit with this many shares
+        #[arg(long, requires = "recovery_threshold")]
+        recovery_shares: Option<u8>,
+        /// Number of recovery shares needed to restore
+        #[arg(long, requires = "recovery_shares")]
+        recovery_threshold: Option<u8>,
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
     /// Run backup now
-    Backup,
+    Backup {
+        /// Store a deduplicated incremental snapshot (default: `incremental` in config)
+        #[arg(long)]
+        incremental: bool,
+        /// List the files that would be archived and their total size, without backing up
+        #[arg(long)]
+        dry_run: bool,
+        /// Back up even if the WhatsApp data hasn't changed since the last backup
+        #[arg(long)]
+        force: bool,
+    },
     /// Restore from a backup file
     Restore {
-        /// Path to encrypted backup file
+        /// Path to encrypted backup file, manifest or snapshot (.snap), or the
+        /// name of one at a destination as shown by `list`
         file: PathBuf,
+        /// Destination to restore from (default: the first that has the backup)
+        #[arg(long)]
+        from: Option<String>,
         /// Output directory (default: current directory)
         #[arg(short, long)]
         output: Option<PathBuf>,
+        /// Private key file for public-key backups (default: identity.key in the config dir)
+        #[arg(long)]
+        identity: Option<PathBuf>,
+        /// Rebuild the recovery key from recovery kit shares (prompts for them)
+        #[arg(long, conflicts_with = "identity")]
+        shares: bool,
+        /// Restore even if the manifest signature is missing or invalid
+        #[arg(long)]
+        allow_unsigned: bool,
+    },
+    /// Check that backups are restorable without extracting them; exits
+    /// non-zero if any check fails
+    Verify {
+        /// Path to encrypted backup file, manifest or snapshot (.snap)
+        #[arg(required_unless_present_any = ["all", "latest"])]
+        file: Option<PathBuf>,
+        /// Verify every backup at every destination
+        #[arg(long, conflicts_with_all = ["file", "latest"])]
+        all: bool,
+        /// Verify the newest backup at each destination
+        #[arg(long, conflicts_with = "file")]
+        latest: bool,
+        /// How far to check: checksum, decrypt or contents (includes the levels before it)
+        #[arg(long, value_enum, default_value = "contents")]
+        level: verify::Level,
+        /// Private key file for public-key backups (default: identity.key in the config dir)
+        #[arg(long)]
+        identity: Option<PathBuf>,
+    },
+    /// Manage the keys new backups are encrypted to
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+    /// Change the passphrase of all existing backups and the secret store
+    Rekey,
+    /// Split a new recovery key into shares; any `threshold` of them can restore
+    /// new backups without the passphrase
+    RecoveryKit {
+        /// Number of shares to create
+        #[arg(long)]
+        shares: u8,
+        /// Number of shares needed to restore
+        #[arg(long)]
+        threshold: u8,
     },
     /// List available backups
     List,
+    /// Remove backups the retention policy no longer keeps
+    Prune {
+        /// Only print what would be kept and why
+        #[arg(long)]
+        dry_run: bool,
+    },
     /// Install launchd schedule (runs every 6 hours)
     Install,
     /// Remove launchd schedule
@@ -45,14 +137,112 @@ enum Commands {
     Status,
 }
 
+/// Where `init` sets up the git remote; unset options keep their config values
+#[derive(Args)]
+struct RemoteArgs {
+    /// Existing git remote (SSH or HTTPS URL, or a local path, created as a
+    /// bare repo if missing). Without it, the repo is created through the provider.
+    #[arg(long)]
+    remote: Option<String>,
+    /// Where the remote is hosted
+    #[arg(long, value_enum)]
+    provider: Option<GitProvider>,
+    /// API base URL for Gitea or self-hosted GitLab
+    #[arg(long)]
+    api_url: Option<String>,
+    /// Name of the repo to create (default: whatsapp-backup-encrypted)
+    #[arg(long)]
+    repo_name: Option<String>,
+    /// Local checkout of the remote (default: ~/<repo name>)
+    #[arg(long)]
+    checkout: Option<PathBuf>,
+}
+
+impl RemoteArgs {
+    fn apply(self, config: &mut Config) {
+        if let Some(provider) = self.provider {
+            config.git_provider = provider;
+        }
+        if let Some(api_url) = self.api_url {
+            config.git_api_url = Some(api_url);
+        }
+        if let Some(repo_name) = self.repo_name {
+            config.git_repo_name = repo_name;
+        }
+        if let Some(checkout) = self.checkout {
+            config.git_checkout = Some(checkout);
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// Add a key slot (prompts for a passphrase unless --public-key is given)
+    Add {
+        /// Name of the slot, e.g. "family" or "offline"
+        label: String,
+        /// X25519 public key (wbpk1...) instead of a passphrase
+        #[arg(long)]
+        public_key: Option<String>,
+    },
+    /// List key slots
+    List,
+    /// Remove a key slot (existing backups are not changed)
+    Remove {
+        /// Name of the slot
+        label: String,
+    },
+}
+
 fn main() {
     let cli = Cli::parse();
 
     let result = match cli.command {
-        Commands::Init => cmd_init(),
-        Commands::Backup => cmd_backup(),
-        Commands::Restore { file, output } => cmd_restore(file, output),
+        Commands::Init {
+            public_key,
+            recovery_shares,
+            recovery_threshold,
+            remote,
+        } => cmd_init(public_key, recovery_threshold.zip(recovery_shares), remote),
+        Commands::Backup {
+            incremental,
+            dry_run,
+            force,
+        } => cmd_backup(incremental, dry_run, force),
+        Commands::Restore {
+            file,
+            from,
+            output,
+            identity,
+            shares,
+            allow_unsigned,
+        } => cmd_restore(
+            file,
+            from,
+            output,
+            restore::RestoreOptions {
+                identity_file: identity.as_deref(),
+                shares,
+                allow_unsigned,
+            },
+        ),
+        // Without a file or --latest, --all was given
+        Commands::Verify {
+            file,
+            latest,
+            level,
+            identity,
+            ..
+        } => cmd_verify(file, latest, level, identity),
+        Commands::Keys { command } => match command {
+            KeysCommand::Add { label, public_key } => cmd_keys_add(label, public_key),
+            KeysCommand::List => cmd_keys_list(),
+            KeysCommand::Remove { label } => cmd_keys_remove(label),
+        },
+        Commands::Rekey => cmd_rekey(),
+        Commands::RecoveryKit { shares, threshold } => cmd_recovery_kit(threshold, shares),
         Commands::List => cmd_list(),
+        Commands::Prune { dry_run } => cmd_prune(dry_run),
         Commands::Install => cmd_install(),
         Commands::Uninstall => cmd_uninstall(),
         Commands::Status => cmd_status(),
@@ -64,16 +254,21 @@ fn main() {
     }
 }
 
-fn cmd_init() -> Result<()> {
+fn cmd_init(public_key: bool, recovery_kit: Option<(u8, u8)>, remote: RemoteArgs) -> Result<()> {
     println!("WhatsApp Backup - Initial Setup\n");
 
     // Check if already initialized
     let config = Config::load()?;
-    if config.initialized && crypto::has_passphrase() {
+    if config.initialized && (crypto::has_passphrase() || config.uses_public_keys()) {
         println!("Already initialized. Use 'status' to check current configuration.");
         return Ok(());
     }
 
+    // Check the share counts before asking for anything
+    let recovery_kit = recovery_kit
+        .map(|(threshold, total)| recovery::create_kit(threshold, total))
+        .transpose()?;
+
     // Verify WhatsApp data exists
     println!("Checking WhatsApp Desktop installation...");
     match paths::whatsapp_data_dir() {
@@ -85,37 +280,68 @@ fn cmd_init() -> Result<()> {
     }
 
     // Get passphrase
-    println!("\nEnter a passphrase for encrypting your backups.");
-    println!("This will be stored securely in your macOS Keychain.");
-    println!("IMPORTANT: Remember this passphrase - you'll need it to restore backups!\n");
+    if public_key {
+        println!("\nEnter a passphrase to protect your private key.");
+        println!("It is NOT stored - scheduled backups only need the public key.");
+        println!("IMPORTANT: Remember this passphrase - you'll need it to restore backups!\n");
+    } else {
+        println!("\nEnter a passphrase for encrypting your backups.");
+        println!("This will be stored in: {}", secrets::configured()?.describe());
+        println!("IMPORTANT: Remember this passphrase - you'll need it to restore backups!\n");
+    }
 
-    print!("Passphrase: ");
-    io::stdout().flush()?;
-    let passphrase = rpassword_fallback()?;
+    let passphrase = keys::prompt_passphrase("Passphrase: ")?;
 
     if passphrase.len() < 8 {
         anyhow::bail!("Passphrase must be at least 8 characters");
     }
 
-    print!("Confirm passphrase: ");
-    io::stdout().flush()?;
-    let confirm = rpassword_fallback()?;
+    let confirm = keys::prompt_passphrase("Confirm passphrase: ")?;
 
     if passphrase != confirm {
         anyhow::bail!("Passphrases don't match");
     }
 
-    // Store passphrase in keychain
-    crypto::store_passphrase(&passphrase)?;
-    println!("\nPassphrase stored in Keychain");
+    let mut key_slots = Vec::new();
+    if public_key {
+        // Generate keypair; only the public key is needed for backups
+        let (secret, public) = keys::generate_keypair();
+        let identity_path = keys::identity_path()?;
+        keys::write_identity(&identity_path, &secret, &passphrase)?;
+
+        let encoded = keys::encode_public_key(&public);
+        println!("\nPublic key: {}", encoded);
+        println!("Private key (passphrase-protected): {}", identity_path.display());
+        println!("IMPORTANT: Keep a copy of the private key file off this machine!");
+
+        key_slots.push(KeySlot {
+            label: "default".to_string(),
+            kind: KeySlotKind::PublicKey {
+                public_key: encoded,
+            },
+        });
+    } else {
+        // Store passphrase in the configured secret store
+        crypto::store_passphrase(&passphrase)?;
+        println!("\nPassphrase stored in: {}", secrets::configured()?.describe());
+    }
 
-    // Create GitHub repo
-    println!("\nSetting up GitHub repository...");
-    let repo_url = git::create_github_repo()?;
+    // Set up the git remote
+    let mut config = Config::load()?;
+    let url = remote.remote.clone();
+    remote.apply(&mut config);
+    println!("\nSetting up git remote...");
+    let repo_url = git::setup_remote(&config, url.as_deref())?;
     println!("  Repository: {}", repo_url);
+    println!("  Checkout: {}", config.git_checkout_dir()?.display());
+
+    // Manifests are signed so tampering on the remotes can be detected
+    let signing_key = signing::create_signing_key(&mut config)?;
+    println!("\nManifest signing key: {}", signing::signing_key_path()?.display());
+    println!("  Public key (pinned in config): {}", signing::encode_verifying_key(&signing_key));
 
     // Save config
-    let mut config = Config::load()?;
+    config.key_slots = key_slots;
     config.set_initialized(Some(repo_url))?;
 
     // Create backup directory
@@ -129,6 +355,10 @@ fn cmd_init() -> Result<()> {
         println!("Google Drive not detected (optional)");
     }
 
+    if let Some(kit) = recovery_kit {
+        install_recovery_kit(&mut config, &kit)?;
+    }
+
     println!("\n Setup complete!");
     println!("Run 'whatsapp-backup backup' to create your first backup.");
     println!("Run 'whatsapp-backup install' to schedule automatic backups.\n");
@@ -136,51 +366,243 @@ fn cmd_init() -> Result<()> {
     Ok(())
 }
 
-fn rpassword_fallback() -> Result<String> {
-    // Simple stdin read for passphrase (terminal echo disabled would be better)
-    let mut input = String::new();
-    io::stdin().read_line(&mut input)?;
-    Ok(input.trim().to_string())
-}
+fn cmd_backup(incremental: bool, dry_run: bool, force: bool) -> Result<()> {
+    if dry_run {
+        println!("Files that would be archived:\n");
+        return backup::dry_run();
+    }
 
-fn cmd_backup() -> Result<()> {
     println!("Starting WhatsApp backup...\n");
-    let backup_path = backup::run_backup()?;
-    println!("\nBackup saved: {}", backup_path.display());
+    if let Some(backup_path) = backup::run_backup(incremental, force)? {
+        println!("\nBackup saved: {}", backup_path.display());
+    }
     Ok(())
 }
 
-fn cmd_restore(file: PathBuf, output: Option<PathBuf>) -> Result<()> {
+fn cmd_restore(
+    file: PathBuf,
+    from: Option<String>,
+    output: Option<PathBuf>,
+    options: restore::RestoreOptions,
+) -> Result<()> {
     let output_dir = output.unwrap_or_else(|| PathBuf::from("."));
 
     if !output_dir.exists() {
         std::fs::create_dir_all(&output_dir)?;
     }
 
-    restore::restore_backup(&file, &output_dir)?;
+    if from.is_none() && file.exists() {
+        restore::restore_backup(&file, &output_dir, &options)?;
+        return Ok(());
+    }
+
+    // Downloads go next to the output so large backups don't fill /tmp
+    let staging = tempfile::tempdir_in(&output_dir)?;
+    let name = file.to_string_lossy();
+    let path =
+        destination::fetch_backup(&Config::load()?, from.as_deref(), &name, staging.path())?;
+    restore::restore_backup(&path, &output_dir, &options)?;
+    Ok(())
+}
+
+fn cmd_verify(
+    file: Option<PathBuf>,
+    latest: bool,
+    level: verify::Level,
+    identity: Option<PathBuf>,
+) -> Result<()> {
+    match file {
+        Some(file) => verify::verify_file(&file, level, identity.as_deref()),
+        None => {
+            let config = Config::load()?;
+            verify::verify_destinations(&config, latest, level, identity.as_deref())
+        }
+    }
+}
+
+fn cmd_keys_add(label: String, public_key: Option<String>) -> Result<()> {
+    let mut config = Config::load()?;
+    if !config.initialized {
+        anyhow::bail!("Not initialized. Run 'whatsapp-backup init' first.");
+    }
+
+    let kind = match public_key {
+        Some(public_key) => {
+            keys::parse_public_key(&public_key)?;
+            KeySlotKind::PublicKey { public_key }
+        }
+        None => {
+            println!("Enter the passphrase for key slot '{}'.", label);
+            println!("It is kept in the secret store so scheduled backups can include it.\n");
+            let passphrase = keys::prompt_passphrase("Passphrase: ")?;
+            if passphrase.len() < 8 {
+                anyhow::bail!("Passphrase must be at least 8 characters");
+            }
+            if passphrase != keys::prompt_passphrase("Confirm passphrase: ")? {
+                anyhow::bail!("Passphrases don't match");
+            }
+            let account = format!("slot-{}", label);
+            crypto::store_passphrase_for(&account, &passphrase)?;
+            KeySlotKind::Passphrase { account }
+        }
+    };
+
+    config.add_key_slot(KeySlot {
+        label: label.clone(),
+        kind,
+    })?;
+    config.save()?;
+
+    println!("Added key slot '{}'", label);
+    println!("New backups can be restored with any of {} keys.", config.key_slots.len());
+    Ok(())
+}
+
+fn cmd_keys_list() -> Result<()> {
+    let config = Config::load()?;
+
+    println!("Key slots for new backups:\n");
+    for slot in config.key_slots() {
+        println!("  {:<12} {}", slot.label, slot.describe());
+    }
+    Ok(())
+}
+
+fn cmd_keys_remove(label: String) -> Result<()> {
+    let mut config = Config::load()?;
+    let removed = config.remove_key_slot(&label)?;
+    config.save()?;
+
+    // The default passphrase also unlocks older backups and the identity file
+    if let KeySlotKind::Passphrase { account } = &removed.kind {
+        if account != secrets::DEFAULT_ACCOUNT {
+            crypto::delete_passphrase_for(account)?;
+        }
+    }
+
+    println!("Removed key slot '{}'", label);
+    println!("Existing backups can still be decrypted with it; new backups can't.");
+    Ok(())
+}
+
+fn cmd_rekey() -> Result<()> {
+    let config = Config::load()?;
+    if !config.initialized {
+        anyhow::bail!("Not initialized. Run 'whatsapp-backup init' first.");
+    }
+
+    // Public-key setups don't keep the passphrase in the secret store
+    let old = match crypto::get_passphrase() {
+        Ok(passphrase) => passphrase,
+        Err(_) => keys::prompt_passphrase("Current passphrase: ")?,
+    };
+
+    println!("Enter the new passphrase.");
+    println!("IMPORTANT: Remember this passphrase - you'll need it to restore backups!\n");
+    let new = keys::prompt_passphrase("New passphrase: ")?;
+    if new.len() < 8 {
+        anyhow::bail!("Passphrase must be at least 8 characters");
+    }
+    if new != keys::prompt_passphrase("Confirm passphrase: ")? {
+        anyhow::bail!("Passphrases don't match");
+    }
+    if new == old {
+        anyhow::bail!("The new passphrase is the same as the current one");
+    }
+
+    rekey::run_rekey(&old, &new)?;
+    println!("\nRekey complete!");
+    Ok(())
+}
+
+fn cmd_recovery_kit(threshold: u8, total: u8) -> Result<()> {
+    let mut config = Config::load()?;
+    if !config.initialized {
+        anyhow::bail!("Not initialized. Run 'whatsapp-backup init' first.");
+    }
+
+    let kit = recovery::create_kit(threshold, total)?;
+    install_recovery_kit(&mut config, &kit)
+}
+
+/// Adds the kit's public key as the recovery key slot (replacing an older
+/// kit), re-encrypts the incremental repository key to it, adds it to existing
+/// backups and prints the shares. The config is saved only once the existing
+/// data opens with the kit.
+fn install_recovery_kit(config: &mut Config, kit: &recovery::RecoveryKit) -> Result<()> {
+    let identities = keys::identities(config, None)?;
+    if config.key_slots().iter().any(|s| s.label == recovery::RECOVERY_SLOT) {
+        config.remove_key_slot(recovery::RECOVERY_SLOT)?;
+    }
+    config.add_key_slot(KeySlot {
+        label: recovery::RECOVERY_SLOT.to_string(),
+        kind: KeySlotKind::PublicKey {
+            public_key: keys::encode_public_key(&kit.public_key),
+        },
+    })?;
+
+    let repo_dir = paths::dedup_repo_dir()?;
+    if dedup::Repository::exists(&repo_dir) {
+        let recipients = keys::recipients(config)?;
+        let signing_key = signing::load_signing_key(config)?;
+        dedup::Repository::rewrap_keys(&repo_dir, &identities, &recipients, signing_key.as_ref())?;
+        destination::sync_repository_mirrors(config, &repo_dir, &identities, "Add recovery key")?;
+    }
+    let recipient = crypto::Recipient::PublicKey(kit.public_key);
+    let count = rekey::add_recipient(config, &identities, &recipient, "Add recovery key")?;
+    println!("Added the recovery key to {} existing backups", count);
+    config.save()?;
+
+    println!(
+        "\nRecovery kit: any {} of these {} shares restore backups without the passphrase.",
+        kit.threshold,
+        kit.shares.len()
+    );
+    println!("Give each share to a different person or place; print them or encode as QR codes.\n");
+    for (i, share) in kit.shares.iter().enumerate() {
+        println!("  Share {}: {}", i + 1, recovery::format_share(share));
+    }
+    println!("\nRestore with: whatsapp-backup restore <file> --shares");
     Ok(())
 }
 
 fn cmd_list() -> Result<()> {
-    let backups = restore::list_backups()?;
+    let config = Config::load()?;
+    let mut found = false;
+
+    for configured in destination::configured(&config)? {
+        let destination = configured.destination.as_ref();
+        let backups = restore::list_backups(destination)?;
+        if backups.is_empty() {
+            continue;
+        }
+        found = true;
 
-    if backups.is_empty() {
+        println!("{} ({}):\n", configured.name, destination.location());
+        for (name, size) in backups {
+            println!("  {} ({:.2} MB)", name, size as f64 / 1_000_000.0);
+        }
+        println!();
+    }
+
+    if !found {
         println!("No backups found.");
         println!("Run 'whatsapp-backup backup' to create one.");
         return Ok(());
     }
 
-    println!("Available backups:\n");
-    for (name, size, _modified) in backups {
-        println!("  {} ({:.2} MB)", name, size as f64 / 1_000_000.0);
-    }
-
-    let backup_dir = paths::backup_dir()?;
-    println!("\nBackup directory: {}", backup_dir.display());
+    println!("Restore one with: whatsapp-backup restore <name> [--from <destination>]");
 
     Ok(())
 }
 
+fn cmd_prune(dry_run: bool) -> Result<()> {
+    if dry_run {
+        println!("Dry run - nothing will be removed.\n");
+    }
+    backup::run_prune(dry_run)
+}
+
 fn cmd_install() -> Result<()> {
     let config = Config::load()?;
     if !config.initialized {
@@ -279,16 +701,34 @@ fn cmd_status() -> Result<()> {
         return Ok(());
     }
 
-    // Keychain status
+    // Encryption key status
+    let key_slots = config.key_slots();
+    let labels: Vec<&str> = key_slots.iter().map(|s| s.label.as_str()).collect();
+    println!("Key slots: {}", labels.join(", "));
+
+    if config.uses_public_keys() {
+        let identity_path = keys::identity_path()?;
+        if identity_path.exists() {
+            println!("Private key: {}", identity_path.display());
+        } else {
+            println!("Private key: Not on this machine (restore with --identity)");
+        }
+    }
     if crypto::has_passphrase() {
-        println!("Encryption key: Stored in Keychain");
-    } else {
+        println!("Encryption key: Stored in {}", secrets::configured()?.describe());
+    } else if !config.uses_public_keys() {
         println!("Encryption key: Missing (run 'whatsapp-backup init')");
     }
 
-    // GitHub repo
-    if let Some(repo) = &config.github_repo {
-        println!("GitHub repo: {}", repo);
+    match &config.signing_public_key {
+        Some(key) => println!("Signing key: {}", key),
+        None => println!("Signing key: None (created on the next backup)"),
+    }
+
+    // Git remote
+    if let Some(repo) = &config.git_remote {
+        println!("Git remote: {} ({})", repo, config.git_provider.describe());
+        println!("Git checkout: {}", config.git_checkout_dir()?.display());
     }
 
     // Last backup
@@ -304,11 +744,16 @@ fn cmd_status() -> Result<()> {
         Err(_) => println!("WhatsApp data: Not found"),
     }
 
-    // Google Drive
-    if let Some(drive) = paths::google_drive_dir() {
-        println!("Google Drive: {}", drive.display());
-    } else {
-        println!("Google Drive: Not detected");
+    // Destinations
+    println!("Destinations:");
+    let available = destination::configured(&config)?;
+    for entry in &config.destinations {
+        match available.iter().find(|c| c.name == entry.name()) {
+            Some(configured) => {
+                println!("  {}: {}", entry.name(), configured.destination.location())
+            }
+            None => println!("  {}: Not set up on this machine", entry.name()),
+        }
     }
 
     // Schedule status
@@ -331,7 +776,7 @@ fn cmd_status() -> Result<()> {
     }
 
     // Backup count
-    let backups = restore::list_backups()?;
+    let backups = restore::list_backups(&destination::Folder::new(paths::backup_dir()?))?;
     println!("\nLocal backups: {}", backups.len());
 
     Ok(())
@@ -1,3 +1,4 @@
+use crate::config::Config;
 use anyhow::{Context, Result};
 use std::path::PathBuf;
 
@@ -36,10 +37,14 @@ pub fn backup_dir() -> Result<PathBuf> {
     Ok(path)
 }
 
-pub fn github_repo_dir() -> Result<PathBuf> {
-    let home = dirs::home_dir().context("Failed to detect home directory")?;
-    let path = home.join("whatsapp-backup-encrypted");
-    Ok(path)
+/// Deduplicated incremental repository (chunks + snapshots)
+pub fn dedup_repo_dir() -> Result<PathBuf> {
+    Ok(backup_dir()?.join("repo"))
+}
+
+/// Local checkout of the git remote (`git_checkout` in config)
+pub fn git_checkout_dir() -> Result<PathBuf> {
+    Config::load()?.git_checkout_dir()
 }
 
 pub fn google_drive_dir() -> Option<PathBuf> {
@@ -0,0 +1,409 @@
+use crate::config::RcloneConfig;
+use crate::destination::{Destination, StoredFile};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Exit codes documented by rclone
+const EXIT_DIRECTORY_NOT_FOUND: i32 = 3;
+const EXIT_FILE_NOT_FOUND: i32 = 4;
+const EXIT_TEMPORARY: i32 = 5;
+const EXIT_FATAL: i32 = 7;
+
+/// Failures of the rclone destination
+#[derive(Debug, thiserror::Error)]
+pub enum RcloneError {
+    #[error("{0} not found - install rclone or set `binary` in the destination")]
+    NotInstalled(String),
+    #[error("Failed to run rclone: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Remote {remote} is not in the rclone config - set it up with `rclone config`")]
+    UnknownRemote { remote: String },
+    #[error("rclone {command}: not found: {message}")]
+    NotFound { command: String, message: String },
+    #[error("rclone {command} failed with a temporary error, the next run retries: {message}")]
+    Temporary { command: String, message: String },
+    #[error("rclone {command} failed: {message}")]
+    Failed { command: String, message: String },
+    #[error("rclone check found {name} differs from the uploaded copy: {message}")]
+    Mismatch { name: String, message: String },
+}
+
+/// Entry of `rclone lsjson` output
+#[derive(Deserialize)]
+struct ListEntry {
+    #[serde(rename = "Path")]
+    path: String,
+    #[serde(rename = "Size")]
+    size: i64,
+    #[serde(rename = "IsDir")]
+    is_dir: bool,
+}
+
+/// Folder on any cloud rclone supports, through the `rclone` binary and a
+/// remote from the user's rclone config
+pub struct Rclone {
+    binary: PathBuf,
+    config: Option<PathBuf>,
+    remote: String,
+    /// Folder on the remote without a trailing slash
+    path: String,
+}
+
+impl Rclone {
+    pub fn new(config: &RcloneConfig) -> Self {
+        Self {
+            binary: config
+                .binary
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("rclone")),
+            config: config.config.clone(),
+            remote: config.remote.trim_end_matches(':').to_string(),
+            path: config.path.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// `remote:path/name` for rclone arguments
+    fn target(&self, name: &str) -> String {
+        match (self.path.is_empty(), name.is_empty()) {
+            (true, _) => format!("{}:{}", self.remote, name),
+            (false, true) => format!("{}:{}", self.remote, self.path),
+            (false, false) => format!("{}:{}/{}", self.remote, self.path, name),
+        }
+    }
+
+    /// Runs `rclone <args>` and returns stdout
+    fn run(&self, args: &[OsString]) -> Result<String, RcloneError> {
+        let mut command = Command::new(&self.binary);
+        if let Some(config) = &self.config {
+            command.arg("--config").arg(config);
+        }
+        let output = command.args(args).output().map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                RcloneError::NotInstalled(self.binary.display().to_string())
+            } else {
+                RcloneError::Io(e)
+            }
+        })?;
+        if output.status.success() {
+            return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+        }
+        let command = args
+            .first()
+            .map(|a| a.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Err(translate_error(
+            &command,
+            &self.remote,
+            output.status.code(),
+            &String::from_utf8_lossy(&output.stderr),
+        ))
+    }
+
+    /// `rclone lsjson` of `target`, or `None` if it doesn't exist
+    fn lsjson(&self, target: &str, flags: &[&str]) -> Result<Option<String>> {
+        let mut args: Vec<OsString> = vec!["lsjson".into()];
+        args.extend(flags.iter().map(OsString::from));
+        args.push(target.into());
+        match self.run(&args) {
+            Ok(output) => Ok(Some(output)),
+            Err(RcloneError::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Compares `file` with the stored `name` by size and, where the remote
+    /// supports one, a hash. rclone check compares files of the same name, so
+    /// `file` is linked under the stored name in a staging folder first.
+    fn check(&self, file: &Path, name: &str) -> Result<()> {
+        let (parent, file_name) = name.rsplit_once('/').unwrap_or(("", name));
+        let staging = tempfile::tempdir()?;
+        let file = std::path::absolute(file)
+            .with_context(|| format!("Failed to resolve {}", file.display()))?;
+        std::os::unix::fs::symlink(&file, staging.path().join(file_name))
+            .with_context(|| format!("Failed to stage {} for rclone check", file.display()))?;
+        let mut list = tempfile::NamedTempFile::new()?;
+        writeln!(list, "{}", file_name)?;
+
+        let args: Vec<OsString> = vec![
+            "check".into(),
+            staging.path().into(),
+            self.target(parent).into(),
+            "--one-way".into(),
+            "--copy-links".into(),
+            "--files-from-raw".into(),
+            list.path().into(),
+        ];
+        match self.run(&args) {
+            Ok(_) => Ok(()),
+            Err(RcloneError::Failed { message, .. }) => Err(RcloneError::Mismatch {
+                name: name.to_string(),
+                message,
+            }
+            .into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Destination for Rclone {
+    fn location(&self) -> String {
+        self.target("")
+    }
+
+    fn put(&self, file: &Path, name: &str) -> Result<()> {
+        self.run(&["copyto".into(), file.into(), self.target(name).into()])?;
+        self.check(file, name)
+    }
+
+    fn get(&self, name: &str, file: &Path) -> Result<()> {
+        self.run(&["copyto".into(), self.target(name).into(), file.into()])?;
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StoredFile>> {
+        // Only list the folder the prefix points into
+        let dir = prefix.rsplit_once('/').map_or("", |(dir, _)| dir);
+        let Some(output) = self.lsjson(&self.target(dir), &["-R", "--files-only"])? else {
+            return Ok(Vec::new());
+        };
+        let mut files: Vec<StoredFile> = parse_listing(&output)?
+            .into_iter()
+            .filter(|entry| !entry.is_dir)
+            // Skip hidden files, like the other destinations
+            .filter(|entry| !entry.path.split('/').any(|c| c.starts_with('.')))
+            .map(|entry| StoredFile {
+                name: if dir.is_empty() {
+                    entry.path
+                } else {
+                    format!("{}/{}", dir, entry.path)
+                },
+                size: entry.size.max(0) as u64,
+            })
+            .filter(|file| file.name.starts_with(prefix))
+            .collect();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        match self.run(&["deletefile".into(), self.target(name).into()]) {
+            // Already gone, as with the other destinations
+            Ok(_) | Err(RcloneError::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn stat(&self, name: &str) -> Result<Option<StoredFile>> {
+        let Some(output) = self.lsjson(&self.target(name), &["--stat"])? else {
+            return Ok(None);
+        };
+        let entry: ListEntry = serde_json::from_str(&output)
+            .with_context(|| format!("Invalid rclone lsjson output: {}", output))?;
+        Ok((!entry.is_dir).then(|| StoredFile {
+            name: name.to_string(),
+            size: entry.size.max(0) as u64,
+        }))
+    }
+}
+
+fn parse_listing(output: &str) -> Result<Vec<ListEntry>> {
+    serde_json::from_str(output)
+        .with_context(|| format!("Invalid rclone lsjson output: {}", output))
+}
+
+/// Turns a failed rclone run into an error from its exit code and the last
+/// error it logged
+fn translate_error(command: &str, remote: &str, code: Option<i32>, stderr: &str) -> RcloneError {
+    let lines: Vec<&str> = stderr
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+    let message = lines
+        .iter()
+        .rev()
+        .find(|l| l.contains("ERROR") || l.contains("CRITICAL") || l.contains("Failed"))
+        .or(lines.last())
+        .map_or_else(
+            || format!("exit code {}", code.unwrap_or(-1)),
+            |l| l.to_string(),
+        );
+    let command = command.to_string();
+
+    if stderr.contains("didn't find section in config file") {
+        return RcloneError::UnknownRemote {
+            remote: remote.to_string(),
+        };
+    }
+    match code {
+        Some(EXIT_DIRECTORY_NOT_FOUND | EXIT_FILE_NOT_FOUND) => {
+            RcloneError::NotFound { command, message }
+        }
+        Some(EXIT_TEMPORARY) => RcloneError::Temporary { command, message },
+        Some(EXIT_FATAL) => RcloneError::Failed {
+            command,
+            message: format!("fatal error: {}", message),
+        },
+        _ => RcloneError::Failed { command, message },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_targets_and_listing() {
+        let rclone = Rclone::new(&RcloneConfig {
+            remote: "gdrive:".to_string(),
+            path: "Backups/WhatsApp/".to_string(),
+            binary: None,
+            config: None,
+        });
+        assert_eq!(rclone.location(), "gdrive:Backups/WhatsApp");
+        assert_eq!(
+            rclone.target("repo/key"),
+            "gdrive:Backups/WhatsApp/repo/key"
+        );
+        let root = Rclone::new(&RcloneConfig {
+            remote: "b2".to_string(),
+            path: String::new(),
+            binary: None,
+            config: None,
+        });
+        assert_eq!(root.target("a.enc"), "b2:a.enc");
+
+        let output = r#"[
+            {"Path":"a.enc","Name":"a.enc","Size":3,"MimeType":"application/octet-stream","ModTime":"2026-01-18T05:59:34Z","IsDir":false},
+            {"Path":"repo","Name":"repo","Size":-1,"IsDir":true}
+        ]"#;
+        let entries = parse_listing(output).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].path.as_str(), entries[0].size), ("a.enc", 3));
+        assert!(entries[1].is_dir);
+    }
+
+    #[test]
+    fn test_errors_are_translated() {
+        let stderr =
+            "2026/01/18 05:59:34 ERROR : a.enc: error reading source: directory not found\n\
+                      2026/01/18 05:59:34 NOTICE: Failed to lsjson: directory not found\n";
+        match translate_error("lsjson", "gdrive", Some(3), stderr) {
+            RcloneError::NotFound { command, message } => {
+                assert_eq!(command, "lsjson");
+                assert!(message.contains("Failed to lsjson"));
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        assert!(matches!(
+            translate_error("copyto", "gdrive", Some(5), "ERROR : rate limited\n"),
+            RcloneError::Temporary { .. }
+        ));
+        assert!(matches!(
+            translate_error(
+                "copyto",
+                "gdrive",
+                Some(1),
+                "CRITICAL: Failed to create file system for \"gdrive:x\": didn't find section in config file"
+            ),
+            RcloneError::UnknownRemote { .. }
+        ));
+        let error = translate_error("deletefile", "gdrive", Some(7), "");
+        assert_eq!(
+            error.to_string(),
+            "rclone deletefile failed: fatal error: exit code 7"
+        );
+    }
+
+    #[test]
+    fn test_fake_binary() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for rclone: missing files exit with code 4, and check
+        // passes only if the staged copy carries the stored name
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("rclone");
+        std::fs::write(
+            &binary,
+            "#!/bin/sh\n\
+             case \"$1\" in\n\
+             copyto) exit 0 ;;\n\
+             check) test -f \"$2/b.enc\" ;;\n\
+             deletefile) echo 'ERROR : b.enc: object not found' >&2; exit 4 ;;\n\
+             *) exit 1 ;;\n\
+             esac\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let rclone = Rclone::new(&RcloneConfig {
+            remote: "gdrive".to_string(),
+            path: String::new(),
+            binary: Some(binary),
+            config: None,
+        });
+
+        let file = dir.path().join("upload.tmp");
+        std::fs::write(&file, b"abc").unwrap();
+        rclone.put(&file, "repo/b.enc").unwrap();
+        assert!(rclone.put(&file, "c.enc").is_err());
+        rclone.delete("b.enc").unwrap();
+    }
+
+    /// Runs against rclone's `local` backend where `rclone` is on `PATH`
+    #[test]
+    fn test_rclone_local_backend() {
+        if Command::new("rclone").arg("version").output().is_err() {
+            eprintln!("Skipping test_rclone_local_backend: rclone is not on PATH");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let rclone = Rclone::new(&RcloneConfig {
+            remote: ":local".to_string(),
+            path: dir.path().join("remote").display().to_string(),
+            binary: None,
+            config: None,
+        });
+
+        let source = dir.path().join("source");
+        std::fs::create_dir_all(source.join("chunks")).unwrap();
+        std::fs::write(source.join("a.enc"), b"abc").unwrap();
+        std::fs::write(source.join("chunks/abcd"), b"chunk").unwrap();
+        std::fs::write(source.join("upload.tmp"), b"other").unwrap();
+        rclone.put(&source.join("a.enc"), "a.enc").unwrap();
+        // The local name doesn't have to match the stored one
+        rclone.put(&source.join("upload.tmp"), "b.enc").unwrap();
+        rclone
+            .put(&source.join("chunks/abcd"), "repo/chunks/abcd")
+            .unwrap();
+
+        let names: Vec<String> = rclone
+            .list("")
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(names, vec!["a.enc", "b.enc", "repo/chunks/abcd"]);
+        assert_eq!(rclone.list("repo/").unwrap().len(), 1);
+        assert!(rclone.list("missing/").unwrap().is_empty());
+        assert_eq!(rclone.stat("a.enc").unwrap().unwrap().size, 3);
+        assert!(rclone.stat("missing").unwrap().is_none());
+
+        // A copy changed behind rclone's back fails the check
+        std::fs::write(dir.path().join("remote/a.enc"), b"abd").unwrap();
+        let error = rclone.check(&source.join("a.enc"), "a.enc").unwrap_err();
+        assert!(error.downcast_ref::<RcloneError>().is_some());
+
+        let download = dir.path().join("download");
+        rclone.get("repo/chunks/abcd", &download).unwrap();
+        assert_eq!(std::fs::read(&download).unwrap(), b"chunk");
+
+        rclone.delete("a.enc").unwrap();
+        assert!(rclone.stat("a.enc").unwrap().is_none());
+        rclone.delete("a.enc").unwrap();
+        rclone.delete("missing/a.enc").unwrap();
+    }
+}
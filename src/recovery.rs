@@ -0,0 +1,228 @@
+use crate::keys;
+use anyhow::{Context, Result};
+use data_encoding::BASE32_NOPAD;
+use sha2::{Digest, Sha256};
+use sharks::{Share, Sharks};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Text prefix of an encoded recovery share
+const SHARE_PREFIX: &str = "WBRS1-";
+/// Label of the key slot holding the recovery public key
+pub const RECOVERY_SLOT: &str = "recovery";
+const KIT_ID_SIZE: usize = 4;
+const CHECKSUM_SIZE: usize = 4;
+/// Characters per group when printing a share
+const GROUP_SIZE: usize = 6;
+
+/// Recovery kit: a recovery keypair whose secret key is split into shares.
+/// Backups are encrypted to the public key through a key slot, so any
+/// `threshold` shares can restore them without the passphrase.
+pub struct RecoveryKit {
+    pub public_key: PublicKey,
+    pub threshold: u8,
+    /// Encoded shares, one per holder
+    pub shares: Vec<String>,
+}
+
+/// Short identifier tying shares to the recovery key they belong to
+fn kit_id(public_key: &PublicKey) -> [u8; KIT_ID_SIZE] {
+    let digest = Sha256::digest(public_key.as_bytes());
+    digest[..KIT_ID_SIZE].try_into().unwrap()
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let digest = Sha256::digest(payload);
+    digest[..CHECKSUM_SIZE].try_into().unwrap()
+}
+
+/// Generates a recovery keypair and splits its secret key into `total`
+/// shares, any `threshold` of which rebuild it
+pub fn create_kit(threshold: u8, total: u8) -> Result<RecoveryKit> {
+    if threshold < 2 || threshold > total {
+        anyhow::bail!(
+            "Invalid recovery kit {}-of-{}: need at least 2 shares and no more than the total",
+            threshold,
+            total
+        );
+    }
+
+    let (secret, public_key) = keys::generate_keypair();
+    let id = kit_id(&public_key);
+    let shares = Sharks(threshold)
+        .dealer(&secret.to_bytes())
+        .take(total as usize)
+        .map(|share| encode_share(&id, threshold, &share))
+        .collect();
+
+    Ok(RecoveryKit {
+        public_key,
+        threshold,
+        shares,
+    })
+}
+
+/// Encodes a share as `WBRS1-` followed by base32 of
+/// [kit id (4)][threshold (1)][share x (1)][share y (32)][checksum (4)],
+/// which only uses characters of the QR alphanumeric mode
+fn encode_share(id: &[u8; KIT_ID_SIZE], threshold: u8, share: &Share) -> String {
+    let mut payload = id.to_vec();
+    payload.push(threshold);
+    payload.extend(Vec::from(share));
+    payload.extend_from_slice(&checksum(&payload));
+    format!("{}{}", SHARE_PREFIX, BASE32_NOPAD.encode(&payload))
+}
+
+/// Splits an encoded share into groups for printing
+pub fn format_share(share: &str) -> String {
+    let body = share.strip_prefix(SHARE_PREFIX).unwrap_or(share);
+    let groups: Vec<&str> = body
+        .as_bytes()
+        .chunks(GROUP_SIZE)
+        .map(|g| std::str::from_utf8(g).unwrap())
+        .collect();
+    format!("{}{}", SHARE_PREFIX, groups.join("-"))
+}
+
+/// A decoded share
+#[derive(Clone)]
+pub struct ParsedShare {
+    kit_id: [u8; KIT_ID_SIZE],
+    pub threshold: u8,
+    share: Share,
+}
+
+impl ParsedShare {
+    /// Share number, for messages
+    pub fn number(&self) -> u8 {
+        self.share.x.0
+    }
+}
+
+/// Parses a share as printed by `format_share`, tolerating spaces, dashes
+/// and lower case
+pub fn parse_share(text: &str) -> Result<ParsedShare> {
+    let normalized: String = text
+        .trim()
+        .to_ascii_uppercase()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let body = normalized
+        .strip_prefix(SHARE_PREFIX)
+        .with_context(|| format!("Recovery shares start with '{}'", SHARE_PREFIX))?
+        .replace('-', "");
+    let bytes = BASE32_NOPAD
+        .decode(body.as_bytes())
+        .context("Invalid recovery share - check for typos")?;
+
+    if bytes.len() < KIT_ID_SIZE + 1 + 2 + CHECKSUM_SIZE {
+        anyhow::bail!("Recovery share is too short");
+    }
+    let (payload, sum) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+    if checksum(payload) != sum {
+        anyhow::bail!("Recovery share checksum mismatch - check for typos");
+    }
+
+    let share = Share::try_from(&payload[KIT_ID_SIZE + 1..]).map_err(anyhow::Error::msg)?;
+    Ok(ParsedShare {
+        kit_id: payload[..KIT_ID_SIZE].try_into().unwrap(),
+        threshold: payload[KIT_ID_SIZE],
+        share,
+    })
+}
+
+/// Rebuilds the recovery secret key from at least `threshold` shares
+pub fn combine_shares(shares: &[ParsedShare]) -> Result<StaticSecret> {
+    let first = shares.first().context("No recovery shares given")?;
+    if shares.iter().any(|s| s.kit_id != first.kit_id) {
+        anyhow::bail!("Recovery shares come from different recovery kits");
+    }
+
+    let parts: Vec<Share> = shares.iter().map(|s| s.share.clone()).collect();
+    let bytes = Sharks(first.threshold)
+        .recover(&parts)
+        .map_err(|e| anyhow::anyhow!("Failed to combine recovery shares: {}", e))?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Recovered key has the wrong length"))?;
+
+    let secret = StaticSecret::from(bytes);
+    if kit_id(&PublicKey::from(&secret)) != first.kit_id {
+        anyhow::bail!("Recovered key doesn't match the recovery kit - a share is wrong");
+    }
+    Ok(secret)
+}
+
+/// Prompts for shares until enough have been entered to rebuild the key
+pub fn prompt_shares() -> Result<StaticSecret> {
+    println!("Enter recovery shares, one per line.");
+    let mut shares: Vec<ParsedShare> = Vec::new();
+
+    loop {
+        let needed = shares.first().map_or(1, |s| s.threshold as usize);
+        if shares.len() >= needed {
+            break;
+        }
+        let line = keys::prompt_passphrase(&format!("Share {}/{}: ", shares.len() + 1, needed))?;
+        if line.is_empty() {
+            anyhow::bail!("Not enough recovery shares");
+        }
+        match parse_share(&line) {
+            Ok(share) if shares.iter().any(|s| s.number() == share.number()) => {
+                println!("  Share #{} was already entered", share.number());
+            }
+            Ok(share) => shares.push(share),
+            Err(e) => println!("  {}", e),
+        }
+    }
+
+    combine_shares(&shares)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_any_threshold_shares_recover_key() {
+        let kit = create_kit(2, 3).unwrap();
+        assert_eq!(kit.shares.len(), 3);
+
+        let parsed: Vec<ParsedShare> = kit
+            .shares
+            .iter()
+            .map(|s| parse_share(&format_share(s).to_lowercase()).unwrap())
+            .collect();
+        for pair in [[0, 1], [0, 2], [1, 2]] {
+            let subset = [parsed[pair[0]].clone(), parsed[pair[1]].clone()];
+            let secret = combine_shares(&subset).unwrap();
+            assert_eq!(PublicKey::from(&secret).as_bytes(), kit.public_key.as_bytes());
+        }
+
+        assert!(combine_shares(&parsed[..1]).is_err());
+    }
+
+    #[test]
+    fn test_share_checksum_and_kit_mismatch() {
+        let kit = create_kit(2, 2).unwrap();
+        let other = create_kit(2, 2).unwrap();
+
+        // Change one character of the share body
+        let mut typo = kit.shares[0].clone().into_bytes();
+        let last = typo.len() - 3;
+        typo[last] = if typo[last] == b'A' { b'B' } else { b'A' };
+        assert!(parse_share(&String::from_utf8(typo).unwrap()).is_err());
+
+        let mixed = [
+            parse_share(&kit.shares[0]).unwrap(),
+            parse_share(&other.shares[1]).unwrap(),
+        ];
+        assert!(combine_shares(&mixed).is_err());
+    }
+
+    #[test]
+    fn test_invalid_threshold() {
+        assert!(create_kit(1, 3).is_err());
+        assert!(create_kit(4, 3).is_err());
+    }
+}
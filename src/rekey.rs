@@ -0,0 +1,500 @@
+use crate::backup::{self, BackupFiles, ChunkWriter};
+use crate::config::{Config, GitHistory};
+use crate::crypto::{self, Header, Identity, KeySlot, KeySource, Recipient};
+use crate::destination::{self, Destination, Folder};
+use crate::restore::{self, StoredBackup};
+use crate::{dedup, git, keys, paths, retention, signing};
+use anyhow::{Context, Result};
+use ed25519_dalek::SigningKey;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Scratch directory for rekeyed files, next to the originals so the final
+/// rename stays on the same filesystem
+const REKEY_DIR: &str = ".rekey";
+
+/// What a rekey changes in each backup
+enum Change<'a> {
+    /// Replace the slot `old` opens with one for `new`
+    Passphrase { old: &'a str, new: &'a str },
+    /// Add a slot for `recipient` to v4 backups that `identities` open
+    AddRecipient {
+        identities: &'a [Identity],
+        recipient: &'a Recipient,
+    },
+}
+
+/// Writes a copy of `backup` (stored in `dir`) into `out_dir` with `change`
+/// applied, and verifies it by decrypting it in full. v4 backups only get their
+/// header rewritten; older formats are re-encrypted as v4 on a passphrase
+/// change and left alone when adding a recipient. The new manifest is signed
+/// with `signing_key`.
+/// Returns `None` if the change doesn't apply to the backup.
+fn rekey_backup(
+    dir: &Path,
+    backup: &StoredBackup,
+    change: &Change,
+    out_dir: &Path,
+    signing_key: Option<&SigningKey>,
+) -> Result<Option<BackupFiles>> {
+    let data: Vec<PathBuf> = backup.data.iter().map(|name| dir.join(name)).collect();
+    let mut reader = restore::open_data(&data)?;
+    let header = crypto::read_header(&mut reader)?;
+    let chunk_size = backup
+        .manifest
+        .as_ref()
+        .map_or(backup::CHUNK_SIZE, |m| m.chunk_size);
+    let mut chunks = ChunkWriter::new(out_dir, &backup.timestamp, chunk_size)
+        .with_signing_key(signing_key.cloned())
+        .with_databases(backup.manifest.as_ref().map_or_else(Vec::new, |m| m.databases.clone()));
+
+    let new_identity;
+    let verify_with: &[Identity] = match change {
+        Change::Passphrase { old, new } => {
+            let old = Identity::Passphrase(old.to_string());
+            match header {
+                Header::Stream(header) if matches!(header.keys, KeySource::Slots(_)) => {
+                    let Some(rekeyed) = header.rekey(&old, &Recipient::passphrase(new))? else {
+                        if let KeySource::Slots(slots) = &header.keys {
+                            if slots.iter().any(|s| matches!(s, KeySlot::Passphrase { .. })) {
+                                anyhow::bail!("No key slot opens with the current passphrase");
+                            }
+                        }
+                        return Ok(None);
+                    };
+                    chunks.write_all(&rekeyed.to_bytes())?;
+                    io::copy(&mut reader, &mut chunks)?;
+                }
+                header => {
+                    let mut decrypted = crypto::decrypt_reader(reader, header, &[old])?;
+                    let mut encryptor = crypto::StreamEncryptor::new(chunks, new)?;
+                    io::copy(&mut decrypted, &mut encryptor)?;
+                    chunks = encryptor.finish()?;
+                }
+            }
+            new_identity = [Identity::Passphrase(new.to_string())];
+            &new_identity
+        }
+        Change::AddRecipient {
+            identities,
+            recipient,
+        } => {
+            let Header::Stream(header) = header else {
+                return Ok(None);
+            };
+            if !matches!(header.keys, KeySource::Slots(_)) {
+                return Ok(None);
+            }
+            let rekeyed = header
+                .add_slot(identities, recipient)?
+                .context("No key slot opens with the available keys")?;
+            chunks.write_all(&rekeyed.to_bytes())?;
+            io::copy(&mut reader, &mut chunks)?;
+            identities
+        }
+    };
+    let files = chunks.finish()?;
+
+    let mut reader = restore::open_data(&files.data)?;
+    let header = crypto::read_header(&mut reader)?;
+    io::copy(
+        &mut crypto::decrypt_reader(reader, header, verify_with)?,
+        &mut io::sink(),
+    )
+    .context("Verification of the rekeyed backup failed")?;
+
+    Ok(Some(files))
+}
+
+/// Moves the rekeyed files over the originals and removes leftover old files
+fn replace_backup(dir: &Path, backup: &StoredBackup, rekeyed: &BackupFiles) -> Result<()> {
+    let mut kept = Vec::new();
+    for src in rekeyed.all() {
+        let target = dir.join(src.file_name().context("Invalid backup filename")?);
+        std::fs::rename(&src, &target)
+            .with_context(|| format!("Failed to replace {}", target.display()))?;
+        kept.push(target);
+    }
+    for old in own_files(backup) {
+        let old = dir.join(old);
+        if !kept.contains(&old) {
+            std::fs::remove_file(old).ok();
+        }
+    }
+    Ok(())
+}
+
+/// Uploads the rekeyed files over the originals at `destination` and removes
+/// leftover old files
+fn upload_backup(
+    destination: &dyn Destination,
+    backup: &StoredBackup,
+    rekeyed: &BackupFiles,
+) -> Result<()> {
+    let mut kept = Vec::new();
+    for src in rekeyed.all() {
+        let name = src
+            .file_name()
+            .and_then(|n| n.to_str())
+            .context("Invalid backup filename")?;
+        destination
+            .put(&src, name)
+            .with_context(|| format!("Failed to replace {}", name))?;
+        kept.push(name.to_string());
+    }
+    for old in own_files(backup) {
+        if !kept.iter().any(|name| name == old) {
+            destination.delete(old)?;
+        }
+    }
+    Ok(())
+}
+
+/// Files of `backup` named after its own timestamp; a manifest can list
+/// anything, and only these are replaced or removed
+fn own_files(backup: &StoredBackup) -> impl Iterator<Item = &String> {
+    backup
+        .files
+        .iter()
+        .filter(|name| retention::is_backup_file(name, &backup.timestamp))
+}
+
+/// Where full backups are rekeyed: the local backup directory and every
+/// destination on this machine in place, other destinations by downloading
+/// their backups and uploading the rekeyed copies
+struct Locations<'a> {
+    dirs: Vec<PathBuf>,
+    remote: Vec<destination::Configured<'a>>,
+}
+
+fn backup_locations(config: &Config) -> Result<Locations<'_>> {
+    let mut locations = Locations {
+        dirs: vec![paths::backup_dir()?],
+        remote: Vec::new(),
+    };
+    for configured in destination::configured(config)? {
+        match configured.destination.local_dir() {
+            Some(dir) if locations.dirs.iter().any(|l| l == dir) => {}
+            Some(dir) => locations.dirs.push(dir.to_path_buf()),
+            None => locations.remote.push(configured),
+        }
+    }
+    Ok(locations)
+}
+
+/// Where a rekeyed backup goes back to
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    /// Directory on this machine, rekeyed in place
+    Dir(&'a Path),
+    /// Other destination, downloaded and uploaded again
+    Remote(&'a dyn Destination),
+}
+
+/// Rekeyed copy of a backup, ready to replace the original
+struct Prepared<'a> {
+    target: Target<'a>,
+    backup: StoredBackup,
+    files: BackupFiles,
+}
+
+/// Rekeys the backups found at `target`, read from `dir` (where remote
+/// backups are downloaded to), writing the copies to `dir/.rekey`
+fn prepare_in<'a>(
+    dir: &Path,
+    target: Target<'a>,
+    found: Vec<StoredBackup>,
+    change: &Change,
+    signing_key: Option<&SigningKey>,
+    prepared: &mut Vec<Prepared<'a>>,
+) -> Result<()> {
+    let out_dir = dir.join(REKEY_DIR);
+    std::fs::create_dir_all(&out_dir)?;
+    for backup in found {
+        let name = backup.name().to_string();
+        if let Target::Remote(destination) = target {
+            for file in &backup.files {
+                if !retention::is_backup_file(file, &backup.timestamp) {
+                    anyhow::bail!("{} lists a file of another backup: {}", name, file);
+                }
+                destination.get(file, &dir.join(file))?;
+            }
+        }
+        match rekey_backup(dir, &backup, change, &out_dir, signing_key)
+            .with_context(|| format!("Failed to rekey {}", name))?
+        {
+            Some(files) => {
+                println!("  {} verified", name);
+                prepared.push(Prepared {
+                    target,
+                    backup,
+                    files,
+                });
+            }
+            None => match change {
+                Change::Passphrase { .. } => {
+                    println!("  {} skipped (not encrypted to the passphrase)", name)
+                }
+                Change::AddRecipient { .. } => {
+                    println!("  {} skipped (older format without key slots)", name)
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Rekeys every backup in `locations`: all rekeyed copies are written and
+/// verified before any original is replaced. Backups at other destinations
+/// are downloaded to a temporary directory first.
+fn rekey_locations(
+    locations: &Locations,
+    change: &Change,
+    signing_key: Option<&SigningKey>,
+) -> Result<usize> {
+    let mut prepared = Vec::new();
+    for dir in &locations.dirs {
+        println!("Rekeying backups in {}", dir.display());
+        let found = restore::find_backups(&Folder::new(dir))?;
+        prepare_in(dir, Target::Dir(dir), found, change, signing_key, &mut prepared)?;
+    }
+
+    // Kept until the rekeyed copies are uploaded
+    let mut staging = Vec::new();
+    for configured in &locations.remote {
+        let destination = configured.destination.as_ref();
+        println!("Rekeying backups at {} ({})", configured.name, destination.location());
+        let dir = tempfile::tempdir()?;
+        let found = restore::find_backups(destination)?;
+        let target = Target::Remote(destination);
+        prepare_in(dir.path(), target, found, change, signing_key, &mut prepared)?;
+        staging.push(dir);
+    }
+
+    for Prepared {
+        target,
+        backup,
+        files,
+    } in &prepared
+    {
+        match target {
+            Target::Dir(dir) => replace_backup(dir, backup, files)?,
+            Target::Remote(destination) => upload_backup(*destination, backup, files)?,
+        }
+    }
+    Ok(prepared.len())
+}
+
+/// Runs `rekey_locations` and removes the scratch directories either way
+fn rekey_in(
+    locations: &Locations,
+    change: &Change,
+    signing_key: Option<&SigningKey>,
+) -> Result<usize> {
+    let result = rekey_locations(locations, change, signing_key);
+    for dir in &locations.dirs {
+        std::fs::remove_dir_all(dir.join(REKEY_DIR)).ok();
+    }
+    result
+}
+
+/// Adds a key slot for `recipient` to every known v4 backup that `identities`
+/// open, without re-encrypting the data
+pub fn add_recipient(
+    config: &Config,
+    identities: &[Identity],
+    recipient: &Recipient,
+    message: &str,
+) -> Result<usize> {
+    let locations = backup_locations(config)?;
+    let signing_key = signing::load_signing_key(config)?;
+    let change = Change::AddRecipient {
+        identities,
+        recipient,
+    };
+    let count = rekey_in(&locations, &change, signing_key.as_ref())?;
+
+    for configured in destination::configured(config)? {
+        configured.destination.commit(message)?;
+    }
+    Ok(count)
+}
+
+/// Changes the passphrase of every known backup, the incremental repository
+/// and the identity file, then updates the secret store
+pub fn run_rekey(old: &str, new: &str) -> Result<()> {
+    let config = Config::load()?;
+    let locations = backup_locations(&config)?;
+    let signing_key = signing::load_signing_key(&config)?;
+    let change = Change::Passphrase { old, new };
+    let count = rekey_in(&locations, &change, signing_key.as_ref())?;
+    println!("Rekeyed {} backups", count);
+
+    let repo_dir = paths::dedup_repo_dir()?;
+    if dedup::Repository::exists(&repo_dir) {
+        dedup::Repository::change_passphrase(&repo_dir, old, new, signing_key.as_ref())?;
+        // Mirrors still hold the key encrypted to the old passphrase
+        let identities = [
+            Identity::Passphrase(new.to_string()),
+            Identity::Passphrase(old.to_string()),
+        ];
+        let message = "Rekey incremental repository";
+        destination::sync_repository_mirrors(&config, &repo_dir, &identities, message)?;
+        println!("Rekeyed incremental repository");
+    }
+
+    let identity_path = keys::identity_path()?;
+    if identity_path.exists() && !keys::is_plain_identity(&identity_path)? {
+        keys::change_identity_passphrase(&identity_path, old, new)?;
+        println!("Rekeyed identity: {}", identity_path.display());
+    }
+
+    for configured in destination::configured(&config)? {
+        configured.destination.commit("Rekey backups")?;
+    }
+    if git::is_repo_initialized() {
+        if config.git_history == GitHistory::Squash {
+            git::bound_history(&config)?;
+        } else {
+            println!("  Note: older commits in the repository history still use the old passphrase");
+        }
+    }
+
+    if crypto::has_passphrase() {
+        match crypto::store_passphrase(new) {
+            Ok(()) => println!("Stored passphrase updated"),
+            // e.g. an environment variable, which can only be changed by the user
+            Err(e) => println!("Update the stored passphrase yourself: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use crate::retention::RetentionPolicy;
+    use std::io::{BufReader, Read};
+
+    fn encrypt_to(path: &Path, recipients: &[Recipient], data: &[u8]) {
+        let file = File::create(path).unwrap();
+        let mut encryptor = crypto::StreamEncryptor::for_recipients(file, recipients).unwrap();
+        encryptor.write_all(data).unwrap();
+        encryptor.finish().unwrap();
+    }
+
+    fn decrypt(path: &Path, identity: Identity) -> Result<Vec<u8>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let header = crypto::read_header(&mut reader)?;
+        let mut data = Vec::new();
+        crypto::decrypt_reader(reader, header, &[identity])?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn local(dir: &Path) -> Locations<'static> {
+        Locations {
+            dirs: vec![dir.to_path_buf()],
+            remote: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_rekey_rewraps_passphrase_slot() {
+        let dir = tempfile::tempdir().unwrap();
+        let (secret, public) = keys::generate_keypair();
+        let recipients = [
+            Recipient::passphrase("old-passphrase"),
+            Recipient::PublicKey(public),
+        ];
+        encrypt_to(&dir.path().join("2026-01-18_00-00-00.enc"), &recipients, b"chats");
+        encrypt_to(
+            &dir.path().join("2026-01-18_06-00-00.enc"),
+            &[Recipient::PublicKey(public)],
+            b"more chats",
+        );
+
+        let locations = local(dir.path());
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let change = Change::Passphrase {
+            old: "old-passphrase",
+            new: "new-passphrase",
+        };
+        let count = rekey_locations(&locations, &change, Some(&signing_key)).unwrap();
+        assert_eq!(count, 1);
+
+        let rekeyed = dir.path().join("2026-01-18_00-00-00.enc");
+        let manifest = restore::read_manifest(&dir.path().join("2026-01-18_00-00-00.enc.manifest"));
+        signing::verify_manifest(&manifest.unwrap(), &signing_key.verifying_key()).unwrap();
+        let new = Identity::Passphrase("new-passphrase".to_string());
+        assert_eq!(decrypt(&rekeyed, new).unwrap(), b"chats");
+        let old = Identity::Passphrase("old-passphrase".to_string());
+        assert!(decrypt(&rekeyed, old).is_err());
+        assert_eq!(decrypt(&rekeyed, Identity::SecretKey(secret)).unwrap(), b"chats");
+    }
+
+    #[test]
+    fn test_rekey_wrong_passphrase_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("2026-01-18_00-00-00.enc");
+        encrypt_to(&path, &[Recipient::passphrase("old-passphrase")], b"chats");
+        let original = std::fs::read(&path).unwrap();
+
+        let locations = local(dir.path());
+        let change = Change::Passphrase {
+            old: "wrong-passphrase",
+            new: "new-passphrase",
+        };
+        assert!(rekey_locations(&locations, &change, None).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn test_add_recipient_to_existing_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("2026-01-18_00-00-00.enc");
+        encrypt_to(&path, &[Recipient::passphrase("passphrase")], b"chats");
+
+        let (secret, public) = keys::generate_keypair();
+        let identities = [Identity::Passphrase("passphrase".to_string())];
+        let change = Change::AddRecipient {
+            identities: &identities,
+            recipient: &Recipient::PublicKey(public),
+        };
+        let locations = local(dir.path());
+        assert_eq!(rekey_locations(&locations, &change, None).unwrap(), 1);
+
+        assert_eq!(decrypt(&path, Identity::SecretKey(secret)).unwrap(), b"chats");
+        let passphrase = Identity::Passphrase("passphrase".to_string());
+        assert_eq!(decrypt(&path, passphrase).unwrap(), b"chats");
+    }
+
+    #[test]
+    fn test_rekey_remote_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("2026-01-18_00-00-00.enc");
+        encrypt_to(&path, &[Recipient::passphrase("old-passphrase")], b"chats");
+        std::fs::write(dir.path().join("notes.txt"), b"not a backup").unwrap();
+
+        let retention = RetentionPolicy::default();
+        let locations = Locations {
+            dirs: Vec::new(),
+            remote: vec![destination::Configured {
+                name: "remote",
+                retention: &retention,
+                destination: Box::new(Folder::new(dir.path())),
+            }],
+        };
+        let change = Change::Passphrase {
+            old: "old-passphrase",
+            new: "new-passphrase",
+        };
+        assert_eq!(rekey_locations(&locations, &change, None).unwrap(), 1);
+
+        let new = Identity::Passphrase("new-passphrase".to_string());
+        assert_eq!(decrypt(&path, new).unwrap(), b"chats");
+        assert!(!dir.path().join(REKEY_DIR).exists());
+        assert!(dir.path().join("notes.txt").exists());
+    }
+}
@@ -1,40 +1,131 @@
-use crate::{backup::Manifest, crypto, paths};
+use crate::backup::{self, Manifest};
+use crate::destination::{self, Destination};
+use crate::{config::Config, crypto, dedup, keys, signing};
 use anyhow::{Context, Result};
 use flate2::read::GzDecoder;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::Path;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use tar::Archive;
 
 /// Reads manifest file
-fn read_manifest(manifest_path: &Path) -> Result<Manifest> {
+pub fn read_manifest(manifest_path: &Path) -> Result<Manifest> {
     let file = File::open(manifest_path)?;
     let manifest: Manifest = serde_json::from_reader(file)?;
     Ok(manifest)
 }
 
-/// Reassembles chunks into original encrypted file, verifying SHA256
-fn reassemble_chunks(manifest_path: &Path, output_path: &Path) -> Result<()> {
-    let manifest = read_manifest(manifest_path)?;
-    let parent = manifest_path.parent().context("No parent directory")?;
+/// How a backup is opened and checked by `restore_backup`
+#[derive(Default)]
+pub struct RestoreOptions<'a> {
+    /// Private key file for public-key backups
+    pub identity_file: Option<&'a Path>,
+    /// Rebuild the recovery key from recovery kit shares
+    pub shares: bool,
+    /// Restore even if the manifest signature is missing or invalid
+    pub allow_unsigned: bool,
+}
+
+/// Finds the manifest of a backup: the file itself, or `<name>.manifest`
+/// next to a single-file backup. Backups from before manifests were written
+/// for single files have none.
+pub fn find_manifest(backup_path: &Path) -> Option<PathBuf> {
+    let name = backup_path.file_name()?.to_str()?;
+    if name.ends_with(".manifest") {
+        return Some(backup_path.to_path_buf());
+    }
+    let manifest = backup_path.with_file_name(format!("{}.manifest", name));
+    manifest.exists().then_some(manifest)
+}
+
+/// Checks the manifest signature against the public key pinned in config,
+/// printing the outcome
+pub fn check_signature(manifest: Option<&Manifest>) -> Result<()> {
+    let Some(key) = signing::pinned_key(&Config::load()?)? else {
+        println!("Warning: no signing key pinned in config, manifest signature not checked");
+        return Ok(());
+    };
+
+    match manifest {
+        Some(manifest) => signing::verify_manifest(manifest, &key)?,
+        None => anyhow::bail!("Backup has no manifest, so it is not signed"),
+    }
+    println!("Signature: valid");
+    Ok(())
+}
+
+/// Checks the signatures of a snapshot and its repository key against the
+/// public key pinned in config, printing the outcome
+pub fn check_snapshot_signature(snapshot_path: &Path) -> Result<()> {
+    let Some(key) = signing::pinned_key(&Config::load()?)? else {
+        println!("Warning: no signing key pinned in config, snapshot signature not checked");
+        return Ok(());
+    };
+    dedup::verify_signatures(snapshot_path, &key)?;
+    println!("Signature: valid");
+    Ok(())
+}
+
+/// Fails on a signature check that failed, unless `--allow-unsigned` was given
+fn enforce_signature(checked: Result<()>, options: &RestoreOptions) -> Result<()> {
+    match checked {
+        Ok(()) => Ok(()),
+        Err(e) if options.allow_unsigned => {
+            println!("Warning: {}", e);
+            Ok(())
+        }
+        Err(e) => anyhow::bail!(
+            "{} - refusing to restore (use --allow-unsigned to override)",
+            e
+        ),
+    }
+}
 
-    let mut output = BufWriter::new(File::create(output_path)?);
+/// Reads the data files of a backup as one stream
+pub fn open_data(data: &[PathBuf]) -> Result<BufReader<Box<dyn Read>>> {
+    let mut reader: Box<dyn Read> = Box::new(io::empty());
+    for path in data {
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        reader = Box::new(reader.chain(file));
+    }
+    Ok(BufReader::new(reader))
+}
+
+/// Copies the chunks listed in `manifest` from `dir` into `output`,
+/// verifying each chunk's size and the SHA256
+pub fn copy_chunks<W: Write>(manifest: &Manifest, dir: &Path, mut output: W) -> Result<()> {
     let mut hasher = Sha256::new();
 
     for chunk_info in &manifest.chunks {
-        let chunk_path = parent.join(&chunk_info.name);
+        let chunk_path = dir.join(&chunk_info.name);
         if !chunk_path.exists() {
             anyhow::bail!("Missing chunk: {}", chunk_info.name);
         }
 
         let mut chunk_file = BufReader::new(File::open(&chunk_path)?);
-        let mut buffer = Vec::new();
-        chunk_file.read_to_end(&mut buffer)?;
+        let mut buffer = [0u8; 65536];
+        let mut size = 0u64;
 
-        hasher.update(&buffer);
-        output.write_all(&buffer)?;
+        loop {
+            let bytes_read = chunk_file.read(&mut buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..bytes_read]);
+            output.write_all(&buffer[..bytes_read])?;
+            size += bytes_read as u64;
+        }
+
+        if size != chunk_info.size {
+            anyhow::bail!(
+                "Chunk {} is {} bytes, manifest records {}",
+                chunk_info.name,
+                size,
+                chunk_info.size
+            );
+        }
     }
     output.flush()?;
 
@@ -51,8 +142,48 @@ fn reassemble_chunks(manifest_path: &Path, output_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Decrypts, decompresses and extracts an encrypted backup in a single streaming pass,
+/// choosing the decoder from the file header
+fn decrypt_and_extract(
+    encrypted_file: &Path,
+    expected_format: Option<u8>,
+    output_dir: &Path,
+    identity_file: Option<&Path>,
+    shares: bool,
+) -> Result<()> {
+    let file = File::open(encrypted_file).context("Failed to open encrypted backup")?;
+    let mut reader = BufReader::new(file);
+    let header = crypto::read_header(&mut reader)?;
+    println!("Format: {}", header.describe());
+
+    if let Some(expected) = expected_format {
+        if expected != header.format_version() {
+            anyhow::bail!(
+                "Format mismatch: manifest records v{}, but the backup is v{}",
+                expected,
+                header.format_version()
+            );
+        }
+    }
+
+    let identities = keys::restore_identities(identity_file, shares)?;
+
+    println!("Decrypting and extracting...");
+    let decrypted = crypto::decrypt_reader(reader, header, &identities)?;
+    let decoder = GzDecoder::new(decrypted);
+    let mut archive = Archive::new(decoder);
+
+    archive
+        .unpack(output_dir)
+        .context("Failed to extract backup")
+}
+
 /// Restores a backup to a specified directory
-pub fn restore_backup(backup_path: &Path, output_dir: &Path) -> Result<()> {
+pub fn restore_backup(
+    backup_path: &Path,
+    output_dir: &Path,
+    options: &RestoreOptions,
+) -> Result<()> {
     if !backup_path.exists() {
         anyhow::bail!("Backup file not found: {}", backup_path.display());
     }
@@ -63,45 +194,76 @@ pub fn restore_backup(backup_path: &Path, output_dir: &Path) -> Result<()> {
         .and_then(|n| n.to_str())
         .unwrap_or("");
 
-    let encrypted_file = if file_name.ends_with(".manifest") {
-        // Chunked backup - reassemble first
-        println!("Detected chunked backup, reassembling...");
-        let manifest = read_manifest(backup_path)?;
-        let reassembled_path = output_dir.join(format!("{}.enc", manifest.timestamp));
-        reassemble_chunks(backup_path, &reassembled_path)?;
-        println!("  Reassembled {} chunks", manifest.chunks.len());
-        reassembled_path
-    } else {
-        backup_path.to_path_buf()
-    };
+    if file_name.ends_with(&format!(".{}", dedup::SNAPSHOT_EXTENSION)) {
+        restore_snapshot(backup_path, output_dir, options)?;
+        print_restore_note(output_dir);
+        return Ok(());
+    }
 
-    // Get passphrase
-    let passphrase = crypto::get_passphrase()?;
+    // The signature is checked before any chunk named in the manifest is read
+    let manifest_path = find_manifest(backup_path);
+    let manifest = manifest_path.as_deref().map(read_manifest).transpose()?;
+    enforce_signature(check_signature(manifest.as_ref()), options)?;
 
-    // Create temp file for decrypted archive
-    let temp_archive = output_dir.join("temp_restore.tar.gz");
+    let (encrypted_file, expected_format, reassembled) = match (&manifest, &manifest_path) {
+        (Some(manifest), Some(manifest_path)) => {
+            let parent = manifest_path.parent().context("No parent directory")?;
+            if manifest.chunks.len() == 1 {
+                copy_chunks(manifest, parent, io::sink())?;
+                let path = parent.join(&manifest.chunks[0].name);
+                (path, Some(manifest.format_version), false)
+            } else {
+                println!("Detected chunked backup, reassembling...");
+                let reassembled_path = output_dir.join(format!("{}.enc", manifest.timestamp));
+                let output = BufWriter::new(File::create(&reassembled_path)?);
+                if let Err(e) = copy_chunks(manifest, parent, output) {
+                    std::fs::remove_file(&reassembled_path).ok();
+                    return Err(e);
+                }
+                println!("  Reassembled {} chunks", manifest.chunks.len());
+                (reassembled_path, Some(manifest.format_version), true)
+            }
+        }
+        _ => (backup_path.to_path_buf(), None, false),
+    };
 
-    println!("Decrypting backup...");
-    crypto::decrypt_file(&encrypted_file, &temp_archive, &passphrase)?;
+    let result = decrypt_and_extract(
+        &encrypted_file,
+        expected_format,
+        output_dir,
+        options.identity_file,
+        options.shares,
+    );
 
     // Clean up reassembled file if we created one
-    if encrypted_file != backup_path {
+    if reassembled {
         std::fs::remove_file(&encrypted_file).ok();
     }
 
-    // Extract archive
-    println!("Extracting...");
-    let file = File::open(&temp_archive).context("Failed to open decrypted archive")?;
-    let decoder = GzDecoder::new(file);
-    let mut archive = Archive::new(decoder);
+    result?;
 
+    print_restore_note(output_dir);
+
+    Ok(())
+}
+
+/// Reassembles a deduplicated snapshot from its repository and extracts it
+fn restore_snapshot(snapshot_path: &Path, output_dir: &Path, options: &RestoreOptions) -> Result<()> {
+    println!("Detected incremental snapshot, reassembling from chunks...");
+    // Checked before the key is used or any chunk is read
+    enforce_signature(check_snapshot_signature(snapshot_path), options)?;
+    let root = dedup::repository_root(snapshot_path)?;
+    let identities = keys::restore_identities(options.identity_file, options.shares)?;
+    let repo = dedup::Repository::open(&root, &identities)?;
+
+    println!("Extracting...");
+    let mut archive = Archive::new(repo.snapshot_reader(snapshot_path)?);
     archive
         .unpack(output_dir)
-        .context("Failed to extract backup")?;
-
-    // Remove temp archive
-    std::fs::remove_file(&temp_archive)?;
+        .context("Failed to extract snapshot")
+}
 
+fn print_restore_note(output_dir: &Path) {
     println!("Restored to: {}", output_dir.display());
     println!(
         "\nNote: The data is extracted to {}/whatsapp-data/",
@@ -112,64 +274,102 @@ pub fn restore_backup(backup_path: &Path, output_dir: &Path) -> Result<()> {
         "  cp -r {}/whatsapp-data/* ~/Library/Group\\ Containers/group.net.whatsapp.WhatsApp.shared/",
         output_dir.display()
     );
+}
 
-    Ok(())
+/// A backup found at a destination
+pub struct StoredBackup {
+    pub timestamp: String,
+    /// Manifest, missing for single-file backups made before manifests
+    /// were written for them
+    pub manifest: Option<Manifest>,
+    /// Names of the encrypted data files in stream order
+    pub data: Vec<String>,
+    /// Names of every file belonging to the backup, the manifest last
+    pub files: Vec<String>,
+    /// Total size of the data files
+    pub size: u64,
 }
 
-/// Lists available backups (grouping chunks as single entries)
-pub fn list_backups() -> Result<Vec<(String, u64, std::time::SystemTime)>> {
-    let backup_dir = paths::backup_dir()?;
+impl StoredBackup {
+    /// Name of the manifest, or the data file of a backup without one
+    pub fn name(&self) -> &str {
+        self.files.last().map_or(&self.timestamp, String::as_str)
+    }
+}
+
+/// Finds single-file and chunked backups at the top level of `destination`.
+/// A `.enc` file with a manifest next to it is found through the manifest.
+pub fn find_backups(destination: &dyn Destination) -> Result<Vec<StoredBackup>> {
+    let stored: HashMap<String, u64> = destination
+        .list("")?
+        .into_iter()
+        .filter(|f| !f.name.contains('/'))
+        .map(|f| (f.name, f.size))
+        .collect();
     let mut backups = Vec::new();
-    let mut seen_timestamps: HashMap<String, bool> = HashMap::new();
-
-    for entry in std::fs::read_dir(&backup_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        let name = path
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("");
-
-        // Handle manifest files (chunked backups)
-        if name.ends_with(".manifest") {
-            if let Ok(manifest) = read_manifest(&path) {
-                let timestamp = &manifest.timestamp;
-                if !seen_timestamps.contains_key(timestamp) {
-                    seen_timestamps.insert(timestamp.clone(), true);
-                    if let Ok(metadata) = entry.metadata() {
-                        if let Ok(modified) = metadata.modified() {
-                            // Show manifest name but with total original size
-                            backups.push((
-                                name.to_string(),
-                                manifest.original_size,
-                                modified,
-                            ));
-                        }
-                    }
-                }
+
+    for (name, &size) in &stored {
+        if let Some(timestamp) = name.strip_suffix(".enc.manifest") {
+            let manifest: Manifest = serde_json::from_slice(&destination::read(destination, name)?)
+                .with_context(|| format!("Failed to parse {}", name))?;
+            let data: Vec<String> = manifest.chunks.iter().map(|c| c.name.clone()).collect();
+            if let Some(missing) = data.iter().find(|n| !stored.contains_key(*n)) {
+                println!("  Skipping {}: missing chunk {}", name, missing);
+                continue;
             }
-        }
-        // Handle regular .enc files (non-chunked)
-        else if name.ends_with(".enc") && !name.contains(".enc.") {
-            // Extract timestamp from filename (e.g., "2026-01-17_19-41-14.enc")
-            let timestamp = name.trim_end_matches(".enc");
-            if !seen_timestamps.contains_key(timestamp) {
-                seen_timestamps.insert(timestamp.to_string(), true);
-                if let Ok(metadata) = entry.metadata() {
-                    if let Ok(modified) = metadata.modified() {
-                        backups.push((
-                            name.to_string(),
-                            metadata.len(),
-                            modified,
-                        ));
-                    }
-                }
+            let size = data.iter().map(|n| stored[n]).sum();
+            let mut files = data.clone();
+            files.push(name.clone());
+            backups.push(StoredBackup {
+                timestamp: timestamp.to_string(),
+                manifest: Some(manifest),
+                data,
+                files,
+                size,
+            });
+        } else if let Some(timestamp) = name.strip_suffix(".enc") {
+            if stored.contains_key(&format!("{}.manifest", name)) {
+                continue;
             }
+            backups.push(StoredBackup {
+                timestamp: timestamp.to_string(),
+                manifest: None,
+                data: vec![name.clone()],
+                files: vec![name.clone()],
+                size,
+            });
         }
     }
 
-    // Sort by modification time, newest first
-    backups.sort_by(|a, b| b.2.cmp(&a.2));
-
+    backups.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
     Ok(backups)
 }
+
+/// Lists the backups and incremental snapshots at `destination`, newest
+/// first, as (name, size). Snapshots are reported with the size of the
+/// reassembled archive.
+pub fn list_backups(destination: &dyn Destination) -> Result<Vec<(String, u64)>> {
+    let mut backups: Vec<(String, String, u64)> = find_backups(destination)?
+        .into_iter()
+        .map(|b| (b.timestamp.clone(), b.name().to_string(), b.size))
+        .collect();
+
+    let snapshots = format!("{}/{}/", backup::INCREMENTAL_DIR, dedup::SNAPSHOTS_DIR);
+    for file in destination.list(&snapshots)? {
+        if !file.name.ends_with(&format!(".{}", dedup::SNAPSHOT_EXTENSION)) {
+            continue;
+        }
+        let data = destination::read(destination, &file.name)?;
+        if let Ok(snapshot) = serde_json::from_slice::<dedup::Snapshot>(&data) {
+            backups.push((snapshot.timestamp, file.name, snapshot.total_size));
+        }
+    }
+
+    // Sort by timestamp, newest first
+    backups.sort_by(|a, b| b.0.cmp(&a.0));
+
+    Ok(backups
+        .into_iter()
+        .map(|(_, name, size)| (name, size))
+        .collect())
+}
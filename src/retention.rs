@@ -0,0 +1,426 @@
+use crate::config::DestinationKind;
+use crate::destination::Destination;
+use crate::restore::{self, StoredBackup};
+use anyhow::Result;
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Format of backup timestamps in filenames and manifests
+pub const TIMESTAMP_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
+/// Grandfather-father-son retention: how many backups to keep per period.
+/// Each rule keeps the newest backup of each of its most recent periods, and a
+/// backup is removed only if no rule keeps it. The newest backup is always kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Most recent backups, regardless of time
+    pub keep_last: u32,
+    pub keep_hourly: u32,
+    pub keep_daily: u32,
+    /// ISO weeks
+    pub keep_weekly: u32,
+    pub keep_monthly: u32,
+    pub keep_yearly: u32,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            keep_last: 4,
+            keep_hourly: 0,
+            keep_daily: 7,
+            keep_weekly: 4,
+            keep_monthly: 12,
+            keep_yearly: 0,
+        }
+    }
+}
+
+/// Policies by destination type; a destination without one (or its own
+/// `retention`) uses the default `retention` policy
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DestinationRetention {
+    /// Also applies to incremental snapshots
+    pub local: Option<RetentionPolicy>,
+    #[serde(alias = "github")]
+    pub git: Option<RetentionPolicy>,
+    pub google_drive: Option<RetentionPolicy>,
+}
+
+impl DestinationRetention {
+    pub fn get(&self, kind: &DestinationKind) -> Option<&RetentionPolicy> {
+        match kind {
+            DestinationKind::Local => self.local.as_ref(),
+            DestinationKind::Git => self.git.as_ref(),
+            DestinationKind::GoogleDrive => self.google_drive.as_ref(),
+            // Set through the entry's own `retention` instead
+            DestinationKind::SyncedFolder { .. }
+            | DestinationKind::S3(_)
+            | DestinationKind::Sftp(_)
+            | DestinationKind::Webdav(_)
+            | DestinationKind::Rclone(_) => None,
+        }
+    }
+}
+
+/// Key identifying the period (hour, day, ...) a backup falls in
+type Period = fn(&NaiveDateTime) -> String;
+
+pub fn parse_timestamp(timestamp: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()
+}
+
+/// Why a backup is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Newest,
+    Last,
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+    /// The newest backup that passed verification
+    NewestVerified,
+    /// The timestamp couldn't be parsed, so the backup is never removed
+    UnknownTime,
+}
+
+impl Reason {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Reason::Newest => "newest",
+            Reason::Last => "last",
+            Reason::Hourly => "hourly",
+            Reason::Daily => "daily",
+            Reason::Weekly => "weekly",
+            Reason::Monthly => "monthly",
+            Reason::Yearly => "yearly",
+            Reason::NewestVerified => "newest verified",
+            Reason::UnknownTime => "unknown timestamp",
+        }
+    }
+}
+
+/// Describes the reasons a backup is kept, or "remove"
+pub fn describe(reasons: &[Reason]) -> String {
+    if reasons.is_empty() {
+        return "remove".to_string();
+    }
+    let names: Vec<&str> = reasons.iter().map(Reason::describe).collect();
+    format!("keep ({})", names.join(", "))
+}
+
+impl RetentionPolicy {
+    /// Policy replacing the old `retention_days` setting, which kept every
+    /// backup of the last `days` days: one per day, and as many recent backups
+    /// as are made in that time every `interval_hours`. Never keeps fewer than
+    /// the defaults.
+    pub fn from_days(days: u32, interval_hours: u32) -> Self {
+        let defaults = Self::default();
+        let per_day = (24 / interval_hours.max(1)).max(1);
+        Self {
+            keep_last: defaults.keep_last.max(days.saturating_mul(per_day)),
+            keep_daily: defaults.keep_daily.max(days),
+            ..defaults
+        }
+    }
+
+    /// Decides which backups to keep. Returns the reasons each backup at the
+    /// same index in `timestamps` is kept; an empty list means remove it.
+    pub fn select(&self, timestamps: &[&str]) -> Vec<Vec<Reason>> {
+        let times: Vec<Option<NaiveDateTime>> =
+            timestamps.iter().map(|t| parse_timestamp(t)).collect();
+        let mut reasons = vec![Vec::new(); timestamps.len()];
+
+        // Newest first
+        let mut order: Vec<usize> = (0..timestamps.len())
+            .filter(|&i| times[i].is_some())
+            .collect();
+        order.sort_by(|&a, &b| times[b].cmp(&times[a]));
+
+        for (i, time) in times.iter().enumerate() {
+            if time.is_none() {
+                reasons[i].push(Reason::UnknownTime);
+            }
+        }
+        if let Some(&newest) = order.first() {
+            reasons[newest].push(Reason::Newest);
+        }
+        for &i in order.iter().take(self.keep_last as usize) {
+            reasons[i].push(Reason::Last);
+        }
+
+        let rules: [(u32, Reason, Period); 5] = [
+            (self.keep_hourly, Reason::Hourly, |t| {
+                format!("{}-{}", t.date(), t.hour())
+            }),
+            (self.keep_daily, Reason::Daily, |t| t.date().to_string()),
+            (self.keep_weekly, Reason::Weekly, |t| {
+                let week = t.iso_week();
+                format!("{}-{}", week.year(), week.week())
+            }),
+            (self.keep_monthly, Reason::Monthly, |t| {
+                format!("{}-{}", t.year(), t.month())
+            }),
+            (self.keep_yearly, Reason::Yearly, |t| t.year().to_string()),
+        ];
+        for (count, reason, period) in rules {
+            let mut periods = HashSet::new();
+            for &i in &order {
+                if periods.len() >= count as usize {
+                    break;
+                }
+                // The first (newest) backup seen in a period represents it
+                if periods.insert(period(times[i].as_ref().unwrap())) {
+                    reasons[i].push(reason);
+                }
+            }
+        }
+
+        reasons
+    }
+}
+
+/// Also keeps the newest backup that passes `verify`, trying from the newest
+/// down, so pruning can't leave only backups that fail to restore
+pub fn keep_newest_verified<F>(timestamps: &[&str], reasons: &mut [Vec<Reason>], mut verify: F)
+where
+    F: FnMut(usize) -> Result<()>,
+{
+    let mut order: Vec<usize> = (0..timestamps.len())
+        .filter(|&i| parse_timestamp(timestamps[i]).is_some())
+        .collect();
+    order.sort_by(|&a, &b| parse_timestamp(timestamps[b]).cmp(&parse_timestamp(timestamps[a])));
+
+    for i in order {
+        match verify(i) {
+            Ok(()) => {
+                reasons[i].push(Reason::NewestVerified);
+                return;
+            }
+            Err(e) => println!("  {}: failed verification: {:#}", timestamps[i], e),
+        }
+    }
+    println!("  Warning: no backup passed verification");
+}
+
+/// Applies `policy` to the full backups at `destination`, printing the
+/// decision for each and every removal. The newest backup that passes
+/// `verify` is always kept. Ages come from the filenames, and only files
+/// named after the backup's own timestamp are removed, since a manifest's
+/// contents aren't authenticated here. Returns the number of backups removed
+/// (or that would be).
+pub fn prune<F>(
+    destination: &dyn Destination,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+    mut verify: F,
+) -> Result<usize>
+where
+    F: FnMut(&StoredBackup) -> Result<()>,
+{
+    let backups = restore::find_backups(destination)?;
+    let timestamps: Vec<&str> = backups.iter().map(|b| b.timestamp.as_str()).collect();
+
+    let mut reasons = policy.select(&timestamps);
+    keep_newest_verified(&timestamps, &mut reasons, |i| verify(&backups[i]));
+
+    let mut removed = 0;
+    for (backup, reasons) in backups.iter().zip(reasons) {
+        if !reasons.is_empty() {
+            if dry_run {
+                println!("  {}: {}", backup.name(), describe(&reasons));
+            }
+            continue;
+        }
+        if dry_run {
+            println!("  {}: remove", backup.name());
+        } else {
+            for file in &backup.files {
+                if is_backup_file(file, &backup.timestamp) {
+                    destination.delete(file)?;
+                } else {
+                    println!("  Not removing {}: not part of {}", file, backup.name());
+                }
+            }
+            println!("  Removed old backup: {}", backup.name());
+        }
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Whether `name` is `<timestamp>.enc` or one of its chunks or manifest
+pub fn is_backup_file(name: &str, timestamp: &str) -> bool {
+    name.strip_prefix(timestamp)
+        .and_then(|rest| rest.strip_prefix(".enc"))
+        .is_some_and(|rest| {
+            (rest.is_empty() || rest.starts_with('.')) && !rest.contains(['/', '\\'])
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::destination::Folder;
+
+    #[test]
+    fn test_select_gfs() {
+        let policy = RetentionPolicy {
+            keep_last: 2,
+            keep_hourly: 0,
+            keep_daily: 3,
+            keep_weekly: 2,
+            keep_monthly: 2,
+            keep_yearly: 0,
+        };
+        let timestamps = [
+            "2026-01-18_18-00-00",
+            "2026-01-18_12-00-00",
+            "2026-01-18_06-00-00",
+            "2026-01-17_18-00-00",
+            "2026-01-16_18-00-00",
+            "2026-01-15_18-00-00",
+            "2026-01-04_18-00-00",
+            "2025-12-20_18-00-00",
+            "2025-11-20_18-00-00",
+            "not-a-timestamp",
+        ];
+        let reasons = policy.select(&timestamps);
+
+        use Reason::*;
+        assert_eq!(reasons[0], vec![Newest, Last, Daily, Weekly, Monthly]);
+        assert_eq!(reasons[1], vec![Last]);
+        assert!(reasons[2].is_empty());
+        assert_eq!(reasons[3], vec![Daily]);
+        assert_eq!(reasons[4], vec![Daily]);
+        assert!(reasons[5].is_empty());
+        // 2026-01-04 is a Sunday, the end of the previous ISO week
+        assert_eq!(reasons[6], vec![Weekly]);
+        assert_eq!(reasons[7], vec![Monthly]);
+        assert!(reasons[8].is_empty());
+        assert_eq!(reasons[9], vec![UnknownTime]);
+    }
+
+    #[test]
+    fn test_select_always_keeps_newest() {
+        let policy = RetentionPolicy {
+            keep_last: 0,
+            keep_hourly: 0,
+            keep_daily: 0,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            keep_yearly: 0,
+        };
+        let reasons = policy.select(&["2026-01-17_18-00-00", "2026-01-18_18-00-00"]);
+        assert!(reasons[0].is_empty());
+        assert_eq!(reasons[1], vec![Reason::Newest]);
+    }
+
+    #[test]
+    fn test_prune_uses_filename_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = [
+            "2026-01-16_18-00-00.enc",
+            "2026-01-17_18-00-00.enc",
+            "2026-01-18_18-00-00.enc",
+        ];
+        // Written newest first, so modification times disagree with the names
+        for name in names.iter().rev() {
+            std::fs::write(dir.path().join(name), b"data").unwrap();
+        }
+        let policy = RetentionPolicy {
+            keep_last: 2,
+            keep_hourly: 0,
+            keep_daily: 0,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            keep_yearly: 0,
+        };
+
+        let folder = Folder::new(dir.path());
+        assert_eq!(prune(&folder, &policy, true, |_| Ok(())).unwrap(), 1);
+        assert!(dir.path().join(names[0]).exists());
+
+        assert_eq!(prune(&folder, &policy, false, |_| Ok(())).unwrap(), 1);
+        assert!(!dir.path().join(names[0]).exists());
+        assert!(dir.path().join(names[1]).exists());
+        assert!(dir.path().join(names[2]).exists());
+    }
+
+    #[test]
+    fn test_prune_only_removes_own_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("2026-01-16_18-00-00.enc.001"), b"data").unwrap();
+        std::fs::write(dir.path().join("2026-01-18_18-00-00.enc"), b"data").unwrap();
+        // An old manifest, with a newer timestamp inside, naming the newest backup
+        let manifest = serde_json::json!({
+            "version": 2,
+            "timestamp": "2026-01-19_18-00-00",
+            "original_size": 8,
+            "chunk_size": 4,
+            "chunks": [
+                {"name": "2026-01-16_18-00-00.enc.001", "size": 4},
+                {"name": "2026-01-18_18-00-00.enc", "size": 4}
+            ],
+            "sha256": ""
+        });
+        std::fs::write(
+            dir.path().join("2026-01-16_18-00-00.enc.manifest"),
+            manifest.to_string(),
+        )
+        .unwrap();
+        let policy = RetentionPolicy {
+            keep_last: 0,
+            keep_hourly: 0,
+            keep_daily: 0,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            keep_yearly: 0,
+        };
+
+        let folder = Folder::new(dir.path());
+        assert_eq!(prune(&folder, &policy, false, |_| Ok(())).unwrap(), 1);
+        assert!(!dir.path().join("2026-01-16_18-00-00.enc.001").exists());
+        assert!(!dir.path().join("2026-01-16_18-00-00.enc.manifest").exists());
+        assert!(dir.path().join("2026-01-18_18-00-00.enc").exists());
+        assert!(is_backup_file("2026-01-16_18-00-00.enc", "2026-01-16_18-00-00"));
+        assert!(!is_backup_file("2026-01-16_18-00-00.encx", "2026-01-16_18-00-00"));
+        assert!(!is_backup_file("2026-01-16_18-00-00.enc./../x", "2026-01-16_18-00-00"));
+    }
+
+    #[test]
+    fn test_keeps_newest_verified() {
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            keep_hourly: 0,
+            keep_daily: 0,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            keep_yearly: 0,
+        };
+        let timestamps = [
+            "2026-01-16_18-00-00",
+            "2026-01-17_18-00-00",
+            "2026-01-18_18-00-00",
+        ];
+        let mut reasons = policy.select(&timestamps);
+        let mut tried = Vec::new();
+        keep_newest_verified(&timestamps, &mut reasons, |i| {
+            tried.push(i);
+            if i == 2 {
+                anyhow::bail!("corrupted");
+            }
+            Ok(())
+        });
+
+        assert_eq!(tried, vec![2, 1]);
+        assert!(reasons[0].is_empty());
+        assert_eq!(reasons[1], vec![Reason::NewestVerified]);
+        assert_eq!(reasons[2], vec![Reason::Newest, Reason::Last]);
+    }
+}
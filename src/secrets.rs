@@ -0,0 +1,398 @@
+use crate::config::{Config, SecretStoreKind};
+use crate::keys;
+use anyhow::{Context, Result};
+use std::fs::OpenOptions;
+use std::io::{IsTerminal, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Service name under which secrets are stored
+const SERVICE: &str = "whatsapp-backup";
+/// Account of the passphrase set by `init`
+pub const DEFAULT_ACCOUNT: &str = "encryption-key";
+/// Environment variable read by the `env` backend for the default account
+pub const DEFAULT_ENV_VARIABLE: &str = "WHATSAPP_BACKUP_PASSPHRASE";
+
+/// Backend holding passphrases, keyed by account name
+pub trait SecretStore {
+    /// Short description for status output
+    fn describe(&self) -> String;
+
+    fn get(&self, account: &str) -> Result<String>;
+
+    fn store(&self, account: &str, secret: &str) -> Result<()>;
+
+    fn delete(&self, account: &str) -> Result<()>;
+
+    fn has(&self, account: &str) -> bool {
+        self.get(account).is_ok()
+    }
+}
+
+/// Secret store selected in the config (the macOS Keychain by default)
+pub fn configured() -> Result<Box<dyn SecretStore>> {
+    Ok(from_kind(&Config::load()?.secret_store))
+}
+
+pub fn from_kind(kind: &SecretStoreKind) -> Box<dyn SecretStore> {
+    match kind {
+        SecretStoreKind::Keychain => Box::new(Keychain),
+        SecretStoreKind::SecretService => Box::new(SecretService),
+        SecretStoreKind::Pass { prefix } => Box::new(Pass {
+            prefix: prefix.clone(),
+        }),
+        SecretStoreKind::Env { variable } => Box::new(Env {
+            variable: variable.clone(),
+        }),
+        SecretStoreKind::File { path } => Box::new(KeyFile { path: path.clone() }),
+        SecretStoreKind::Prompt => Box::new(Prompt),
+    }
+}
+
+/// Runs `program` with `args`, optionally feeding `input` on stdin, and
+/// returns stdout
+fn run(program: &str, args: &[&str], input: Option<&str>) -> Result<String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("{} failed: {}", program, stderr.trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// macOS Keychain via the `security` command
+pub struct Keychain;
+
+impl SecretStore for Keychain {
+    fn describe(&self) -> String {
+        "macOS Keychain".to_string()
+    }
+
+    fn get(&self, account: &str) -> Result<String> {
+        let output = run(
+            "security",
+            &["find-generic-password", "-s", SERVICE, "-a", account, "-w"],
+            None,
+        )
+        .context(
+            "Failed to retrieve passphrase from keychain.\n\
+             Run 'whatsapp-backup init' to set up encryption.",
+        )?;
+        Ok(output.trim().to_string())
+    }
+
+    fn store(&self, account: &str, secret: &str) -> Result<()> {
+        // First try to delete any existing entry
+        self.delete(account).ok();
+
+        run(
+            "security",
+            &[
+                "add-generic-password",
+                "-s", SERVICE,
+                "-a", account,
+                "-w", secret,
+                "-U", // Update if exists
+            ],
+            None,
+        )
+        .context("Failed to store passphrase in keychain")?;
+        Ok(())
+    }
+
+    fn delete(&self, account: &str) -> Result<()> {
+        run(
+            "security",
+            &["delete-generic-password", "-s", SERVICE, "-a", account],
+            None,
+        )
+        .context("Failed to delete passphrase")?;
+        Ok(())
+    }
+}
+
+/// Linux Secret Service (GNOME Keyring, KWallet) over D-Bus via `secret-tool`
+pub struct SecretService;
+
+impl SecretStore for SecretService {
+    fn describe(&self) -> String {
+        "Secret Service".to_string()
+    }
+
+    fn get(&self, account: &str) -> Result<String> {
+        let output = run(
+            "secret-tool",
+            &["lookup", "service", SERVICE, "account", account],
+            None,
+        )
+        .context("Failed to retrieve passphrase from the Secret Service")?;
+        if output.is_empty() {
+            anyhow::bail!("No passphrase in the Secret Service for account '{}'", account);
+        }
+        Ok(output.trim_end_matches('\n').to_string())
+    }
+
+    fn store(&self, account: &str, secret: &str) -> Result<()> {
+        let label = format!("{} ({})", SERVICE, account);
+        run(
+            "secret-tool",
+            &[
+                "store", "--label", &label,
+                "service", SERVICE,
+                "account", account,
+            ],
+            Some(secret),
+        )
+        .context("Failed to store passphrase in the Secret Service")?;
+        Ok(())
+    }
+
+    fn delete(&self, account: &str) -> Result<()> {
+        run(
+            "secret-tool",
+            &["clear", "service", SERVICE, "account", account],
+            None,
+        )
+        .context("Failed to delete passphrase from the Secret Service")?;
+        Ok(())
+    }
+}
+
+/// The standard Unix password manager; entries live at `<prefix>/<account>`
+pub struct Pass {
+    prefix: String,
+}
+
+impl Pass {
+    fn entry(&self, account: &str) -> String {
+        format!("{}/{}", self.prefix.trim_end_matches('/'), account)
+    }
+}
+
+impl SecretStore for Pass {
+    fn describe(&self) -> String {
+        format!("pass ({})", self.prefix)
+    }
+
+    fn get(&self, account: &str) -> Result<String> {
+        let output = run("pass", &["show", &self.entry(account)], None)
+            .context("Failed to retrieve passphrase from pass")?;
+        // pass stores the password on the first line
+        Ok(output.lines().next().unwrap_or("").to_string())
+    }
+
+    fn store(&self, account: &str, secret: &str) -> Result<()> {
+        let input = format!("{}\n", secret);
+        run(
+            "pass",
+            &["insert", "--multiline", "--force", &self.entry(account)],
+            Some(&input),
+        )
+        .context("Failed to store passphrase in pass")?;
+        Ok(())
+    }
+
+    fn delete(&self, account: &str) -> Result<()> {
+        run("pass", &["rm", "--force", &self.entry(account)], None)
+            .context("Failed to delete passphrase from pass")?;
+        Ok(())
+    }
+}
+
+/// Read-only store for CI and containers: the default account comes from
+/// `variable`, other accounts from `<variable>_<ACCOUNT>`
+pub struct Env {
+    variable: String,
+}
+
+impl Env {
+    fn variable_for(&self, account: &str) -> String {
+        if account == DEFAULT_ACCOUNT {
+            return self.variable.clone();
+        }
+        let suffix: String = account
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect();
+        format!("{}_{}", self.variable, suffix)
+    }
+}
+
+impl SecretStore for Env {
+    fn describe(&self) -> String {
+        format!("environment variable {}", self.variable)
+    }
+
+    fn get(&self, account: &str) -> Result<String> {
+        let variable = self.variable_for(account);
+        std::env::var(&variable)
+            .with_context(|| format!("Environment variable {} is not set", variable))
+    }
+
+    fn store(&self, account: &str, _secret: &str) -> Result<()> {
+        anyhow::bail!(
+            "Can't store passphrases in the environment; set {} yourself",
+            self.variable_for(account)
+        )
+    }
+
+    fn delete(&self, account: &str) -> Result<()> {
+        anyhow::bail!(
+            "Can't delete passphrases from the environment; unset {} yourself",
+            self.variable_for(account)
+        )
+    }
+}
+
+/// Passphrase in a file readable only by the owner. The default account uses
+/// `path`, other accounts `<path>.<account>`.
+pub struct KeyFile {
+    path: PathBuf,
+}
+
+impl KeyFile {
+    fn path_for(&self, account: &str) -> PathBuf {
+        if account == DEFAULT_ACCOUNT {
+            return self.path.clone();
+        }
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", account));
+        PathBuf::from(name)
+    }
+}
+
+impl SecretStore for KeyFile {
+    fn describe(&self) -> String {
+        format!("key file {}", self.path.display())
+    }
+
+    fn get(&self, account: &str) -> Result<String> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = self.path_for(account);
+        let metadata = std::fs::metadata(&path)
+            .with_context(|| format!("Key file not found: {}", path.display()))?;
+        if metadata.permissions().mode() & 0o077 != 0 {
+            anyhow::bail!(
+                "Key file {} is accessible by other users; run 'chmod 600' on it",
+                path.display()
+            );
+        }
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read key file: {}", path.display()))?;
+        Ok(contents.trim_end_matches('\n').to_string())
+    }
+
+    fn store(&self, account: &str, secret: &str) -> Result<()> {
+        let path = self.path_for(account);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("Failed to write key file: {}", path.display()))?;
+        file.write_all(secret.as_bytes())?;
+        Ok(())
+    }
+
+    fn delete(&self, account: &str) -> Result<()> {
+        let path = self.path_for(account);
+        std::fs::remove_file(&path)
+            .with_context(|| format!("Failed to delete key file: {}", path.display()))
+    }
+}
+
+/// Nothing is stored; the passphrase is asked for on the terminal each time
+pub struct Prompt;
+
+impl SecretStore for Prompt {
+    fn describe(&self) -> String {
+        "prompt on terminal".to_string()
+    }
+
+    fn get(&self, account: &str) -> Result<String> {
+        if !std::io::stdin().is_terminal() {
+            anyhow::bail!("No terminal to prompt for the '{}' passphrase", account);
+        }
+        let prompt = if account == DEFAULT_ACCOUNT {
+            "Passphrase: ".to_string()
+        } else {
+            format!("Passphrase ({}): ", account)
+        };
+        keys::prompt_passphrase(&prompt)
+    }
+
+    fn store(&self, _account: &str, _secret: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self, _account: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn has(&self, _account: &str) -> bool {
+        std::io::stdin().is_terminal()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_key_file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = KeyFile {
+            path: dir.path().join("passphrase"),
+        };
+
+        store.store(DEFAULT_ACCOUNT, "secret one").unwrap();
+        store.store("slot-family", "secret two").unwrap();
+        assert_eq!(store.get(DEFAULT_ACCOUNT).unwrap(), "secret one");
+        assert_eq!(store.get("slot-family").unwrap(), "secret two");
+
+        let mode = std::fs::metadata(dir.path().join("passphrase")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::set_permissions(
+            dir.path().join("passphrase"),
+            std::fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        assert!(store.get(DEFAULT_ACCOUNT).is_err());
+
+        store.delete("slot-family").unwrap();
+        assert!(!store.has("slot-family"));
+    }
+
+    #[test]
+    fn test_env_store_variable_names() {
+        let store = Env {
+            variable: DEFAULT_ENV_VARIABLE.to_string(),
+        };
+        assert_eq!(store.variable_for(DEFAULT_ACCOUNT), DEFAULT_ENV_VARIABLE);
+        assert_eq!(
+            store.variable_for("slot-family"),
+            "WHATSAPP_BACKUP_PASSPHRASE_SLOT_FAMILY"
+        );
+        assert!(store.store(DEFAULT_ACCOUNT, "secret").is_err());
+    }
+}
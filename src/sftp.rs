@@ -0,0 +1,440 @@
+use crate::config::SftpConfig;
+use crate::destination::{copy_hashed, Destination, StoredFile};
+use anyhow::{Context, Result};
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, RenameFlags, Session};
+use std::cell::OnceCell;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long to wait for the server before giving up
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// SFTP status codes for a missing file or directory
+const NO_SUCH_FILE: i32 = 2;
+const NO_SUCH_PATH: i32 = 10;
+
+/// Suffix of files being uploaded, renamed away once verified
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Suffix of a file set aside while it is being replaced
+const PREVIOUS_SUFFIX: &str = ".previous";
+
+/// Directory on an SSH server. Files are uploaded under a temporary name,
+/// read back and checked, then renamed into place, so a backup never
+/// appears half-written.
+pub struct Sftp {
+    host: String,
+    port: u16,
+    user: String,
+    key_path: Option<PathBuf>,
+    /// Without a trailing slash
+    remote_dir: String,
+    /// Opened on first use, so an unreachable server only fails this
+    /// destination
+    connection: OnceCell<(Session, ssh2::Sftp)>,
+}
+
+impl Sftp {
+    pub fn new(config: &SftpConfig) -> Self {
+        let remote_dir = match config.remote_dir.trim_end_matches('/') {
+            "" if config.remote_dir.starts_with('/') => "/",
+            dir => dir,
+        };
+        Self {
+            host: config.host.clone(),
+            port: config.port,
+            user: config.user.clone(),
+            key_path: config.key_path.clone(),
+            remote_dir: remote_dir.to_string(),
+            connection: OnceCell::new(),
+        }
+    }
+
+    fn sftp(&self) -> Result<&ssh2::Sftp> {
+        if let Some((_, sftp)) = self.connection.get() {
+            return Ok(sftp);
+        }
+        let session = self
+            .connect()
+            .with_context(|| format!("Failed to connect to {}", self.location()))?;
+        let sftp = session.sftp().context("Failed to start SFTP")?;
+        Ok(&self.connection.get_or_init(|| (session, sftp)).1)
+    }
+
+    /// Opens an SSH session, checking the server against `~/.ssh/known_hosts`
+    /// and authenticating with the configured key, or the SSH agent and then
+    /// an unencrypted `~/.ssh/id_ed25519`, `id_ecdsa` or `id_rsa`
+    fn connect(&self) -> Result<Session> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .with_context(|| format!("Failed to resolve {}", self.host))?;
+        let tcp = TcpStream::connect_timeout(&address, TIMEOUT)?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(TIMEOUT.as_millis() as u32);
+        session.handshake()?;
+        self.check_host_key(&session)?;
+
+        match &self.key_path {
+            Some(key) => session
+                .userauth_pubkey_file(&self.user, None, key, None)
+                .with_context(|| format!("Authentication with {} failed", key.display()))?,
+            None => {
+                if session.userauth_agent(&self.user).is_err() {
+                    let home = dirs::home_dir().unwrap_or_default();
+                    for name in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+                        let key = home.join(".ssh").join(name);
+                        if key.exists()
+                            && session
+                                .userauth_pubkey_file(&self.user, None, &key, None)
+                                .is_ok()
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        if !session.authenticated() {
+            anyhow::bail!("Authentication as {} failed", self.user);
+        }
+        Ok(session)
+    }
+
+    fn check_host_key(&self, session: &Session) -> Result<()> {
+        let (key, _) = session.host_key().context("Server sent no host key")?;
+        let mut known_hosts = session.known_hosts()?;
+        let file = dirs::home_dir()
+            .context("Failed to detect home directory")?
+            .join(".ssh/known_hosts");
+        if file.exists() {
+            known_hosts.read_file(&file, KnownHostFileKind::OpenSSH)?;
+        }
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => anyhow::bail!(
+                "Host key of {} does not match {}; refusing to connect",
+                self.host,
+                file.display()
+            ),
+            CheckResult::NotFound => anyhow::bail!(
+                "Host key of {} is not in {}; connect once with `ssh -p {} {}@{}` to add it",
+                self.host,
+                file.display(),
+                self.port,
+                self.user,
+                self.host
+            ),
+            CheckResult::Failure => anyhow::bail!("Failed to check the host key of {}", self.host),
+        }
+    }
+
+    /// Moves `partial` to `target`. SFTP servers before protocol version 5
+    /// (OpenSSH among them) can't rename over a file, and the ssh2 crate
+    /// doesn't expose the `posix-rename@openssh.com` extension, so an
+    /// existing `target` is set aside first and only removed once `partial`
+    /// is in place. A file is always there under one of the two names.
+    fn replace(&self, partial: &Path, target: &Path) -> Result<()> {
+        let sftp = self.sftp()?;
+        let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+        if sftp.rename(partial, target, Some(flags)).is_ok() {
+            return Ok(());
+        }
+
+        let previous = hidden_path(target, PREVIOUS_SUFFIX);
+        let set_aside = sftp.stat(target).is_ok();
+        if set_aside {
+            // A leftover from an interrupted replace; `target` is newer
+            if sftp.stat(&previous).is_ok() {
+                sftp.unlink(&previous)?;
+            }
+            sftp.rename(target, &previous, None)
+                .with_context(|| format!("Failed to set aside {}", target.display()))?;
+        }
+        if let Err(e) = sftp.rename(partial, target, None) {
+            if set_aside {
+                let _ = sftp.rename(&previous, target, None);
+            }
+            return Err(e).with_context(|| format!("Failed to rename {}", partial.display()));
+        }
+        if set_aside {
+            sftp.unlink(&previous)?;
+        }
+        Ok(())
+    }
+
+    /// Moves back a file left set aside by an interrupted `replace`.
+    /// Returns whether there was one.
+    fn restore_previous(&self, name: &str) -> Result<bool> {
+        let sftp = self.sftp()?;
+        let target = self.path(name);
+        let previous = hidden_path(&target, PREVIOUS_SUFFIX);
+        if sftp.stat(&previous).is_err() || sftp.stat(&target).is_ok() {
+            return Ok(false);
+        }
+        sftp.rename(&previous, &target, None)
+            .with_context(|| format!("Failed to restore {}", previous.display()))?;
+        Ok(true)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        remote_path(&self.remote_dir, name)
+    }
+
+    /// Creates the directory holding `name` and any missing parents
+    fn create_dirs(&self, name: &str) -> Result<()> {
+        let sftp = self.sftp()?;
+        let mut dir = self.remote_dir.clone();
+        let parents = name.rsplit_once('/').map_or("", |(parent, _)| parent);
+        let mut components = vec![""];
+        components.extend(parents.split('/').filter(|c| !c.is_empty()));
+        for component in components {
+            if !component.is_empty() {
+                dir = format!("{}/{}", dir.trim_end_matches('/'), component);
+            }
+            let path = Path::new(&dir);
+            if sftp.stat(path).is_err() {
+                sftp.mkdir(path, 0o700)
+                    .with_context(|| format!("Failed to create {}", dir))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the files under `dir` (named `prefix...`) to `files`, skipping
+    /// hidden entries such as partial uploads
+    fn walk(&self, dir: &Path, prefix: &str, files: &mut Vec<StoredFile>) -> Result<()> {
+        let entries = match self.sftp()?.readdir(dir) {
+            Ok(entries) => entries,
+            Err(e) if is_not_found(&e) => return Ok(()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to list {}", dir.display()));
+            }
+        };
+        for (path, stat) in entries {
+            let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if file_name.starts_with('.') {
+                continue;
+            }
+            let name = format!("{}{}", prefix, file_name);
+            if stat.is_dir() {
+                self.walk(&path, &format!("{}/", name), files)?;
+            } else if stat.is_file() {
+                files.push(StoredFile {
+                    name,
+                    size: stat.size.unwrap_or(0),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Destination for Sftp {
+    fn location(&self) -> String {
+        let dir = if self.remote_dir.starts_with('/') {
+            self.remote_dir.clone()
+        } else {
+            format!("/~/{}", self.remote_dir)
+        };
+        format!("sftp://{}@{}:{}{}", self.user, self.host, self.port, dir)
+    }
+
+    fn put(&self, file: &Path, name: &str) -> Result<()> {
+        let sftp = self.sftp()?;
+        self.create_dirs(name)?;
+        self.restore_previous(name)?;
+        let target = self.path(name);
+        let partial = hidden_path(&target, PARTIAL_SUFFIX);
+
+        let mut input = BufReader::new(
+            File::open(file).with_context(|| format!("Failed to open {}", file.display()))?,
+        );
+        let mut output = BufWriter::new(
+            sftp.create(&partial)
+                .with_context(|| format!("Failed to create {}", partial.display()))?,
+        );
+        let (size, sha256) = copy_hashed(&mut input, &mut output)
+            .with_context(|| format!("Failed to upload {}", name))?;
+        output.into_inner().map_err(|e| e.into_error())?;
+
+        // Read the upload back, so a short write or bad disk is caught before
+        // it replaces anything
+        let mut uploaded = BufReader::new(sftp.open(&partial)?);
+        let (stored_size, stored_sha256) = copy_hashed(&mut uploaded, &mut std::io::sink())?;
+        if (stored_size, &stored_sha256) != (size, &sha256) {
+            let _ = sftp.unlink(&partial);
+            anyhow::bail!(
+                "Upload of {} is corrupt: {} bytes with SHA256 {}, expected {} bytes with {}",
+                name,
+                stored_size,
+                stored_sha256,
+                size,
+                sha256
+            );
+        }
+
+        self.replace(&partial, &target)?;
+        let stored = self.stat(name)?.map_or(0, |f| f.size);
+        if stored != size {
+            anyhow::bail!(
+                "{} is {} bytes on the server, expected {}",
+                name,
+                stored,
+                size
+            );
+        }
+        Ok(())
+    }
+
+    fn get(&self, name: &str, file: &Path) -> Result<()> {
+        let sftp = self.sftp()?;
+        let opened = match sftp.open(self.path(name)) {
+            Err(e) if is_not_found(&e) && self.restore_previous(name)? => {
+                sftp.open(self.path(name))
+            }
+            opened => opened,
+        };
+        let mut input = BufReader::new(opened.with_context(|| format!("Failed to open {}", name))?);
+        let mut output = BufWriter::new(
+            File::create(file).with_context(|| format!("Failed to create {}", file.display()))?,
+        );
+        std::io::copy(&mut input, &mut output)
+            .with_context(|| format!("Failed to download {}", name))?;
+        output.flush()?;
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StoredFile>> {
+        // Only walk the directory the prefix points into
+        let (dir, _) = prefix.rsplit_once('/').unwrap_or(("", ""));
+        let mut files = Vec::new();
+        if dir.is_empty() {
+            self.walk(Path::new(&self.remote_dir), "", &mut files)?;
+        } else {
+            self.walk(&self.path(dir), &format!("{}/", dir), &mut files)?;
+        }
+        files.retain(|f| f.name.starts_with(prefix));
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        self.sftp()?
+            .unlink(&self.path(name))
+            .with_context(|| format!("Failed to remove {}", name))
+    }
+
+    fn stat(&self, name: &str) -> Result<Option<StoredFile>> {
+        match self.sftp()?.stat(&self.path(name)) {
+            Ok(stat) if stat.is_file() => Ok(Some(StoredFile {
+                name: name.to_string(),
+                size: stat.size.unwrap_or(0),
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if is_not_found(&e) && self.restore_previous(name)? => self.stat(name),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn is_not_found(error: &ssh2::Error) -> bool {
+    matches!(error.code(), ErrorCode::SFTP(NO_SUCH_FILE | NO_SUCH_PATH))
+}
+
+/// `name` (`/`-separated) inside the remote directory `dir`
+fn remote_path(dir: &str, name: &str) -> PathBuf {
+    PathBuf::from(format!("{}/{}", dir.trim_end_matches('/'), name))
+}
+
+/// Hidden name next to `target`, such as the one an upload is written to first
+fn hidden_path(target: &Path, suffix: &str) -> PathBuf {
+    let file_name = target
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    target.with_file_name(format!(".{}{}", file_name, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_paths() {
+        let sftp = Sftp::new(&SftpConfig {
+            host: "nas.local".to_string(),
+            port: 2222,
+            user: "backup".to_string(),
+            key_path: None,
+            remote_dir: "/volume1/whatsapp/".to_string(),
+        });
+        assert_eq!(
+            sftp.location(),
+            "sftp://backup@nas.local:2222/volume1/whatsapp"
+        );
+        assert_eq!(
+            sftp.path("repo/chunks/ab/abcd"),
+            Path::new("/volume1/whatsapp/repo/chunks/ab/abcd")
+        );
+        assert_eq!(
+            hidden_path(&sftp.path("a.enc"), PARTIAL_SUFFIX),
+            Path::new("/volume1/whatsapp/.a.enc.partial")
+        );
+        assert_eq!(
+            hidden_path(&sftp.path("repo/key"), PREVIOUS_SUFFIX),
+            Path::new("/volume1/whatsapp/repo/.key.previous")
+        );
+        assert_eq!(remote_path("/", "a.enc"), Path::new("/a.enc"));
+        assert_eq!(remote_path("backups", "a.enc"), Path::new("backups/a.enc"));
+    }
+
+    /// Runs against a real server, e.g.
+    /// `docker run -p 2222:22 -e USER_NAME=backup -e PUBLIC_KEY="$(cat key.pub)"
+    /// linuxserver/openssh-server` with its host key in `~/.ssh/known_hosts`:
+    /// `WHATSAPP_BACKUP_TEST_SFTP=backup@localhost:2222:/config/backups
+    /// WHATSAPP_BACKUP_TEST_SFTP_KEY=key cargo test -- --ignored`
+    #[test]
+    #[ignore = "needs an SSH server, see WHATSAPP_BACKUP_TEST_SFTP"]
+    fn test_sftp_server() {
+        let target = std::env::var("WHATSAPP_BACKUP_TEST_SFTP").unwrap();
+        let (user, rest) = target.split_once('@').unwrap();
+        let (host, rest) = rest.split_once(':').unwrap();
+        let (port, remote_dir) = rest.split_once(':').unwrap();
+        let sftp = Sftp::new(&SftpConfig {
+            host: host.to_string(),
+            port: port.parse().unwrap(),
+            user: user.to_string(),
+            key_path: std::env::var_os("WHATSAPP_BACKUP_TEST_SFTP_KEY").map(PathBuf::from),
+            remote_dir: format!("{}/test-{}", remote_dir, std::process::id()),
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("source");
+        std::fs::write(&file, vec![7u8; 200_000]).unwrap();
+        sftp.put(&file, "a.enc").unwrap();
+        sftp.put(&file, "repo/chunks/ab/abcd").unwrap();
+        // Replacing an existing file
+        sftp.put(&file, "a.enc").unwrap();
+
+        let names: Vec<String> = sftp.list("").unwrap().into_iter().map(|f| f.name).collect();
+        assert_eq!(names, vec!["a.enc", "repo/chunks/ab/abcd"]);
+        assert_eq!(sftp.stat("a.enc").unwrap().unwrap().size, 200_000);
+        assert!(sftp.stat("missing").unwrap().is_none());
+
+        let download = dir.path().join("download");
+        sftp.get("repo/chunks/ab/abcd", &download).unwrap();
+        assert_eq!(std::fs::read(&download).unwrap(), vec![7u8; 200_000]);
+
+        sftp.delete("a.enc").unwrap();
+        sftp.delete("repo/chunks/ab/abcd").unwrap();
+        assert!(sftp.list("").unwrap().is_empty());
+    }
+}
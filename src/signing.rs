@@ -0,0 +1,187 @@
+use crate::backup::Manifest;
+use crate::config::Config;
+use crate::paths;
+use anyhow::{Context, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::RngCore;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+/// Text prefixes for encoded Ed25519 keys
+const VERIFYING_KEY_PREFIX: &str = "wbvk1";
+const SIGNING_KEY_PREFIX: &str = "wbsg1";
+
+const SIGNING_KEY_FILE: &str = "signing.key";
+
+/// Location of the manifest signing key. It is stored unencrypted so
+/// scheduled backups can sign without a passphrase.
+pub fn signing_key_path() -> Result<PathBuf> {
+    Ok(paths::config_dir()?.join(SIGNING_KEY_FILE))
+}
+
+pub fn encode_verifying_key(key: &VerifyingKey) -> String {
+    format!("{}{}", VERIFYING_KEY_PREFIX, hex::encode(key.as_bytes()))
+}
+
+pub fn parse_verifying_key(encoded: &str) -> Result<VerifyingKey> {
+    let hex_part = encoded
+        .trim()
+        .strip_prefix(VERIFYING_KEY_PREFIX)
+        .with_context(|| format!("Expected a key starting with '{}'", VERIFYING_KEY_PREFIX))?;
+    let bytes: [u8; 32] = hex::decode(hex_part)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Key must be 32 bytes"))?;
+    VerifyingKey::from_bytes(&bytes).context("Invalid signing public key")
+}
+
+/// Creates a signing key, readable only by the owner, and pins its public
+/// key in `config` (the caller saves the config)
+pub fn create_signing_key(config: &mut Config) -> Result<VerifyingKey> {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let key = SigningKey::from_bytes(&bytes);
+
+    let path = signing_key_path()?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("Failed to write signing key: {}", path.display()))?;
+    file.write_all(format!("{}{}", SIGNING_KEY_PREFIX, hex::encode(bytes)).as_bytes())?;
+
+    let verifying_key = key.verifying_key();
+    config.signing_public_key = Some(encode_verifying_key(&verifying_key));
+    Ok(verifying_key)
+}
+
+/// Loads the signing key, checking it against the pinned public key.
+/// Returns `None` if no signing key has been set up.
+pub fn load_signing_key(config: &Config) -> Result<Option<SigningKey>> {
+    let Some(pinned) = pinned_key(config)? else {
+        return Ok(None);
+    };
+
+    let path = signing_key_path()?;
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("Signing key not found: {}", path.display()))?;
+    let bytes: [u8; 32] = contents
+        .trim()
+        .strip_prefix(SIGNING_KEY_PREFIX)
+        .and_then(|h| hex::decode(h).ok())
+        .and_then(|b| b.try_into().ok())
+        .with_context(|| format!("Invalid signing key: {}", path.display()))?;
+
+    let key = SigningKey::from_bytes(&bytes);
+    if key.verifying_key() != pinned {
+        anyhow::bail!(
+            "Signing key {} doesn't match the public key pinned in config",
+            path.display()
+        );
+    }
+    Ok(Some(key))
+}
+
+/// Public key manifests must be signed with, if one is pinned
+pub fn pinned_key(config: &Config) -> Result<Option<VerifyingKey>> {
+    config
+        .signing_public_key
+        .as_deref()
+        .map(parse_verifying_key)
+        .transpose()
+}
+
+/// Bytes covered by the signature: the manifest as JSON without its signature
+fn signed_bytes(manifest: &Manifest) -> Result<Vec<u8>> {
+    let unsigned = Manifest {
+        signature: None,
+        ..manifest.clone()
+    };
+    Ok(serde_json::to_vec(&unsigned)?)
+}
+
+pub fn sign_manifest(manifest: &mut Manifest, key: &SigningKey) -> Result<()> {
+    manifest.signature = Some(sign(&signed_bytes(manifest)?, key));
+    Ok(())
+}
+
+/// Checks the manifest signature against `key`
+pub fn verify_manifest(manifest: &Manifest, key: &VerifyingKey) -> Result<()> {
+    let signature = manifest
+        .signature
+        .as_deref()
+        .context("Manifest is not signed")?;
+    verify(&signed_bytes(manifest)?, signature, key, "Manifest")
+}
+
+/// Hex-encoded signature over `bytes`
+pub fn sign(bytes: &[u8], key: &SigningKey) -> String {
+    hex::encode(key.sign(bytes).to_bytes())
+}
+
+/// Checks a hex-encoded signature over `bytes` against `key`; `what` names
+/// the signed object in errors
+pub fn verify(bytes: &[u8], signature: &str, key: &VerifyingKey, what: &str) -> Result<()> {
+    let signature: [u8; 64] = hex::decode(signature.trim())
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .with_context(|| format!("{} signature is malformed", what))?;
+
+    key.verify(bytes, &Signature::from_bytes(&signature))
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "{} signature is invalid - the backup may have been tampered with",
+                what
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::ChunkInfo;
+
+    fn manifest() -> Manifest {
+        Manifest {
+            version: 2,
+            format_version: 4,
+            timestamp: "2026-01-18_05-59-34".to_string(),
+            original_size: 10,
+            chunk_size: 90_000_000,
+            chunks: vec![ChunkInfo {
+                name: "2026-01-18_05-59-34.enc".to_string(),
+                size: 10,
+            }],
+            sha256: "00".repeat(32),
+            databases: Vec::new(),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify_manifest() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let other = SigningKey::from_bytes(&[8u8; 32]);
+
+        let mut signed = manifest();
+        assert!(verify_manifest(&signed, &key.verifying_key()).is_err());
+        sign_manifest(&mut signed, &key).unwrap();
+        verify_manifest(&signed, &key.verifying_key()).unwrap();
+        assert!(verify_manifest(&signed, &other.verifying_key()).is_err());
+
+        // Swapping the data hash must invalidate the signature
+        signed.sha256 = "11".repeat(32);
+        assert!(verify_manifest(&signed, &key.verifying_key()).is_err());
+    }
+
+    #[test]
+    fn test_verifying_key_encoding() {
+        let key = SigningKey::from_bytes(&[7u8; 32]).verifying_key();
+        let parsed = parse_verifying_key(&encode_verifying_key(&key)).unwrap();
+        assert_eq!(parsed, key);
+        assert!(parse_verifying_key("wbpk1abcd").is_err());
+    }
+}
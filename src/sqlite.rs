@@ -0,0 +1,126 @@
+use anyhow::{Context, Result};
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+/// First bytes of every SQLite database file
+const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+/// How often a snapshot is retried while WhatsApp holds a lock
+const SNAPSHOT_ATTEMPTS: u32 = 40;
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Files SQLite keeps next to a database while it is open
+const SIDECAR_SUFFIXES: &[&str] = &["-wal", "-shm", "-journal"];
+
+/// Result of `PRAGMA integrity_check` on a database snapshot, recorded in the manifest
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DatabaseCheck {
+    /// Path inside the archive
+    pub path: String,
+    /// "ok", or the problems SQLite reported
+    pub integrity: String,
+}
+
+impl DatabaseCheck {
+    pub fn is_ok(&self) -> bool {
+        self.integrity == "ok"
+    }
+}
+
+/// Whether `path` starts with the SQLite header
+pub fn is_database(path: &Path) -> bool {
+    let mut magic = [0u8; 16];
+    std::fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok()
+        && &magic == SQLITE_MAGIC
+}
+
+/// Whether `path` is a WAL, shared-memory or rollback journal file of a
+/// database next to it
+pub fn is_sidecar(path: &Path) -> bool {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    SIDECAR_SUFFIXES.iter().any(|suffix| {
+        name.strip_suffix(suffix)
+            .is_some_and(|db| !db.is_empty() && is_database(&path.with_file_name(db)))
+    })
+}
+
+/// Copies a live database into `dest` with the online-backup API, which reads
+/// a consistent view including changes still in the WAL. The copy uses a
+/// rollback journal so it is a single self-contained file.
+pub fn snapshot(source: &Path, dest: &Path) -> Result<()> {
+    let src = Connection::open_with_flags(
+        source,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .with_context(|| format!("Failed to open database: {}", source.display()))?;
+
+    let mut dst = Connection::open(dest)?;
+    {
+        let backup = Backup::new(&src, &mut dst)?;
+        // All pages in one step, so the copy comes from a single read transaction
+        let mut attempts = 0;
+        loop {
+            match backup.step(-1)? {
+                StepResult::Done => break,
+                // WhatsApp may hold a write lock for a moment
+                _ if attempts < SNAPSHOT_ATTEMPTS => {
+                    attempts += 1;
+                    std::thread::sleep(RETRY_DELAY);
+                }
+                _ => anyhow::bail!("Database stayed locked: {}", source.display()),
+            }
+        }
+    }
+    dst.pragma_update(None, "journal_mode", "DELETE")?;
+    Ok(())
+}
+
+/// Runs `PRAGMA integrity_check`, returning "ok" or the reported problems
+pub fn integrity_check(path: &Path) -> Result<String> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let rows = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(rows.join("; "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_includes_wal_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("ChatStorage.sqlite");
+
+        // Keep the writer open with automatic checkpoints off, like a running app
+        let writer = Connection::open(&source).unwrap();
+        writer.pragma_update(None, "journal_mode", "WAL").unwrap();
+        writer.pragma_update(None, "wal_autocheckpoint", 0).unwrap();
+        writer
+            .execute_batch("CREATE TABLE messages (text TEXT); INSERT INTO messages VALUES ('hi');")
+            .unwrap();
+        assert!(is_database(&source));
+        assert!(is_sidecar(&dir.path().join("ChatStorage.sqlite-wal")));
+        assert!(!is_sidecar(&dir.path().join("notes.txt-wal")));
+
+        let copy = dir.path().join("copy.sqlite");
+        snapshot(&source, &copy).unwrap();
+        drop(writer);
+
+        let conn = Connection::open(&copy).unwrap();
+        let text: String = conn
+            .query_row("SELECT text FROM messages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(text, "hi");
+        assert_eq!(integrity_check(&copy).unwrap(), "ok");
+        assert!(!dir.path().join("copy.sqlite-wal").exists());
+    }
+}
@@ -0,0 +1,305 @@
+use crate::config::Config;
+use crate::crypto::{self, Identity};
+use crate::destination::{self, Destination};
+use crate::restore::{self, StoredBackup};
+use crate::{dedup, keys};
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use std::collections::HashSet;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use tar::Archive;
+
+/// Files every WhatsApp Desktop backup contains
+const EXPECTED_FILES: &[&str] = &["ChatStorage.sqlite"];
+
+/// How far `verify` checks a backup; each level includes the ones before it
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Level {
+    /// Manifest signature, chunk sizes and SHA256 of the encrypted data
+    Checksum,
+    /// Decrypt the whole stream, checking every AEAD tag
+    Decrypt,
+    /// Walk the tar archive and look for the expected WhatsApp files
+    Contents,
+}
+
+fn is_snapshot(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some(dedup::SNAPSHOT_EXTENSION)
+}
+
+/// Identities for `level`; snapshots can only be read with the repository key
+fn identities_for(
+    level: Level,
+    snapshots: bool,
+    identity_file: Option<&Path>,
+) -> Result<Vec<Identity>> {
+    if level > Level::Checksum || snapshots {
+        keys::restore_identities(identity_file, false)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// Prints the outcome of one check and counts failures
+fn report(result: Result<()>, failed: &mut usize) {
+    match result {
+        Ok(()) => println!("  OK"),
+        Err(e) => {
+            println!("  FAILED: {:#}", e);
+            *failed += 1;
+        }
+    }
+}
+
+fn summarize(failed: usize, total: usize) -> Result<()> {
+    if failed > 0 {
+        anyhow::bail!("{} of {} backups failed verification", failed, total);
+    }
+    println!("\n{} backups verified", total);
+    Ok(())
+}
+
+/// Verifies one backup file up to `level`
+pub fn verify_file(path: &Path, level: Level, identity_file: Option<&Path>) -> Result<()> {
+    let identities = identities_for(level, is_snapshot(path), identity_file)?;
+    let mut failed = 0;
+    println!("Verifying {}", path.display());
+    report(verify_backup(path, level, &identities), &mut failed);
+    summarize(failed, 1)
+}
+
+/// Verifies the backups and snapshots at every configured destination, or
+/// only the newest at each with `latest`, reporting every failure. Backups
+/// not on this machine are downloaded to a temporary directory first. Fails
+/// if any backup fails or a destination can't be listed.
+pub fn verify_destinations(
+    config: &Config,
+    latest: bool,
+    level: Level,
+    identity_file: Option<&Path>,
+) -> Result<()> {
+    let destinations = destination::configured(config)?;
+    let mut unlisted = 0;
+    let mut backups = Vec::new();
+    for configured in &destinations {
+        match restore::list_backups(configured.destination.as_ref()) {
+            Ok(listed) => {
+                let count = if latest { 1 } else { listed.len() };
+                backups.extend(listed.into_iter().take(count).map(|(name, _)| (configured, name)));
+            }
+            Err(e) => {
+                println!("Listing {} FAILED: {:#}", configured.name, e);
+                unlisted += 1;
+            }
+        }
+    }
+    if backups.is_empty() && unlisted == 0 {
+        anyhow::bail!("No backups to verify");
+    }
+
+    let snapshots = backups.iter().any(|(_, name)| is_snapshot(Path::new(name)));
+    let identities = identities_for(level, snapshots, identity_file)?;
+    let mut failed = 0;
+    for (configured, name) in &backups {
+        println!("Verifying {} at {}", name, configured.name);
+        let result = tempfile::tempdir().map_err(Into::into).and_then(|staging| {
+            let destination = configured.destination.as_ref();
+            let path = destination::fetch_from(destination, configured.name, name, staging.path())?
+                .with_context(|| format!("Backup not found: {}", name))?;
+            verify_backup(&path, level, &identities)
+        });
+        report(result, &mut failed);
+    }
+    summarize(failed, backups.len())?;
+    if unlisted > 0 {
+        anyhow::bail!("{} destinations could not be listed", unlisted);
+    }
+    Ok(())
+}
+
+/// Checks a backup at `destination`: the signature, chunk sizes and
+/// checksum if it's on this machine, otherwise the signature and the sizes of
+/// the stored chunks
+pub fn verify_stored(destination: &dyn Destination, backup: &StoredBackup) -> Result<()> {
+    if let Some(dir) = destination.local_dir() {
+        return verify_backup(&dir.join(backup.name()), Level::Checksum, &[]);
+    }
+
+    restore::check_signature(backup.manifest.as_ref())?;
+    if let Some(manifest) = &backup.manifest {
+        for chunk in &manifest.chunks {
+            match destination.stat(&chunk.name)? {
+                Some(file) if file.size == chunk.size => {}
+                Some(file) => anyhow::bail!(
+                    "Chunk {} is {} bytes, manifest records {}",
+                    chunk.name,
+                    file.size,
+                    chunk.size
+                ),
+                None => anyhow::bail!("Missing chunk: {}", chunk.name),
+            }
+        }
+        println!("  Sizes: valid ({} chunks)", manifest.chunks.len());
+    }
+    Ok(())
+}
+
+fn verify_backup(path: &Path, level: Level, identities: &[Identity]) -> Result<()> {
+    if !path.exists() {
+        anyhow::bail!("Backup file not found: {}", path.display());
+    }
+
+    if is_snapshot(path) {
+        restore::check_snapshot_signature(path)?;
+        let repo = dedup::Repository::open(&dedup::repository_root(path)?, identities)?;
+        let reader = repo.snapshot_reader(path)?;
+        let mut rest = if level == Level::Contents {
+            check_contents(reader)?
+        } else {
+            reader
+        };
+        io::copy(&mut rest, &mut io::sink())?;
+        println!("  Snapshot chunks: valid");
+        return Ok(());
+    }
+
+    // The signature is checked before any chunk named in the manifest is read
+    let manifest_path = restore::find_manifest(path);
+    let manifest = manifest_path
+        .as_deref()
+        .map(restore::read_manifest)
+        .transpose()?;
+    restore::check_signature(manifest.as_ref())?;
+
+    let (data, expected_format) = match (&manifest, &manifest_path) {
+        (Some(manifest), Some(manifest_path)) => {
+            let dir = manifest_path.parent().context("No parent directory")?;
+            restore::copy_chunks(manifest, dir, io::sink())?;
+            println!("  Checksum: valid ({} chunks)", manifest.chunks.len());
+            for database in manifest.databases.iter().filter(|d| !d.is_ok()) {
+                println!("  Warning: {} was archived with: {}", database.path, database.integrity);
+            }
+            let data = manifest.chunks.iter().map(|c| dir.join(&c.name)).collect();
+            (data, Some(manifest.format_version))
+        }
+        _ => (vec![path.to_path_buf()], None),
+    };
+
+    if level > Level::Checksum {
+        check_data(&data, expected_format, level, identities)?;
+    }
+    Ok(())
+}
+
+/// Decrypts the data files in a streaming pass, and at the contents level
+/// also walks the archive inside
+fn check_data(
+    data: &[PathBuf],
+    expected_format: Option<u8>,
+    level: Level,
+    identities: &[Identity],
+) -> Result<()> {
+    let mut reader = restore::open_data(data)?;
+    let header = crypto::read_header(&mut reader)?;
+    if let Some(expected) = expected_format {
+        if expected != header.format_version() {
+            anyhow::bail!(
+                "Format mismatch: manifest records v{}, but the backup is v{}",
+                expected,
+                header.format_version()
+            );
+        }
+    }
+
+    let mut decrypted = crypto::decrypt_reader(reader, header, identities)?;
+    if level == Level::Contents {
+        let decoder = check_contents(GzDecoder::new(decrypted))?;
+        decrypted = decoder.into_inner();
+    }
+    // Read to the end so the final segment's tag is checked too
+    io::copy(&mut decrypted, &mut io::sink()).context("Decryption failed")?;
+    println!("  Decryption: valid");
+    Ok(())
+}
+
+/// Reads every entry of the tar stream and checks that the expected WhatsApp
+/// files are present. Returns the reader, positioned after the archive.
+fn check_contents<R: Read>(reader: R) -> Result<R> {
+    let mut archive = Archive::new(reader);
+    let mut found = HashSet::new();
+    let mut count = 0;
+
+    for entry in archive.entries().context("Failed to read archive")? {
+        let mut entry = entry.context("Failed to read archive")?;
+        if let Some(name) = entry.path()?.file_name().and_then(|n| n.to_str()) {
+            found.insert(name.to_string());
+        }
+        io::copy(&mut entry, &mut io::sink()).context("Failed to read archive")?;
+        count += 1;
+    }
+
+    let missing: Vec<&str> = EXPECTED_FILES
+        .iter()
+        .copied()
+        .filter(|name| !found.contains(*name))
+        .collect();
+    if !missing.is_empty() {
+        anyhow::bail!("Archive is missing {}", missing.join(", "));
+    }
+    println!("  Contents: {} entries, expected files present", count);
+    Ok(archive.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Recipient;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn archive(files: &[&str]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for name in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(4);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("whatsapp-data/{}", name), &b"data"[..])
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn encrypt(path: &Path, data: &[u8]) {
+        let file = std::fs::File::create(path).unwrap();
+        let recipients = [Recipient::passphrase("passphrase")];
+        let mut encryptor = crypto::StreamEncryptor::for_recipients(file, &recipients).unwrap();
+        encryptor.write_all(data).unwrap();
+        encryptor.finish().unwrap();
+    }
+
+    #[test]
+    fn test_check_data_levels() {
+        let dir = tempfile::tempdir().unwrap();
+        let identities = [Identity::Passphrase("passphrase".to_string())];
+
+        let good = [dir.path().join("good.enc")];
+        encrypt(&good[0], &archive(&["ChatStorage.sqlite", "Media/photo.jpg"]));
+        check_data(&good, None, Level::Contents, &identities).unwrap();
+        assert!(check_data(&good, Some(2), Level::Decrypt, &identities).is_err());
+
+        let incomplete = [dir.path().join("incomplete.enc")];
+        encrypt(&incomplete[0], &archive(&["Media/photo.jpg"]));
+        check_data(&incomplete, None, Level::Decrypt, &identities).unwrap();
+        assert!(check_data(&incomplete, None, Level::Contents, &identities).is_err());
+
+        let mut bytes = std::fs::read(&good[0]).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        std::fs::write(&good[0], bytes).unwrap();
+        assert!(check_data(&good, None, Level::Decrypt, &identities).is_err());
+    }
+}
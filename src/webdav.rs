@@ -0,0 +1,769 @@
+use crate::config::WebdavConfig;
+use crate::destination::{Destination, StoredFile};
+use crate::s3::uri_encode;
+use crate::secrets;
+use anyhow::{Context, Result};
+use data_encoding::BASE64;
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Environment variable with the password, instead of the secret store
+pub const PASSWORD_VARIABLE: &str = "WHATSAPP_BACKUP_WEBDAV_PASSWORD";
+
+/// Files larger than this are sent to Nextcloud in parts of this size
+/// (chunked upload v2), which keeps each request under the server's upload
+/// size and time limits. Nextcloud needs at least 5 MiB per part but the last.
+const CHUNK_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Suffix of files being uploaded, renamed away once verified
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Properties requested by PROPFIND
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/></d:prop></d:propfind>"#;
+
+/// Folder on a WebDAV server
+pub struct Webdav {
+    /// Folder URL without a trailing slash
+    url: String,
+    /// Decoded path of `url` with a trailing slash, to turn hrefs into names
+    base_path: String,
+    user: Option<String>,
+    /// Secret store account of the password
+    password_account: String,
+    /// `Authorization` header, looked up on first use
+    authorization: OnceCell<Option<String>>,
+    /// Folders known to exist, so each is created at most once
+    folders: RefCell<HashSet<String>>,
+    /// Nextcloud upload folder for chunked uploads (`.../dav/uploads/<user>`),
+    /// for `url`s inside `.../dav/files/<user>/`
+    uploads_url: Option<String>,
+    chunk_size: u64,
+}
+
+impl Webdav {
+    /// Destination for the config entry called `name`
+    pub fn new(name: &str, config: &WebdavConfig) -> Self {
+        let url = config.url.trim_end_matches('/').to_string();
+        let base_path = format!("{}/", percent_decode(url_path(&url)).trim_end_matches('/'));
+        Self {
+            uploads_url: uploads_url(&url),
+            url,
+            base_path,
+            user: config.user.clone(),
+            password_account: format!("webdav-{}", name),
+            authorization: OnceCell::new(),
+            folders: RefCell::new(HashSet::new()),
+            chunk_size: CHUNK_SIZE,
+        }
+    }
+
+    /// Basic authentication with the password from the environment or the
+    /// secret store
+    fn authorization(&self) -> Result<Option<&str>> {
+        if let Some(authorization) = self.authorization.get() {
+            return Ok(authorization.as_deref());
+        }
+        let authorization = match &self.user {
+            Some(user) => {
+                let password = match std::env::var(PASSWORD_VARIABLE) {
+                    Ok(password) => password,
+                    Err(_) => secrets::configured()?
+                        .get(&self.password_account)
+                        .with_context(|| {
+                            format!(
+                                "No password for {}: set {} or store it in the secret store as {}",
+                                self.url, PASSWORD_VARIABLE, self.password_account
+                            )
+                        })?,
+                };
+                let credentials = format!("{}:{}", user, password);
+                Some(format!("Basic {}", BASE64.encode(credentials.as_bytes())))
+            }
+            None => None,
+        };
+        Ok(self.authorization.get_or_init(|| authorization).as_deref())
+    }
+
+    fn file_url(&self, name: &str) -> String {
+        format!("{}/{}", self.url, uri_encode(name, false))
+    }
+
+    /// Request for `url` with authentication
+    fn request(&self, method: &str, url: &str) -> Result<ureq::Request> {
+        let request = ureq::request(method, url);
+        Ok(match self.authorization()? {
+            Some(authorization) => request.set("Authorization", authorization),
+            None => request,
+        })
+    }
+
+    /// Creates the folder holding `name` and any missing parents
+    fn create_folders(&self, name: &str) -> Result<()> {
+        let mut folder = String::new();
+        let parents = name.rsplit_once('/').map_or("", |(parent, _)| parent);
+        let mut folders = vec![String::new()];
+        for component in parents.split('/').filter(|c| !c.is_empty()) {
+            folder = format!("{}{}/", folder, component);
+            folders.push(folder.clone());
+        }
+        for folder in folders {
+            if self.folders.borrow().contains(&folder) {
+                continue;
+            }
+            let url = format!("{}/{}", self.url, uri_encode(&folder, false));
+            match self.request("MKCOL", &url)?.call() {
+                // 405: the folder already exists
+                Ok(_) | Err(ureq::Error::Status(405, _)) => {}
+                Err(e) => {
+                    return Err(status_error("MKCOL", &url, e));
+                }
+            }
+            self.folders.borrow_mut().insert(folder);
+        }
+        Ok(())
+    }
+
+    /// Files and folders directly inside the folder `url` (or `url` itself
+    /// with `depth` 0), as (name, size, is folder). Returns `None` if it
+    /// doesn't exist.
+    fn propfind(&self, url: &str, depth: &str) -> Result<Option<Vec<(String, u64, bool)>>> {
+        let response = match self
+            .request("PROPFIND", url)?
+            .set("Depth", depth)
+            .set("Content-Type", "application/xml; charset=utf-8")
+            .send_string(PROPFIND_BODY)
+        {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(status_error("PROPFIND", url, e)),
+        };
+        let body = response.into_string()?;
+        let doc = roxmltree::Document::parse(&body)
+            .with_context(|| format!("Invalid PROPFIND response: {}", body))?;
+
+        let mut entries = Vec::new();
+        for item in doc.descendants().filter(|n| n.has_tag_name("response")) {
+            let Some(href) = descendant(item, "href").and_then(|n| n.text()) else {
+                continue;
+            };
+            let path = percent_decode(url_path(href));
+            let Some(name) = path.strip_prefix(&self.base_path).or_else(|| {
+                // The folder itself, without its trailing slash
+                (path == self.base_path.trim_end_matches('/')).then_some("")
+            }) else {
+                continue;
+            };
+            // Properties that weren't found are reported in a non-200 propstat
+            let props = item.children().filter(|n| {
+                n.has_tag_name("propstat")
+                    && descendant(*n, "status")
+                        .and_then(|s| s.text())
+                        .is_some_and(|s| s.contains(" 200 "))
+            });
+            let mut size = 0;
+            let mut is_folder = false;
+            for prop in props {
+                is_folder |= descendant(prop, "collection").is_some();
+                if let Some(length) = descendant(prop, "getcontentlength").and_then(|n| n.text()) {
+                    size = length.trim().parse()?;
+                }
+            }
+            entries.push((name.trim_end_matches('/').to_string(), size, is_folder));
+        }
+        Ok(Some(entries))
+    }
+
+    /// Streams `body` to `url` with its length set, so the body isn't sent
+    /// with chunked transfer encoding, which some servers refuse
+    fn put_stream<R: Read + Send>(&self, url: &str, body: R, size: u64) -> Result<()> {
+        self.request("PUT", url)?
+            .set("Content-Type", "application/octet-stream")
+            .set("Content-Length", &size.to_string())
+            .send(body)
+            .map_err(|e| status_error("PUT", url, e))?;
+        Ok(())
+    }
+
+    /// Renames `from` to `to` on the server, replacing `to`
+    fn move_to(&self, from: &str, to: &str) -> Result<()> {
+        self.request("MOVE", from)?
+            .set("Destination", to)
+            .set("Overwrite", "T")
+            .call()
+            .map_err(|e| status_error("MOVE", from, e))?;
+        Ok(())
+    }
+
+    /// Uploads `file` to a hidden `.<name>.partial`, checks its size and only
+    /// then renames it to `name`, so an interrupted upload never leaves a
+    /// truncated file under the real name
+    fn put_partial(&self, file: &Path, name: &str, size: u64) -> Result<()> {
+        let partial = match name.rsplit_once('/') {
+            Some((dir, file_name)) => format!("{}/.{}{}", dir, file_name, PARTIAL_SUFFIX),
+            None => format!(".{}{}", name, PARTIAL_SUFFIX),
+        };
+        let partial_url = self.file_url(&partial);
+        let input = BufReader::new(
+            File::open(file).with_context(|| format!("Failed to open {}", file.display()))?,
+        );
+        self.put_stream(&partial_url, input, size)?;
+
+        let stored = self.stat(&partial)?.map_or(0, |f| f.size);
+        if stored != size {
+            let _ = self.delete(&partial);
+            anyhow::bail!(
+                "Upload of {} is incomplete: {} bytes on the server, expected {}",
+                name,
+                stored,
+                size
+            );
+        }
+        self.move_to(&partial_url, &self.file_url(name))
+    }
+
+    /// Nextcloud chunked upload v2: the parts go to a new upload folder and
+    /// the server assembles them into `name` when `.file` is moved there.
+    /// Returns `false` if the server doesn't offer chunked uploads.
+    fn put_chunked(&self, uploads_url: &str, file: &Path, name: &str, size: u64) -> Result<bool> {
+        let target = self.file_url(name);
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let folder = format!(
+            "{}/whatsapp-backup-{}-{}",
+            uploads_url,
+            std::process::id(),
+            nanos
+        );
+        match self
+            .request("MKCOL", &folder)?
+            .set("Destination", &target)
+            .call()
+        {
+            Ok(_) => {}
+            Err(ureq::Error::Status(404 | 405 | 501, _)) => return Ok(false),
+            Err(e) => return Err(status_error("MKCOL", &folder, e)),
+        }
+
+        let result = self.upload_parts(&folder, &target, file, size);
+        if result.is_err() {
+            // The upload folder would otherwise stay until the server expires it
+            if let Ok(request) = self.request("DELETE", &folder) {
+                let _ = request.call();
+            }
+        }
+        result.map(|()| true)
+    }
+
+    /// Sends `file` to the upload `folder` in parts and has the server
+    /// assemble them at `target`
+    fn upload_parts(&self, folder: &str, target: &str, file: &Path, size: u64) -> Result<()> {
+        let mut input =
+            File::open(file).with_context(|| format!("Failed to open {}", file.display()))?;
+        for number in 1..=size.div_ceil(self.chunk_size) {
+            let length = (size - (number - 1) * self.chunk_size).min(self.chunk_size);
+            let part = BufReader::new(Read::by_ref(&mut input).take(length));
+            let url = format!("{}/{}", folder, number);
+            self.request("PUT", &url)?
+                .set("Destination", target)
+                .set("Content-Type", "application/octet-stream")
+                .set("Content-Length", &length.to_string())
+                .send(part)
+                .map_err(|e| status_error("PUT", &url, e))?;
+        }
+
+        let assembled = format!("{}/.file", folder);
+        self.request("MOVE", &assembled)?
+            .set("Destination", target)
+            .set("Overwrite", "T")
+            .set("OC-Total-Length", &size.to_string())
+            .call()
+            .map_err(|e| status_error("MOVE", &assembled, e))?;
+        Ok(())
+    }
+
+    /// Adds the files in the folder `dir` (a name ending in `/`, or empty
+    /// for the root) and its subfolders to `files`, skipping hidden entries
+    fn walk(&self, dir: &str, files: &mut Vec<StoredFile>) -> Result<()> {
+        let url = format!("{}/{}", self.url, uri_encode(dir, false));
+        let Some(entries) = self.propfind(&url, "1")? else {
+            return Ok(());
+        };
+        for (name, size, is_folder) in entries {
+            let Some(file_name) = name.strip_prefix(dir) else {
+                continue;
+            };
+            if file_name.is_empty() || file_name.starts_with('.') || file_name.contains('/') {
+                continue;
+            }
+            if is_folder {
+                self.walk(&format!("{}/", name), files)?;
+            } else {
+                files.push(StoredFile { name, size });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Destination for Webdav {
+    fn location(&self) -> String {
+        self.url.clone()
+    }
+
+    fn put(&self, file: &Path, name: &str) -> Result<()> {
+        self.create_folders(name)?;
+        let size = std::fs::metadata(file)
+            .with_context(|| format!("Failed to read {}", file.display()))?
+            .len();
+        let chunked = match &self.uploads_url {
+            Some(uploads_url) if size > self.chunk_size => {
+                self.put_chunked(uploads_url, file, name, size)?
+            }
+            _ => false,
+        };
+        if !chunked {
+            self.put_partial(file, name, size)?;
+        }
+
+        let stored = self.stat(name)?.map_or(0, |f| f.size);
+        if stored != size {
+            anyhow::bail!(
+                "{} is {} bytes on the server, expected {}",
+                name,
+                stored,
+                size
+            );
+        }
+        Ok(())
+    }
+
+    fn get(&self, name: &str, file: &Path) -> Result<()> {
+        let url = self.file_url(name);
+        let response = self
+            .request("GET", &url)?
+            .call()
+            .map_err(|e| status_error("GET", &url, e))?;
+        let mut output = BufWriter::new(
+            File::create(file).with_context(|| format!("Failed to create {}", file.display()))?,
+        );
+        std::io::copy(&mut response.into_reader(), &mut output)
+            .with_context(|| format!("Failed to download {}", name))?;
+        output.flush()?;
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StoredFile>> {
+        // Only walk the folder the prefix points into
+        let dir = prefix
+            .rsplit_once('/')
+            .map_or(String::new(), |(dir, _)| format!("{}/", dir));
+        let mut files = Vec::new();
+        self.walk(&dir, &mut files)?;
+        files.retain(|f| f.name.starts_with(prefix));
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        let url = self.file_url(name);
+        match self.request("DELETE", &url)?.call() {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(()),
+            Err(e) => Err(status_error("DELETE", &url, e)),
+        }
+    }
+
+    fn stat(&self, name: &str) -> Result<Option<StoredFile>> {
+        let entries = self.propfind(&self.file_url(name), "0")?;
+        Ok(entries
+            .into_iter()
+            .flatten()
+            .find(|(_, _, is_folder)| !is_folder)
+            .map(|(_, size, _)| StoredFile {
+                name: name.to_string(),
+                size,
+            }))
+    }
+}
+
+/// Error for a failed request, with the server's response body
+fn status_error(method: &str, url: &str, error: ureq::Error) -> anyhow::Error {
+    match error {
+        ureq::Error::Status(code, response) => {
+            let body = response.into_string().unwrap_or_default();
+            anyhow::anyhow!("{} {} returned {}: {}", method, url, code, body.trim())
+        }
+        e => anyhow::Error::new(e).context(format!("{} {} failed", method, url)),
+    }
+}
+
+/// Nextcloud's upload folder for a folder URL inside a user's files
+/// (`<server>/remote.php/dav/files/<user>/...`)
+fn uploads_url(url: &str) -> Option<String> {
+    const FILES: &str = "/remote.php/dav/files/";
+    let start = url.find(FILES)?;
+    let rest = &url[start + FILES.len()..];
+    let user = rest.split('/').next().filter(|u| !u.is_empty())?;
+    Some(format!("{}/remote.php/dav/uploads/{}", &url[..start], user))
+}
+
+/// Path of a URL, or the value itself if it is already a path
+fn url_path(url: &str) -> &str {
+    match url.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => url,
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = value.get(i + 1..i + 3);
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn descendant<'a>(node: roxmltree::Node<'a, 'a>, name: &str) -> Option<roxmltree::Node<'a, 'a>> {
+    node.descendants().find(|n| n.has_tag_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    const AUTHORIZATION: &str = "Basic dXNlcjpzZWNyZXQ="; // user:secret
+    const FILES_PATH: &str = "/remote.php/dav/files/user/Whats App";
+    const UPLOADS_PATH: &str = "/remote.php/dav/uploads/user";
+
+    /// Nextcloud-like WebDAV server storing files in `root` and chunked
+    /// uploads in `uploads`; records the files assembled from chunks
+    struct MockServer {
+        root: PathBuf,
+        uploads: PathBuf,
+        /// Whether the uploads folder exists, as on Nextcloud
+        chunking: bool,
+        /// Drops the last byte of every PUT, like a cut-off upload
+        truncate_puts: bool,
+        chunked_uploads: Vec<String>,
+    }
+
+    impl MockServer {
+        fn new(dir: &Path) -> Self {
+            let root = dir.join("server");
+            let uploads = dir.join("uploads");
+            std::fs::create_dir_all(&root).unwrap();
+            std::fs::create_dir_all(&uploads).unwrap();
+            Self {
+                root,
+                uploads,
+                chunking: true,
+                truncate_puts: false,
+                chunked_uploads: Vec::new(),
+            }
+        }
+
+        /// Local path and name relative to its top folder of a request URL
+        fn local_path(&self, url: &str) -> Option<(PathBuf, String)> {
+            let path = percent_decode(url_path(url));
+            let (top, relative) = match path.strip_prefix(FILES_PATH) {
+                Some(relative) => (&self.root, relative),
+                None if self.chunking => (&self.uploads, path.strip_prefix(UPLOADS_PATH)?),
+                None => return None,
+            };
+            let relative = relative.trim_matches('/').to_string();
+            Some((top.join(&relative), relative))
+        }
+    }
+
+    fn header(request: &tiny_http::Request, name: &str) -> Option<String> {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+            .map(|h| h.value.to_string())
+    }
+
+    fn propfind_entry(href: &str, path: &Path) -> String {
+        let prop = if path.is_dir() {
+            "<d:resourcetype><d:collection/></d:resourcetype>".to_string()
+        } else {
+            format!(
+                "<d:resourcetype/><d:getcontentlength>{}</d:getcontentlength>",
+                path.metadata().unwrap().len()
+            )
+        };
+        format!(
+            "<d:response><d:href>{}</d:href><d:propstat><d:prop>{}</d:prop>\
+             <d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+            href, prop
+        )
+    }
+
+    /// Concatenates the numbered parts in `folder`, in order
+    fn assemble(folder: &Path) -> Vec<u8> {
+        let mut parts: Vec<(u32, PathBuf)> = std::fs::read_dir(folder)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .map(|p| (p.file_name().unwrap().to_str().unwrap().parse().unwrap(), p))
+            .collect();
+        parts.sort();
+        parts
+            .iter()
+            .flat_map(|(_, p)| std::fs::read(p).unwrap())
+            .collect()
+    }
+
+    fn handle(state: &mut MockServer, request: &mut tiny_http::Request) -> (u16, String) {
+        if header(request, "Authorization").as_deref() != Some(AUTHORIZATION) {
+            return (401, String::new());
+        }
+        let mut body = Vec::new();
+        request.as_reader().read_to_end(&mut body).unwrap();
+        let url = request.url().to_string();
+        let Some((path, relative)) = state.local_path(&url) else {
+            return (404, String::new());
+        };
+        let parent_exists = path.parent().unwrap().is_dir();
+
+        match request.method().as_str() {
+            "MKCOL" if path.exists() => (405, String::new()),
+            "MKCOL" if !parent_exists => (409, String::new()),
+            "MKCOL" => {
+                std::fs::create_dir(&path).unwrap();
+                (201, String::new())
+            }
+            "PUT" if !parent_exists => (409, String::new()),
+            "PUT" => {
+                if state.truncate_puts {
+                    body.pop();
+                }
+                std::fs::write(&path, body).unwrap();
+                (201, String::new())
+            }
+            "MOVE" => {
+                let destination = header(request, "Destination").unwrap();
+                let (target, name) = state.local_path(&destination).unwrap();
+                if path.file_name().unwrap() == ".file" {
+                    let folder = path.parent().unwrap();
+                    let data = assemble(folder);
+                    let total = header(request, "OC-Total-Length").unwrap();
+                    if data.len().to_string() != total {
+                        return (400, "Chunks don't add up to OC-Total-Length".to_string());
+                    }
+                    std::fs::write(&target, data).unwrap();
+                    std::fs::remove_dir_all(folder).unwrap();
+                    state.chunked_uploads.push(name);
+                } else if path.is_file() {
+                    std::fs::rename(&path, &target).unwrap();
+                } else {
+                    return (404, String::new());
+                }
+                (201, String::new())
+            }
+            "GET" => match std::fs::read(&path) {
+                Ok(data) => (200, String::from_utf8(data).unwrap()),
+                Err(_) => (404, String::new()),
+            },
+            "DELETE" if path.is_file() => {
+                std::fs::remove_file(&path).unwrap();
+                (204, String::new())
+            }
+            "DELETE" if path.is_dir() && !relative.is_empty() => {
+                std::fs::remove_dir_all(&path).unwrap();
+                (204, String::new())
+            }
+            "DELETE" => (404, String::new()),
+            "PROPFIND" if !path.exists() => (404, String::new()),
+            "PROPFIND" => {
+                let mut xml = String::from(r#"<d:multistatus xmlns:d="DAV:">"#);
+                xml.push_str(&propfind_entry(url.trim_end_matches('/'), &path));
+                if header(request, "Depth").as_deref() == Some("1") && path.is_dir() {
+                    // Servers answer with absolute URLs too
+                    let prefix = format!("http://localhost{}", url.trim_end_matches('/'));
+                    for entry in std::fs::read_dir(&path).unwrap() {
+                        let entry = entry.unwrap();
+                        let name = entry.file_name().to_string_lossy().to_string();
+                        let href = format!("{}/{}", prefix, uri_encode(&name, true));
+                        xml.push_str(&propfind_entry(&href, &entry.path()));
+                    }
+                }
+                xml.push_str("</d:multistatus>");
+                (207, xml)
+            }
+            _ => (405, String::new()),
+        }
+    }
+
+    /// Serves the mock server on a local port, returning the folder URL
+    fn serve(state: Arc<Mutex<MockServer>>) -> String {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let port = server.server_addr().to_ip().unwrap().port();
+        std::thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let (status, body) = handle(&mut state.lock().unwrap(), &mut request);
+                let response = tiny_http::Response::from_string(body).with_status_code(status);
+                request.respond(response).unwrap();
+            }
+        });
+        format!(
+            "http://127.0.0.1:{}{}/",
+            port,
+            uri_encode(FILES_PATH, false)
+        )
+    }
+
+    /// Destination on the mock server that sends files over 4 bytes in chunks
+    fn mock_destination(state: &Arc<Mutex<MockServer>>) -> Webdav {
+        let config = WebdavConfig {
+            url: serve(state.clone()),
+            user: Some("user".to_string()),
+        };
+        let webdav = Webdav {
+            chunk_size: 4,
+            ..Webdav::new("webdav", &config)
+        };
+        webdav
+            .authorization
+            .set(Some(AUTHORIZATION.to_string()))
+            .unwrap();
+        webdav
+    }
+
+    #[test]
+    fn test_uploads_url() {
+        assert_eq!(
+            uploads_url("https://cloud.example.com/remote.php/dav/files/me/WhatsApp").as_deref(),
+            Some("https://cloud.example.com/remote.php/dav/uploads/me")
+        );
+        assert_eq!(uploads_url("https://dav.example.com/backups"), None);
+    }
+
+    #[test]
+    fn test_webdav_operations() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(Mutex::new(MockServer::new(dir.path())));
+        let root = state.lock().unwrap().root.clone();
+        let webdav = mock_destination(&state);
+
+        let small = dir.path().join("small");
+        let large = dir.path().join("large");
+        std::fs::write(&small, b"abc").unwrap();
+        std::fs::write(&large, b"0123456789").unwrap();
+        webdav.put(&small, "a.enc").unwrap();
+        webdav.put(&large, "b.enc.001").unwrap();
+        webdav.put(&small, "repo/chunks/ab/abcd").unwrap();
+        webdav.put(&small, "repo/key").unwrap();
+        webdav.put(&large, "repo/key").unwrap();
+        std::fs::write(root.join(".partial"), b"hidden").unwrap();
+
+        assert_eq!(
+            state.lock().unwrap().chunked_uploads,
+            vec!["b.enc.001", "repo/key"]
+        );
+        assert_eq!(
+            std::fs::read(root.join("b.enc.001")).unwrap(),
+            b"0123456789"
+        );
+        assert_eq!(
+            std::fs::read(root.join("repo/chunks/ab/abcd")).unwrap(),
+            b"abc"
+        );
+        let uploads = state.lock().unwrap().uploads.clone();
+        assert!(std::fs::read_dir(uploads).unwrap().next().is_none());
+
+        let names = |prefix| -> Vec<String> {
+            webdav
+                .list(prefix)
+                .unwrap()
+                .into_iter()
+                .map(|f| f.name)
+                .collect()
+        };
+        assert_eq!(
+            names(""),
+            vec!["a.enc", "b.enc.001", "repo/chunks/ab/abcd", "repo/key"]
+        );
+        assert_eq!(names("repo/chunks/"), vec!["repo/chunks/ab/abcd"]);
+        assert!(names("missing/").is_empty());
+
+        let stat = webdav.stat("b.enc.001").unwrap().unwrap();
+        assert_eq!(stat.size, 10);
+        assert!(webdav.stat("repo").unwrap().is_none());
+        assert!(webdav.stat("missing").unwrap().is_none());
+
+        let download = dir.path().join("download");
+        webdav.get("repo/key", &download).unwrap();
+        assert_eq!(std::fs::read(&download).unwrap(), b"0123456789");
+
+        webdav.delete("a.enc").unwrap();
+        assert!(!root.join("a.enc").exists());
+        assert_eq!(names("a"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_incomplete_uploads_are_not_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(Mutex::new(MockServer::new(dir.path())));
+        let root = state.lock().unwrap().root.clone();
+        let webdav = mock_destination(&state);
+        let small = dir.path().join("small");
+        let large = dir.path().join("large");
+        std::fs::write(&small, b"abc").unwrap();
+        std::fs::write(&large, b"0123456789").unwrap();
+
+        state.lock().unwrap().truncate_puts = true;
+        let error = webdav.put(&small, "a.enc").unwrap_err();
+        assert!(error.to_string().contains("incomplete"), "{}", error);
+        assert!(webdav.put(&large, "b.enc.001").is_err());
+        assert!(std::fs::read_dir(&root).unwrap().next().is_none());
+
+        // Without chunked uploads, large files also go through a partial file
+        let mut server = state.lock().unwrap();
+        server.truncate_puts = false;
+        server.chunking = false;
+        drop(server);
+        webdav.put(&large, "b.enc.001").unwrap();
+        assert_eq!(
+            std::fs::read(root.join("b.enc.001")).unwrap(),
+            b"0123456789"
+        );
+        assert!(state.lock().unwrap().chunked_uploads.is_empty());
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_wrong_password_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(Mutex::new(MockServer::new(dir.path())));
+        let config = WebdavConfig {
+            url: serve(state),
+            user: Some("user".to_string()),
+        };
+        let webdav = Webdav::new("webdav", &config);
+        webdav
+            .authorization
+            .set(Some("Basic d3Jvbmc=".to_string()))
+            .unwrap();
+        let error = webdav.list("").unwrap_err();
+        assert!(error.to_string().contains("returned 401"), "{}", error);
+    }
+}